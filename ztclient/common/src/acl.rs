@@ -0,0 +1,462 @@
+//! What the ACL policies should do, worked out locally from the policies and the machines, so it
+//! can be compared with what the clients actually got.
+//!
+//! ```no_run
+//! # async fn example(
+//! #     np: &ztclient_common::ninjapanda::NinjaPandaClient,
+//! #     docker: &bollard::Docker,
+//! #     policies: &[ztclient_common::models::AclPolicy],
+//! # ) -> ztclient_common::Result<()> {
+//! use ztclient_common::{acl::AclEvaluation, errors::Errors, ztclient::ztclient_netmap};
+//!
+//! let hostnames = vec!["policy".to_string()];
+//! let machines = np.get_all_machines(&hostnames).await?;
+//! let evaluation = AclEvaluation::evaluate(policies, &machines)?;
+//! let mut errors = Errors::new();
+//! for machine in machines.iter() {
+//!     let netmap = ztclient_netmap(docker, &machine.hostname).await?;
+//!     evaluation.check_netmap(&mut errors, &machine.machine_id, &netmap);
+//! }
+//! errors.assert_pop();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every rule Ninja Panda accepts is an `accept`, so the order of policies and rules does not
+//! change the outcome; rules with any other action are ignored.  Machines see each other as peers
+//! when either one can reach the other, and a machine's packet filter holds the rules that have it
+//! as a destination.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    errors::Errors,
+    models::{ztn::NetMap, AclPolicy, Machine},
+    ninjapanda::bare_machine_id,
+    policy::{Ports, Protocol},
+    Error, Result,
+};
+
+/// Groups may list other groups; this is how deep they may go before it is called a cycle.
+const MAX_GROUP_DEPTH: usize = 8;
+
+/// `source` may reach `destination` over `protocol` on `ports`.  Machine IDs are bare.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub source: String,
+    pub destination: String,
+    pub protocol: Protocol,
+    pub ports: Ports,
+    pub policy_id: String,
+}
+
+/// One (protocol, source, destination, ports) entry of a packet filter, with addresses without
+/// their prefix length.  Both the prediction and the live filter are flattened into these, so they
+/// compare equal however the rules are grouped.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FilterEntry {
+    pub protocol: Protocol,
+    pub source: String,
+    pub destination: String,
+    pub first_port: u16,
+    pub last_port: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct AclEvaluation {
+    machines: Vec<Machine>,
+    grants: Vec<Grant>,
+}
+
+impl AclEvaluation {
+    /// Resolves every rule of `policies` against `machines`.  Machines named by a rule but not in
+    /// `machines` are left out, so pass every machine the policies can refer to.
+    pub fn evaluate(policies: &[AclPolicy], machines: &[Machine]) -> Result<AclEvaluation> {
+        let mut grants = Vec::new();
+        for policy in policies.iter() {
+            for acl in policy.acls.iter().filter(|acl| acl.action == "accept") {
+                let protocols = parse_protocols(&acl.protocol)?;
+                let ports = parse_ports(&acl.port)?;
+                let sources = resolve_all(policy, machines, &acl.sources)?;
+                let destinations = resolve_all(policy, machines, &acl.destinations)?;
+                for source in sources.iter() {
+                    for destination in destinations.iter() {
+                        for protocol in protocols.iter() {
+                            for ports in ports.iter() {
+                                grants.push(Grant {
+                                    source: source.clone(),
+                                    destination: destination.clone(),
+                                    protocol: *protocol,
+                                    ports: *ports,
+                                    policy_id: policy.aclpolicy_id.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(AclEvaluation {
+            machines: machines.to_vec(),
+            grants,
+        })
+    }
+
+    pub fn grants(&self) -> &[Grant] {
+        &self.grants
+    }
+
+    /// Whether `from` may reach `to`.  With no `port`, any port will do, which is also what ICMP
+    /// needs.
+    pub fn allows(&self, from: &str, to: &str, protocol: Protocol, port: Option<u16>) -> bool {
+        let (from, to) = (bare_machine_id(from), bare_machine_id(to));
+        self.grants.iter().any(|g| {
+            g.source == from
+                && g.destination == to
+                && g.protocol == protocol
+                && port.is_none_or(|p| g.ports.contains(p))
+        })
+    }
+
+    /// The bare IDs of the machines `machine_id` should have as peers, sorted.
+    pub fn peers(&self, machine_id: &str) -> Vec<String> {
+        let machine_id = bare_machine_id(machine_id);
+        let peers: BTreeSet<&str> = self
+            .grants
+            .iter()
+            .filter_map(|g| {
+                if g.source == machine_id {
+                    Some(g.destination.as_str())
+                } else if g.destination == machine_id {
+                    Some(g.source.as_str())
+                } else {
+                    None
+                }
+            })
+            .filter(|peer| *peer != machine_id)
+            .collect();
+        peers.into_iter().map(str::to_string).collect()
+    }
+
+    /// The packet filter `machine_id` should get.  Empty means the netmap has no `PacketFilter`.
+    pub fn packet_filter(&self, machine_id: &str) -> BTreeSet<FilterEntry> {
+        let machine_id = bare_machine_id(machine_id);
+        let mut entries = BTreeSet::new();
+        for grant in self.grants.iter().filter(|g| g.destination == machine_id) {
+            let (first_port, last_port) = grant.ports.bounds();
+            for source in self.addresses(&grant.source) {
+                for destination in self.addresses(&grant.destination) {
+                    entries.insert(FilterEntry {
+                        protocol: grant.protocol,
+                        source: source.to_string(),
+                        destination: destination.to_string(),
+                        first_port,
+                        last_port,
+                    });
+                }
+            }
+        }
+        entries
+    }
+
+    /// Records whether the netmap of `machine_id` has the predicted peers and packet filter.
+    /// Peers are compared by address, since that is what the netmap and Ninja Panda share.
+    #[track_caller]
+    pub fn check_netmap(&self, errors: &mut Errors, machine_id: &str, netmap: &NetMap) {
+        let expected_peers: BTreeSet<String> = self
+            .peers(machine_id)
+            .iter()
+            .flat_map(|peer| self.addresses(peer))
+            .map(str::to_string)
+            .collect();
+        let actual_peers: BTreeSet<String> = netmap
+            .peers
+            .iter()
+            .flatten()
+            .flat_map(|peer| peer.addresses.iter())
+            .map(|address| without_prefix_len(address).to_string())
+            .collect();
+        errors.eq("peer addresses", expected_peers, actual_peers);
+        match live_packet_filter(netmap) {
+            Ok(actual) => errors.eq("packet filter", self.packet_filter(machine_id), actual),
+            Err(e) => errors.add_error(e.to_string()),
+        }
+    }
+
+    fn addresses<'a>(&'a self, machine_id: &'a str) -> impl Iterator<Item = &'a str> {
+        self.machines
+            .iter()
+            .filter(move |m| m.machine_id == machine_id)
+            .flat_map(|m| m.ip_addresses.iter())
+            .map(|address| without_prefix_len(address))
+    }
+}
+
+/// Flattens the `PacketFilter` of a netmap into entries comparable with
+/// [`AclEvaluation::packet_filter`].
+pub fn live_packet_filter(netmap: &NetMap) -> Result<BTreeSet<FilterEntry>> {
+    let mut entries = BTreeSet::new();
+    for rule in netmap.packet_filter.iter().flatten() {
+        let protocols = parse_protocols(&rule.ipproto)?;
+        for source in rule.srcs.iter() {
+            for destination in rule.dsts.iter() {
+                let port = |p: i64| {
+                    u16::try_from(p).map_err(|_| {
+                        Error::Verification(format!("{p} in the packet filter is not a port"))
+                    })
+                };
+                for protocol in protocols.iter() {
+                    entries.insert(FilterEntry {
+                        protocol: *protocol,
+                        source: without_prefix_len(source).to_string(),
+                        destination: without_prefix_len(&destination.net).to_string(),
+                        first_port: port(destination.ports.first)?,
+                        last_port: port(destination.ports.last)?,
+                    });
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Ninja Panda writes `tcp`, the client lists IANA numbers such as `[6 17]`; empty or `*` means
+/// every protocol.
+fn parse_protocols(protocols: &str) -> Result<Vec<Protocol>> {
+    let names: Vec<&str> = protocols
+        .split(|c: char| c == ',' || c == '[' || c == ']' || c.is_whitespace())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() || names == ["*"] {
+        return Ok(Protocol::ALL.to_vec());
+    }
+    names.into_iter().map(str::parse).collect()
+}
+
+/// A single port specification, or several separated by commas.
+fn parse_ports(ports: &str) -> Result<Vec<Ports>> {
+    ports.split(',').map(str::parse).collect()
+}
+
+fn without_prefix_len(address: &str) -> &str {
+    address.split('/').next().unwrap_or(address)
+}
+
+fn resolve_all(
+    policy: &AclPolicy,
+    machines: &[Machine],
+    selectors: &[String],
+) -> Result<BTreeSet<String>> {
+    let mut resolved = BTreeSet::new();
+    for selector in selectors.iter() {
+        resolve(policy, machines, selector, 0, &mut resolved)?;
+    }
+    Ok(resolved)
+}
+
+/// Adds the bare IDs of the machines `selector` stands for to `resolved`.
+fn resolve(
+    policy: &AclPolicy,
+    machines: &[Machine],
+    selector: &str,
+    depth: usize,
+    resolved: &mut BTreeSet<String>,
+) -> Result<()> {
+    let matching = |matches: &dyn Fn(&Machine) -> bool| {
+        machines
+            .iter()
+            .filter(|m| matches(m))
+            .map(|m| m.machine_id.clone())
+            .collect::<Vec<_>>()
+    };
+    let machine_ids = if selector == "*" {
+        matching(&|_| true)
+    } else if selector.starts_with("group:") {
+        if depth >= MAX_GROUP_DEPTH {
+            return Err(Error::Verification(format!(
+                "policy {}: group {selector} nests too deep",
+                policy.aclpolicy_id
+            )));
+        }
+        let group = policy
+            .groups
+            .iter()
+            .find(|g| g.key == selector)
+            .ok_or_else(|| {
+                Error::Verification(format!(
+                    "policy {} refers to unknown group {selector}",
+                    policy.aclpolicy_id
+                ))
+            })?;
+        for value in group.values.iter() {
+            resolve(policy, machines, value, depth + 1, resolved)?;
+        }
+        return Ok(());
+    } else if let Some(machine_id) = selector.strip_prefix("machine:") {
+        matching(&|m| m.machine_id == machine_id)
+    } else if let Some(user) = selector.strip_prefix("user:") {
+        matching(&|m| {
+            m.user_info.as_ref().is_some_and(|u| {
+                u.user_info_id == user || u.email == user || u.display_name == user
+            })
+        })
+    } else if selector.starts_with("tag:") {
+        matching(&|m| has_tag(m, selector))
+    } else if selector.parse::<std::net::IpAddr>().is_ok() {
+        matching(&|m| {
+            m.ip_addresses
+                .iter()
+                .any(|a| without_prefix_len(a) == selector)
+        })
+    } else {
+        return Err(Error::Verification(format!(
+            "policy {}: cannot resolve {selector:?}",
+            policy.aclpolicy_id
+        )));
+    };
+    resolved.extend(machine_ids);
+    Ok(())
+}
+
+fn has_tag(machine: &Machine, tag: &str) -> bool {
+    [&machine.forced_tags, &machine.valid_tags]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|t| t.as_str() == Some(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{
+            ztn::{Dst, PacketFilter, Ports as FilterPorts, SelfNode},
+            UserInfo,
+        },
+        policy::PolicyBuilder,
+    };
+
+    fn machine(id: &str, address: &str) -> Machine {
+        Machine {
+            machine_id: id.to_string(),
+            ip_addresses: vec![address.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn machines() -> Vec<Machine> {
+        let mut web = machine("3", "100.64.0.3");
+        web.valid_tags = Some(vec!["tag:web".into()]);
+        let mut admin = machine("4", "100.64.0.4");
+        admin.user_info = Some(UserInfo {
+            email: "admin@example.com".to_string(),
+            ..Default::default()
+        });
+        vec![
+            machine("1", "100.64.0.1"),
+            machine("2", "100.64.0.2"),
+            web,
+            admin,
+        ]
+    }
+
+    #[test]
+    fn one_way_rule() {
+        let policy = PolicyBuilder::with_id("p")
+            .machines("a", &["1".to_string()])
+            .machines("b", &["machine:2".to_string()])
+            .allow("a", "b", Protocol::Tcp, 80)
+            .build()
+            .unwrap();
+        let evaluation = AclEvaluation::evaluate(&[policy], &machines()).unwrap();
+
+        assert!(evaluation.allows("1", "machine:2", Protocol::Tcp, Some(80)));
+        assert!(!evaluation.allows("1", "2", Protocol::Tcp, Some(81)));
+        assert!(!evaluation.allows("1", "2", Protocol::Udp, Some(80)));
+        assert!(!evaluation.allows("2", "1", Protocol::Tcp, Some(80)));
+        assert_eq!(evaluation.peers("1"), vec!["2"]);
+        assert_eq!(evaluation.peers("2"), vec!["1"]);
+        assert!(evaluation.peers("3").is_empty());
+        assert!(evaluation.packet_filter("1").is_empty());
+        assert_eq!(
+            evaluation
+                .packet_filter("2")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![FilterEntry {
+                protocol: Protocol::Tcp,
+                source: "100.64.0.1".to_string(),
+                destination: "100.64.0.2".to_string(),
+                first_port: 80,
+                last_port: 80,
+            }]
+        );
+    }
+
+    #[test]
+    fn tags_users_and_wildcards() {
+        let policy = PolicyBuilder::with_id("p")
+            .users("admins", &["admin@example.com"])
+            .allow("admins", "tag:web", Protocol::Tcp, 8000..=8080)
+            .allow("admins", "*", Protocol::Icmp, ..)
+            .build()
+            .unwrap();
+        let evaluation = AclEvaluation::evaluate(&[policy], &machines()).unwrap();
+
+        assert!(evaluation.allows("4", "3", Protocol::Tcp, Some(8080)));
+        assert!(!evaluation.allows("4", "1", Protocol::Tcp, Some(8080)));
+        assert!(evaluation.allows("4", "1", Protocol::Icmp, None));
+        assert_eq!(evaluation.peers("4"), vec!["1", "2", "3"]);
+        assert_eq!(evaluation.peers("3"), vec!["4"]);
+    }
+
+    #[test]
+    fn unknown_groups_are_errors() {
+        let mut policy = PolicyBuilder::new()
+            .allow("tag:web", "tag:web", Protocol::Tcp, 80)
+            .build()
+            .unwrap();
+        policy.acls[0].sources = vec!["group:missing".to_string()];
+        assert!(AclEvaluation::evaluate(&[policy], &machines()).is_err());
+    }
+
+    #[test]
+    fn prediction_matches_live_netmap() {
+        let policy = PolicyBuilder::with_id("p")
+            .machines("peers", &["1".to_string(), "2".to_string()])
+            .allow("peers", "peers", Protocol::Tcp, ..)
+            .build()
+            .unwrap();
+        let evaluation = AclEvaluation::evaluate(&[policy], &machines()).unwrap();
+        let peer = SelfNode {
+            addresses: vec!["100.64.0.2/32".to_string()],
+            ..Default::default()
+        };
+        let dst = |net: &str| Dst {
+            net: net.to_string(),
+            ports: FilterPorts {
+                first: 0,
+                last: 65535,
+            },
+        };
+        let netmap = NetMap {
+            peers: Some(vec![peer]),
+            packet_filter: Some(vec![PacketFilter {
+                ipproto: "[6]".to_string(),
+                srcs: vec!["100.64.0.1/32".to_string(), "100.64.0.2".to_string()],
+                dsts: vec![dst("100.64.0.1/32")],
+                caps: vec![],
+            }]),
+            ..Default::default()
+        };
+
+        let mut errors = Errors::new();
+        evaluation.check_netmap(&mut errors, "1", &netmap);
+        assert!(errors.failures().is_empty(), "{:?}", errors.failures());
+
+        let mut errors = Errors::new();
+        evaluation.check_netmap(&mut errors, "2", &netmap);
+        assert_eq!(errors.failures().len(), 2);
+    }
+}
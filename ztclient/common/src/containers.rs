@@ -1,6 +1,15 @@
-use std::{thread::sleep, time::Duration};
+use std::collections::HashMap;
 
-use bollard::{container::RemoveContainerOptions, Docker};
+use bollard::{
+    container::{ListContainersOptions, RemoveContainerOptions},
+    service::ContainerSummary,
+    Docker,
+};
+
+use crate::{
+    context::{block_on_detached, DEBUG_CONTAINERS_VAR},
+    get_labels, Result,
+};
 
 pub struct ContainerRemover {
     pub container_name: String,
@@ -14,18 +23,16 @@ impl ContainerRemover {
 
 impl Drop for ContainerRemover {
     fn drop(&mut self) {
-        println!("Dropping container!!");
-        let docker = Docker::connect_with_local_defaults().unwrap();
         let name = self.container_name.clone();
-        let res = tokio::spawn(async move {
-            println!("Inside dropping closure");
-            remove_container(&docker, &name).await;
+        block_on_detached(async move {
+            if let Ok(docker) = Docker::connect_with_local_defaults() {
+                remove_container(&docker, &name).await;
+            }
         });
-        sleep(Duration::from_secs(1));
     }
 }
 pub async fn remove_container(docker: &Docker, container_name: &str) {
-    let debug_containers = std::env::var("TEST_DEBUG_CONTAINERS").is_ok();
+    let debug_containers = std::env::var(DEBUG_CONTAINERS_VAR).is_ok();
     if debug_containers {
         return;
     }
@@ -40,3 +47,65 @@ pub async fn remove_container(docker: &Docker, container_name: &str) {
         .await;
     if remove_result.is_err() {}
 }
+
+/// Label naming the compose service of a container in the stack that `create-environment`
+/// starts. Clients don't have it.
+pub const SERVICE_LABEL: &str = "com.docker.compose.service";
+
+/// Lists every container, running or stopped, that carries the labels from [`get_labels`] and is
+/// attached to `network_name`, i.e. the clients started by this tool in one environment.
+pub async fn list_labelled_containers(
+    docker: &Docker,
+    network_name: &str,
+) -> Result<Vec<ContainerSummary>> {
+    let containers = list_project_containers(docker, network_name).await?;
+    Ok(containers.into_iter().filter(|c| !is_service(c)).collect())
+}
+
+/// Lists the service containers (Postgres, Kafka, Ninja Panda, ...) that `create-environment`
+/// started on `network_name`.
+pub async fn list_service_containers(
+    docker: &Docker,
+    network_name: &str,
+) -> Result<Vec<ContainerSummary>> {
+    let containers = list_project_containers(docker, network_name).await?;
+    Ok(containers.into_iter().filter(is_service).collect())
+}
+
+fn is_service(summary: &ContainerSummary) -> bool {
+    summary
+        .labels
+        .as_ref()
+        .is_some_and(|labels| labels.contains_key(SERVICE_LABEL))
+}
+
+/// Every container carrying the labels from [`get_labels`] on `network_name`.
+async fn list_project_containers(
+    docker: &Docker,
+    network_name: &str,
+) -> Result<Vec<ContainerSummary>> {
+    let label_filters = get_labels()
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters: HashMap::from([
+                ("label".to_string(), label_filters),
+                ("network".to_string(), vec![network_name.to_string()]),
+            ]),
+            ..Default::default()
+        }))
+        .await?;
+    Ok(containers)
+}
+
+/// The container's name without the leading `/` that the Docker API puts on it.
+pub fn summary_name(summary: &ContainerSummary) -> Option<String> {
+    summary
+        .names
+        .as_ref()?
+        .first()
+        .map(|name| name.trim_start_matches('/').to_string())
+}
@@ -0,0 +1,264 @@
+use std::{future::Future, sync::Mutex};
+
+use bollard::Docker;
+
+use crate::{
+    containers::remove_container,
+    current_environment, get_running_json,
+    models::{CreatePreauthTokenRequest, PreauthToken},
+    ninjapanda::NinjaPandaClient,
+    ztclient::{self, start_ztclientd},
+    Config, Error, Result, RuntimeInformation,
+};
+
+/// When set, nothing a test created is cleaned up, so it can be inspected afterwards.
+pub const DEBUG_CONTAINERS_VAR: &str = "TEST_DEBUG_CONTAINERS";
+
+/// Everything a test created that has to be removed again.
+#[derive(Debug, Default)]
+struct Resources {
+    containers: Vec<String>,
+    machine_ids: Vec<String>,
+    acl_policy_ids: Vec<String>,
+    /// (namespace, key) pairs.
+    preauth_keys: Vec<(String, String)>,
+    namespaces: Vec<String>,
+}
+
+impl Resources {
+    fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+            && self.machine_ids.is_empty()
+            && self.acl_policy_ids.is_empty()
+            && self.preauth_keys.is_empty()
+            && self.namespaces.is_empty()
+    }
+}
+
+/// Hands a test its Docker connection, Ninja Panda client, [`Config`] and runtime information,
+/// and keeps track of what the test creates. When the context is dropped, including while a
+/// failed assertion unwinds, the containers are removed, the machines, ACL policies and
+/// namespaces are deleted from Ninja Panda and the preauth keys are expired. Set
+/// `TEST_DEBUG_CONTAINERS` to keep them.
+///
+/// The `create_*` and `start_*` methods track what they create. Anything created another way can
+/// be registered with the `track_*` methods.
+pub struct TestContext {
+    pub docker: Docker,
+    pub np: NinjaPandaClient,
+    pub config: Config,
+    pub runtime_info: RuntimeInformation,
+    resources: Mutex<Resources>,
+}
+
+impl TestContext {
+    /// Builds a context for the current environment from `.env` and its runtime information.
+    pub fn new() -> Result<TestContext> {
+        dotenv::dotenv().ok();
+        let config = envy::from_env::<Config>()?.for_environment(&current_environment());
+        let runtime_info = get_running_json()?;
+        let docker = Docker::connect_with_unix_defaults()?;
+        Ok(TestContext::from_parts(docker, config, runtime_info))
+    }
+
+    pub fn from_parts(
+        docker: Docker,
+        config: Config,
+        runtime_info: RuntimeInformation,
+    ) -> TestContext {
+        TestContext {
+            np: NinjaPandaClient::from(&runtime_info),
+            docker,
+            config,
+            runtime_info,
+            resources: Mutex::new(Resources::default()),
+        }
+    }
+
+    fn resources(&self) -> std::sync::MutexGuard<'_, Resources> {
+        // A panic while the lock was held doesn't leave the lists inconsistent.
+        self.resources
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn track_container(&self, container_name: &str) {
+        self.resources().containers.push(container_name.to_string());
+    }
+
+    pub fn track_machine(&self, machine_id: &str) {
+        self.resources().machine_ids.push(machine_id.to_string());
+    }
+
+    pub fn track_acl_policy(&self, policy_id: &str) {
+        self.resources().acl_policy_ids.push(policy_id.to_string());
+    }
+
+    pub fn track_preauth_key(&self, namespace_name: &str, key: &str) {
+        self.resources()
+            .preauth_keys
+            .push((namespace_name.to_string(), key.to_string()));
+    }
+
+    /// Only track namespaces that belong to this test: other tests running at the same time may
+    /// be using a shared one such as `optm`.
+    pub fn track_namespace(&self, namespace_name: &str) {
+        self.resources().namespaces.push(namespace_name.to_string());
+    }
+
+    /// Creates a namespace that is deleted again on teardown.
+    pub async fn create_namespace(&self, namespace_name: &str) -> Result<()> {
+        self.np.create_namespace(namespace_name).await?;
+        self.track_namespace(namespace_name);
+        Ok(())
+    }
+
+    pub async fn start_client(&self, container_name: &str) -> Result<()> {
+        self.track_container(container_name);
+        start_ztclientd(&self.docker, &self.config, container_name).await?;
+        Ok(())
+    }
+
+    /// Starts a client and registers it for the given user. Returns the machine ID.
+    pub async fn create_and_register_client(
+        &self,
+        container_name: &str,
+        namespace_name: &str,
+        user_info_id: usize,
+    ) -> Result<String> {
+        self.track_container(container_name);
+        let machine_id = ztclient::create_and_register_client(
+            &self.np,
+            &self.docker,
+            &self.config,
+            container_name,
+            namespace_name,
+            user_info_id,
+        )
+        .await?;
+        self.track_machine(&machine_id);
+        Ok(machine_id)
+    }
+
+    pub async fn make_all_machines_peers(&self, machine_ids: &[String]) -> Result<String> {
+        let policy_id = self.np.make_all_machines_peers(machine_ids).await?;
+        self.track_acl_policy(&policy_id);
+        Ok(policy_id)
+    }
+
+    pub async fn grant_one_directional_policy(
+        &self,
+        machine_id1: &str,
+        machine_id2: &str,
+        port_number: u32,
+    ) -> Result<String> {
+        let policy_id = self
+            .np
+            .grant_one_directional_policy(machine_id1, machine_id2, port_number)
+            .await?;
+        self.track_acl_policy(&policy_id);
+        Ok(policy_id)
+    }
+
+    pub async fn create_preauth_token(
+        &self,
+        request: &CreatePreauthTokenRequest,
+    ) -> Result<PreauthToken> {
+        let token = self.np.create_preauth_token(request).await?;
+        self.track_preauth_key(&token.namespace, &token.key);
+        Ok(token)
+    }
+
+    /// Cleans up now instead of on drop, and reports the first thing that couldn't be removed.
+    pub async fn cleanup(&self) -> Result<()> {
+        if std::env::var(DEBUG_CONTAINERS_VAR).is_ok() {
+            return Ok(());
+        }
+        let resources = std::mem::take(&mut *self.resources());
+        match cleanup_resources(&self.docker, &self.np, resources)
+            .await
+            .into_iter()
+            .next()
+        {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestContext {
+    fn drop(&mut self) {
+        let resources = std::mem::take(&mut *self.resources());
+        if resources.is_empty() {
+            return;
+        }
+        if std::env::var(DEBUG_CONTAINERS_VAR).is_ok() {
+            log::info!("{DEBUG_CONTAINERS_VAR} is set, leaving {resources:?}");
+            return;
+        }
+        let runtime_info = self.runtime_info.clone();
+        block_on_detached(async move {
+            // The test's clients belong to its runtime, which is blocked until we return.
+            let Ok(docker) = Docker::connect_with_unix_defaults() else {
+                log::warn!("Unable to connect to Docker, leaving {resources:?}");
+                return;
+            };
+            let np = NinjaPandaClient::from(&runtime_info);
+            for e in cleanup_resources(&docker, &np, resources).await {
+                log::warn!("Cleanup failed: {e}");
+            }
+        });
+    }
+}
+
+/// Removes everything in `resources`, carrying on past failures, and returns the failures. Not
+/// found errors are ignored since the test may have deleted things itself.
+async fn cleanup_resources(
+    docker: &Docker,
+    np: &NinjaPandaClient,
+    resources: Resources,
+) -> Vec<Error> {
+    let mut errors = Vec::new();
+    let mut keep = |result: Result<()>| match result {
+        Err(e) if e.status() != Some(reqwest::StatusCode::NOT_FOUND) => errors.push(e),
+        _ => (),
+    };
+
+    for container_name in resources.containers.iter() {
+        remove_container(docker, container_name).await;
+    }
+    for machine_id in resources.machine_ids.iter() {
+        keep(np.delete_machine(machine_id).await);
+    }
+    for policy_id in resources.acl_policy_ids.iter() {
+        keep(np.delete_acl_policy(policy_id).await);
+    }
+    for (namespace_name, key) in resources.preauth_keys.iter() {
+        keep(np.expire_preauth_token(namespace_name, key).await);
+    }
+    // Last, since a namespace can't be deleted while it still has machines.
+    for namespace_name in resources.namespaces.iter() {
+        keep(np.delete_namespace(namespace_name).await);
+    }
+    errors
+}
+
+/// Runs `future` to completion on a new thread with its own runtime. Drop impls use this to do
+/// async cleanup without needing, or blocking, the runtime of the test being torn down.
+pub(crate) fn block_on_detached<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = std::thread::spawn(move || {
+        match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime.block_on(future),
+            Err(e) => log::warn!("Unable to start cleanup runtime: {e}"),
+        }
+    });
+    if handle.join().is_err() {
+        log::warn!("Cleanup thread panicked");
+    }
+}
@@ -0,0 +1,538 @@
+//! Brings up the Ninja Panda stack from `docker-compose.yaml` directly through the Docker API, so
+//! that the tester does not need the compose CLI. The observability containers (Jaeger, Zipkin,
+//! the OTEL collector and Prometheus) are left to compose since the tests don't depend on them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bollard::{
+    container::{Config as ContainerConfig, CreateContainerOptions, NetworkingConfig},
+    image::CreateImageOptions,
+    network::CreateNetworkOptions,
+    service::{
+        EndpointSettings, HealthConfig, HostConfig, PortBinding, RestartPolicy,
+        RestartPolicyNameEnum,
+    },
+    Docker,
+};
+use futures::StreamExt;
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    containers::SERVICE_LABEL, get_labels, readiness::wait_for_container, scoped_name, Config,
+    Error, Result, RuntimeContainer, DEFAULT_ENVIRONMENT,
+};
+
+pub const NINJA_PANDA_IMAGE: &str = "ninja-panda:latest";
+pub const NINJA_PANDA2_CONTAINER_NAME: &str = "ztclient_ninja_panda2";
+pub const REDIS_CONTAINER_NAME: &str = "ztclient_redis";
+pub const NGINX_CONTAINER_NAME: &str = "ztclient_nginx";
+
+const SERVICE_READY_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// The knobs `create-environment` exposes on top of the `.env` [`Config`].
+#[derive(Debug, Clone)]
+pub struct EnvironmentSettings {
+    /// Host port nginx publishes the Ninja Panda API on in the default environment.
+    pub ninja_panda_port: u32,
+    /// Host port Postgres is published on in the default environment.
+    pub database_port: u32,
+    /// Host port Kafka is published and advertised on. Kafka hands this address to host-side
+    /// clients, so unlike the other ports it can't be left to Docker; see [`free_host_port`].
+    pub kafka_port: u32,
+    /// Pause between the backends becoming healthy and starting Ninja Panda.
+    pub backend_settle_time: Duration,
+    /// Directory holding `nginx/` and the other service configuration.
+    pub conf_dir: PathBuf,
+    /// Environment the stack belongs to, see [`scoped_name`].
+    pub environment_id: String,
+}
+
+/// One container of the stack, equivalent to a service entry in `docker-compose.yaml`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceSpec {
+    pub container_name: String,
+    /// The compose service name, also recorded in the [`SERVICE_LABEL`] label.
+    pub service: String,
+    pub image: String,
+    /// Extra names the container answers to on the network: the compose service name and, in a
+    /// named environment, the unscoped container name that nginx.conf and the clients use.
+    pub aliases: Vec<String>,
+    pub env: Vec<String>,
+    pub user: Option<String>,
+    /// (container port, host port) pairs. Without a host port Docker picks a free one.
+    pub ports: Vec<(u32, Option<u32>)>,
+    pub binds: Vec<String>,
+    pub extra_hosts: Vec<String>,
+    /// Shell command run by the Docker healthcheck. Services without one are ready once running.
+    pub healthcheck: Option<String>,
+}
+
+/// The services in the order they have to come up.
+#[derive(Debug, Clone)]
+pub struct Stack {
+    pub backends: Vec<ServiceSpec>,
+    pub ninja_pandas: Vec<ServiceSpec>,
+    pub proxy: ServiceSpec,
+}
+
+impl Stack {
+    /// Names of all the containers, in start order.
+    pub fn container_names(&self) -> Vec<String> {
+        self.backends
+            .iter()
+            .chain(self.ninja_pandas.iter())
+            .chain(std::iter::once(&self.proxy))
+            .map(|service| service.container_name.clone())
+            .collect()
+    }
+
+    /// Builds the stack for `settings.environment_id` from the unscoped `.env` configuration.
+    pub fn new(config: &Config, settings: &EnvironmentSettings) -> Stack {
+        let environment_id = settings.environment_id.as_str();
+        let is_default = environment_id == DEFAULT_ENVIRONMENT;
+        // Only the default environment claims the fixed host ports from docker-compose.yaml, apart
+        // from Kafka's, which the caller picks.
+        let fixed_port = |port: u32| is_default.then_some(port);
+        let aliases = |service: &str, container_name: &str| {
+            let mut aliases = vec![service.to_string()];
+            if !is_default {
+                aliases.push(container_name.to_string());
+            }
+            aliases
+        };
+
+        let kafka = ServiceSpec {
+            container_name: scoped_name(&config.kafka_container_name, environment_id),
+            image: "bitnami/kafka:latest".to_string(),
+            service: "kafka".to_string(),
+            aliases: aliases("kafka", &config.kafka_container_name),
+            env: [
+                "KAFKA_ENABLE_KRAFT=yes",
+                "KAFKA_CFG_NODE_ID=1",
+                "KAFKA_CFG_PROCESS_ROLES=broker,controller",
+                "KAFKA_CFG_CONTROLLER_LISTENER_NAMES=CONTROLLER",
+                "KAFKA_CFG_LISTENERS=PLAINTEXT://:9092,CONTROLLER://kafka:9093,DOCKER://kafka:9094",
+                "KAFKA_CFG_LISTENER_SECURITY_PROTOCOL_MAP=CONTROLLER:PLAINTEXT,PLAINTEXT:PLAINTEXT,DOCKER:PLAINTEXT",
+                "KAFKA_BROKER_ID=1",
+                "KAFKA_CFG_CONTROLLER_QUORUM_VOTERS=1@kafka:9093",
+                "ALLOW_PLAINTEXT_LISTENER=yes",
+                "KAFKA_OFFSETS_TOPIC_REPLICATION_FACTOR=1",
+            ]
+            .map(str::to_string)
+            .into_iter()
+            .chain(std::iter::once(format!(
+                "KAFKA_CFG_ADVERTISED_LISTENERS=PLAINTEXT://127.0.0.1:{},DOCKER://kafka:9094",
+                settings.kafka_port
+            )))
+            .collect(),
+            ports: vec![(9092, Some(settings.kafka_port))],
+            healthcheck: Some(
+                "/opt/bitnami/kafka/bin/kafka-topics.sh --bootstrap-server kafka:9094 --describe"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let postgres = ServiceSpec {
+            container_name: scoped_name(&config.postgres_container_name, environment_id),
+            image: "postgres:14-alpine".to_string(),
+            service: "postgres".to_string(),
+            aliases: aliases("postgres", &config.postgres_container_name),
+            env: vec![
+                format!("POSTGRES_PASSWORD={}", config.postgres_password),
+                format!("POSTGRES_USER={}", config.postgres_user),
+                format!("POSTGRES_NAME={}", config.postgres_name),
+                format!("POSTGRES_DB={}", config.postgres_db),
+            ],
+            user: Some("postgres".to_string()),
+            ports: vec![(5432, fixed_port(settings.database_port))],
+            healthcheck: Some("pg_isready -d postgres".to_string()),
+            ..Default::default()
+        };
+        let redis = ServiceSpec {
+            container_name: scoped_name(REDIS_CONTAINER_NAME, environment_id),
+            image: "redis:latest".to_string(),
+            service: "redis".to_string(),
+            aliases: aliases("redis", REDIS_CONTAINER_NAME),
+            ports: vec![(6379, fixed_port(6379))],
+            ..Default::default()
+        };
+
+        let ninja_panda_env = vec![
+            format!("NINJA_DB_HOST={}", config.postgres_container_name),
+            format!("NINJA_DB_PASS={}", config.ninja_postgres_password),
+            format!("NINJA_DB_NAME={}", config.ninja_postgres_name),
+            "NINJA_LOG_LEVEL=trace".to_string(),
+            format!("NINJA_MACHINE_AUTH_URL={}", config.ninja_machine_auth_url),
+            "NINJA_GEOCODING_ENABLED=false".to_string(),
+            "NINJA_METRICS_LISTEN_ADDR=0.0.0.0:9090".to_string(),
+            "NINJA_OTEL_EXPORTER_OTLP_ENDPOINT=otel-collector:4317".to_string(),
+            "NINJA_RELAY_FILE_URLS=https://resources-dev.cyberight.net/relay.json".to_string(),
+            "CACHE_TYPE=redis".to_string(),
+            format!("CACHE_ADDRESS={REDIS_CONTAINER_NAME}:6379"),
+            format!(
+                "KAFKA_BOOTSTRAP_SERVER={}:9094",
+                config.kafka_container_name
+            ),
+        ];
+        let ninja_pandas = [
+            ("ninjapanda01", config.ninja_panda_container_name.as_str()),
+            ("ninjapanda02", NINJA_PANDA2_CONTAINER_NAME),
+        ]
+        .into_iter()
+        .map(|(service, container_name)| ServiceSpec {
+            container_name: scoped_name(container_name, environment_id),
+            image: NINJA_PANDA_IMAGE.to_string(),
+            service: service.to_string(),
+            aliases: aliases(service, container_name),
+            env: ninja_panda_env.clone(),
+            healthcheck: Some("ninjapanda health".to_string()),
+            ..Default::default()
+        })
+        .collect();
+
+        let proxy = ServiceSpec {
+            container_name: scoped_name(NGINX_CONTAINER_NAME, environment_id),
+            image: "nginx:latest".to_string(),
+            service: "nginx".to_string(),
+            aliases: aliases("nginx", NGINX_CONTAINER_NAME),
+            ports: vec![
+                (80, fixed_port(settings.ninja_panda_port)),
+                (443, fixed_port(15200)),
+            ],
+            binds: vec![format!(
+                "{}:/etc/nginx",
+                settings.conf_dir.join("nginx").display()
+            )],
+            // nginx.conf lists the host as a fallback upstream.
+            extra_hosts: vec!["host.docker.internal:host-gateway".to_string()],
+            ..Default::default()
+        };
+
+        Stack {
+            backends: vec![redis, kafka, postgres],
+            ninja_pandas,
+            proxy,
+        }
+    }
+}
+
+/// Asks the OS for a free TCP port on the host, for services that need to know their host port
+/// before they start. Another process could take it in the meantime, which creating the
+/// container then reports.
+pub fn free_host_port() -> Result<u32> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    Ok(listener.local_addr()?.port() as u32)
+}
+
+/// Finds the `conf` directory next to `docker-compose.yaml`, from either the repository root or
+/// a crate directory.
+pub fn find_conf_dir() -> Result<PathBuf> {
+    let candidates = ["./conf", "../../conf"];
+    candidates
+        .iter()
+        .map(Path::new)
+        .find(|path| path.join("nginx").is_dir())
+        .map(|path| path.canonicalize())
+        .unwrap_or_else(|| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no conf directory in any of {candidates:?}"),
+            ))
+        })
+        .map_err(Error::from)
+}
+
+/// Creates the network, then starts every service of `stack` and waits for it to be healthy.
+/// Containers left over from a previous run are replaced.
+pub async fn bring_up(
+    docker: &Docker,
+    network_name: &str,
+    stack: &Stack,
+    backend_settle_time: Duration,
+) -> Result<()> {
+    ensure_network(docker, network_name).await?;
+
+    for service in stack.backends.iter() {
+        start_service(docker, network_name, service).await?;
+    }
+    let deadline = Instant::now() + SERVICE_READY_TIMEOUT;
+    for service in stack.backends.iter() {
+        wait_for_container(docker, &service.container_name, deadline).await?;
+    }
+    sleep(backend_settle_time).await;
+
+    // The second replica waits for the first to have run its migrations.
+    for service in stack.ninja_pandas.iter() {
+        start_service(docker, network_name, service).await?;
+        let deadline = Instant::now() + SERVICE_READY_TIMEOUT;
+        wait_for_container(docker, &service.container_name, deadline).await?;
+    }
+
+    start_service(docker, network_name, &stack.proxy).await?;
+    let deadline = Instant::now() + SERVICE_READY_TIMEOUT;
+    wait_for_container(docker, &stack.proxy.container_name, deadline).await
+}
+
+async fn ensure_network(docker: &Docker, network_name: &str) -> Result<()> {
+    if docker
+        .inspect_network::<String>(network_name, None)
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+    log::info!("Creating network {network_name}");
+    docker
+        .create_network(CreateNetworkOptions {
+            name: network_name,
+            check_duplicate: true,
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+async fn ensure_image(docker: &Docker, image: &str) -> Result<()> {
+    if docker.inspect_image(image).await.is_ok() {
+        return Ok(());
+    }
+    log::info!("Pulling {image}");
+    let mut pull = docker.create_image(
+        Some(CreateImageOptions {
+            from_image: image,
+            ..Default::default()
+        }),
+        None,
+        None,
+    );
+    while let Some(progress) = pull.next().await {
+        progress?;
+    }
+    Ok(())
+}
+
+async fn start_service(docker: &Docker, network_name: &str, service: &ServiceSpec) -> Result<()> {
+    ensure_image(docker, &service.image).await?;
+
+    // Unlike remove_container this ignores TEST_DEBUG_CONTAINERS: a stale service has to go.
+    let _ = docker
+        .remove_container(
+            &service.container_name,
+            Some(bollard::container::RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await;
+
+    let port_bindings = service
+        .ports
+        .iter()
+        .map(|(container_port, host_port)| {
+            (
+                format!("{container_port}/tcp"),
+                Some(vec![PortBinding {
+                    host_ip: None,
+                    host_port: host_port.map(|port| port.to_string()),
+                }]),
+            )
+        })
+        .collect();
+    let exposed_ports = service
+        .ports
+        .iter()
+        .map(|(container_port, _)| (format!("{container_port}/tcp"), HashMap::new()))
+        .collect();
+    let healthcheck = service.healthcheck.as_ref().map(|test| HealthConfig {
+        test: Some(vec!["CMD-SHELL".to_string(), test.clone()]),
+        interval: Some(Duration::from_secs(10).as_nanos() as i64),
+        timeout: Some(Duration::from_secs(2).as_nanos() as i64),
+        retries: Some(3),
+        start_period: Some(Duration::from_secs(15).as_nanos() as i64),
+    });
+
+    // The project label groups the stack with the clients, the service label tells them apart.
+    let mut labels: HashMap<String, String> = get_labels()
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    labels.insert(SERVICE_LABEL.to_string(), service.service.clone());
+
+    let config = ContainerConfig {
+        image: Some(service.image.clone()),
+        labels: Some(labels),
+        hostname: Some(service.container_name.clone()),
+        env: Some(service.env.clone()),
+        user: service.user.clone(),
+        exposed_ports: Some(exposed_ports),
+        healthcheck,
+        host_config: Some(HostConfig {
+            port_bindings: Some(port_bindings),
+            binds: Some(service.binds.clone()),
+            extra_hosts: Some(service.extra_hosts.clone()),
+            restart_policy: Some(RestartPolicy {
+                name: Some(RestartPolicyNameEnum::ALWAYS),
+                maximum_retry_count: None,
+            }),
+            ..Default::default()
+        }),
+        networking_config: Some(NetworkingConfig {
+            endpoints_config: HashMap::from([(
+                network_name.to_string(),
+                EndpointSettings {
+                    aliases: Some(service.aliases.clone()),
+                    ..Default::default()
+                },
+            )]),
+        }),
+        ..Default::default()
+    };
+
+    log::info!("Starting {}", service.container_name);
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: service.container_name.as_str(),
+                ..Default::default()
+            }),
+            config,
+        )
+        .await?;
+    docker
+        .start_container::<String>(&service.container_name, None)
+        .await?;
+    Ok(())
+}
+
+/// Reads back the image and published ports of each container, for the runtime information.
+pub async fn describe_containers(
+    docker: &Docker,
+    container_names: &[String],
+) -> Result<Vec<RuntimeContainer>> {
+    let mut containers = Vec::new();
+    for name in container_names.iter() {
+        let inspect = docker.inspect_container(name, None).await?;
+        let image = inspect
+            .config
+            .and_then(|config| config.image)
+            .unwrap_or_default();
+        let ports = inspect
+            .network_settings
+            .and_then(|settings| settings.ports)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(container_port, bindings)| {
+                let host_port = bindings?.into_iter().find_map(|b| b.host_port)?;
+                Some((container_port, host_port))
+            })
+            .collect();
+        containers.push(RuntimeContainer {
+            name: name.clone(),
+            image,
+            ports,
+        });
+    }
+    Ok(containers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            ninja_panda_container_name: "ztclient_ninja_panda1".to_string(),
+            postgres_container_name: "ztclient_postgres".to_string(),
+            postgres_user: "ninjaadmin".to_string(),
+            postgres_name: "ninjapanda".to_string(),
+            postgres_db: "ninjapanda".to_string(),
+            postgres_password: "secret".to_string(),
+            docker_network_name: "ztclient-tester".to_string(),
+            ztclient_image: "ztclient-tester:latest".to_string(),
+            ninja_postgres_name: "ninjapanda".to_string(),
+            ninja_postgres_password: "secret".to_string(),
+            ninja_machine_auth_url: "http://localhost:3007/login".to_string(),
+            kafka_container_name: "ztclient_kafka".to_string(),
+        }
+    }
+
+    #[test]
+    fn stack_uses_settings_and_config() {
+        let settings = EnvironmentSettings {
+            ninja_panda_port: 16000,
+            database_port: 16100,
+            kafka_port: 9092,
+            backend_settle_time: Duration::ZERO,
+            conf_dir: PathBuf::from("/repo/conf"),
+            environment_id: DEFAULT_ENVIRONMENT.to_string(),
+        };
+        let stack = Stack::new(&config(), &settings);
+
+        let postgres = stack
+            .backends
+            .iter()
+            .find(|s| s.container_name == "ztclient_postgres")
+            .unwrap();
+        assert_eq!(postgres.ports, vec![(5432, Some(16100))]);
+        assert!(postgres
+            .env
+            .contains(&"POSTGRES_PASSWORD=secret".to_string()));
+
+        assert_eq!(stack.ninja_pandas.len(), 2);
+        assert_eq!(
+            stack.ninja_pandas[0].container_name,
+            "ztclient_ninja_panda1"
+        );
+        assert!(stack.ninja_pandas[1]
+            .env
+            .contains(&"KAFKA_BOOTSTRAP_SERVER=ztclient_kafka:9094".to_string()));
+
+        assert_eq!(stack.proxy.ports[0], (80, Some(16000)));
+        assert_eq!(stack.proxy.binds, vec!["/repo/conf/nginx:/etc/nginx"]);
+    }
+
+    #[test]
+    fn named_environment_is_scoped() {
+        let settings = EnvironmentSettings {
+            ninja_panda_port: 16000,
+            database_port: 16100,
+            kafka_port: 32000,
+            backend_settle_time: Duration::ZERO,
+            conf_dir: PathBuf::from("/repo/conf"),
+            environment_id: "ci".to_string(),
+        };
+        let stack = Stack::new(&config(), &settings);
+
+        assert_eq!(stack.proxy.container_name, "ztclient_nginx-ci");
+        assert!(stack.proxy.aliases.contains(&"ztclient_nginx".to_string()));
+        assert_eq!(stack.proxy.ports, vec![(80, None), (443, None)]);
+        assert_eq!(
+            stack.ninja_pandas[1].container_name,
+            "ztclient_ninja_panda2-ci"
+        );
+        assert!(stack
+            .backends
+            .iter()
+            .all(|s| s.container_name.ends_with("-ci")));
+        assert!(stack
+            .backends
+            .iter()
+            .filter(|s| s.service != "kafka")
+            .flat_map(|s| s.ports.iter())
+            .all(|(_, host_port)| host_port.is_none()));
+        let kafka = stack
+            .backends
+            .iter()
+            .find(|s| s.service == "kafka")
+            .unwrap();
+        assert_eq!(kafka.ports, vec![(9092, Some(32000))]);
+        assert!(kafka.env.contains(
+            &"KAFKA_CFG_ADVERTISED_LISTENERS=PLAINTEXT://127.0.0.1:32000,DOCKER://kafka:9094"
+                .to_string()
+        ));
+    }
+}
@@ -1,61 +1,251 @@
-use crate::{models::status::StatusUserInfo, users::UserInfo};
+use std::{fmt, panic::Location, time::Duration};
 
+use reqwest::{Method, StatusCode};
+
+use crate::{
+    models::status::{BackendState, StatusUserInfo},
+    users::UserInfo,
+};
+
+/// Error type returned by every fallible function in this crate.  Tests can match on the
+/// variant to assert that a call failed in a specific way instead of unwinding.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The Docker daemon rejected a request (create, start, exec, inspect, ...).
+    #[error("docker error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    /// A command executed inside a container did not produce what we needed.
+    #[error("`{command}` in container {container} failed: {message}")]
+    Exec {
+        container: String,
+        command: String,
+        message: String,
+    },
+    /// The request to Ninja Panda never produced a response.
+    #[error("{method} {url} failed: {source}")]
+    Http {
+        method: Method,
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    /// Ninja Panda answered with a non-success HTTP status.
+    #[error("{method} {url} returned {status}: {body}")]
+    NinjaPanda {
+        method: Method,
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+    /// A payload could not be decoded.  `payload` holds the raw text that was received.
+    #[error("unable to decode {what}: {source}\n{payload}")]
+    Decode {
+        what: String,
+        payload: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// Waiting for a condition took longer than allowed.
+    #[error("timed out after {elapsed:?} waiting for {what}")]
+    Timeout { what: String, elapsed: Duration },
+    /// A client did not get to the wanted backend state in time.  `observed` lists the states it
+    /// went through, without repeats, and `last_error` why the last status query failed, if it
+    /// did.
+    #[error(
+        "timed out after {elapsed:?} waiting for {container} to be {wanted}, saw {}{}",
+        join_states(observed),
+        last_error.as_ref().map(|e| format!(" (last error: {e})")).unwrap_or_default()
+    )]
+    StateTimeout {
+        container: String,
+        wanted: String,
+        observed: Vec<BackendState>,
+        last_error: Option<String>,
+        elapsed: Duration,
+    },
+    /// A service container stopped or failed its healthcheck while the environment was coming up.
+    #[error("{container} is {state}")]
+    ServiceUnhealthy { container: String, state: String },
+    /// The data came back but did not match what the caller required.
+    #[error("verification failed: {0}")]
+    Verification(String),
+    /// Some of a batch of clients could not be started or registered.
+    #[error("{0}")]
+    ClientsFailed(String),
+    /// A scenario file could not be parsed or refers to things it doesn't declare.
+    #[error("invalid scenario: {0}")]
+    Scenario(String),
+    /// A setting given on the command line is out of range.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("unable to read configuration: {0}")]
+    Config(#[from] envy::Error),
+    #[error("unable to read runtime information from any of {0:?}")]
+    RuntimeInformationNotFound(Vec<String>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// The HTTP status returned by Ninja Panda, if the request got that far.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::NinjaPanda { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout { .. } | Error::StateTimeout { .. })
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn join_states(states: &[BackendState]) -> String {
+    let states: Vec<String> = states.iter().map(ToString::to_string).collect();
+    states.join(" -> ")
+}
+
+/// One check made through [`Errors`], passed or not.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckRecord {
+    pub name: String,
+    /// The container the check was about, see [`Errors::in_container`].
+    pub container: Option<String>,
+    pub expected: String,
+    pub actual: String,
+    /// `file:line:column` of the call that made the check.
+    pub location: String,
+    pub passed: bool,
+}
+
+impl fmt::Display for CheckRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.name)?;
+        if let Some(container) = &self.container {
+            write!(f, " [{container}]")?;
+        }
+        write!(f, ": expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+/// Collects checks instead of stopping at the first failing one.  Every check is recorded with
+/// where it was made, so [`Errors::assert_pop`] can say which ones failed and a report can list
+/// them all; see [`crate::report`].
 #[derive(Default)]
 pub struct Errors {
-    pub strings: Vec<String>,
+    pub checks: Vec<CheckRecord>,
+    container: Option<String>,
 }
 
 impl Errors {
     pub fn new() -> Errors {
-        Errors {
-            strings: Vec::new(),
-        }
+        Errors::default()
+    }
+
+    /// Attributes the checks that follow to `container_name`.
+    pub fn in_container(&mut self, container_name: &str) {
+        self.container = Some(container_name.to_string());
     }
+
+    pub fn failures(&self) -> Vec<&CheckRecord> {
+        self.checks.iter().filter(|c| !c.passed).collect()
+    }
+
+    /// Records a check made by the caller's caller.
+    #[track_caller]
+    pub fn record(&mut self, name: &str, expected: String, actual: String, passed: bool) {
+        let location = Location::caller();
+        self.checks.push(CheckRecord {
+            name: name.to_string(),
+            container: self.container.clone(),
+            expected,
+            actual,
+            location: format!(
+                "{}:{}:{}",
+                location.file(),
+                location.line(),
+                location.column()
+            ),
+            passed,
+        });
+    }
+
+    #[track_caller]
+    pub fn eq<T: PartialEq + fmt::Debug>(&mut self, name: &str, expected: T, actual: T) {
+        let passed = expected == actual;
+        self.record(name, format!("{expected:?}"), format!("{actual:?}"), passed);
+    }
+
+    #[track_caller]
     pub fn add_error(&mut self, err: String) {
-        self.strings.push(err);
+        self.record(&err, "no error".to_string(), "error".to_string(), false);
     }
 
+    #[track_caller]
     pub fn string_eq_assert(&mut self, str1: String, str2: String) {
-        if str1 != str2 {
-            let res = format!("{}!={}", str1, str2);
-            self.strings.push(res);
-        }
+        self.string_slice_eq_assert(&str1, &str2);
     }
+    #[track_caller]
     pub fn string_slice_eq_assert(&mut self, str1: &str, str2: &str) {
-        if str1 != str2 {
-            let res = format!("{}!={}", str1, str2);
-            self.strings.push(res);
-        }
+        self.record(
+            "strings equal",
+            str1.to_string(),
+            str2.to_string(),
+            str1 == str2,
+        );
     }
+    #[track_caller]
     pub fn bool_assert(&mut self, check: bool, reason: String) {
-        if !check {
-            self.strings.push(reason);
-        }
+        self.record(&reason, "true".to_string(), check.to_string(), check);
     }
+    /// Panics, listing every failed check, if any check failed.  With `ZTCLIENT_REPORT_DIR` set,
+    /// writes a JUnit report of all checks there first.
     pub fn assert_pop(&mut self) {
-        if !self.strings.is_empty() {
-            dbg!(&self.strings);
-            panic!();
+        crate::report::write_test_report(self);
+        let failures = self.failures();
+        if !failures.is_empty() {
+            let lines: Vec<String> = failures.iter().map(|c| c.to_string()).collect();
+            panic!("{} checks failed:\n{}", failures.len(), lines.join("\n"));
         }
     }
-    pub fn num_eq_assert<T: PartialEq>(&mut self, num1: T, num2: T, comment: &str) {
-        if num1 != num2 {
-            self.add_error(comment.to_string());
-        }
+    #[track_caller]
+    pub fn num_eq_assert<T: PartialEq + fmt::Debug>(&mut self, num1: T, num2: T, comment: &str) {
+        self.eq(comment, num1, num2);
     }
+    #[track_caller]
     pub fn expect_none<T>(&mut self, opt: &Option<T>, field_name: &str) {
-        if opt.is_some() {
-            self.add_error(format!("{} expected to be None, was Some", field_name))
-        }
+        let actual = if opt.is_some() { "Some" } else { "None" };
+        self.record(
+            field_name,
+            "None".to_string(),
+            actual.to_string(),
+            opt.is_none(),
+        );
     }
+    #[track_caller]
     pub fn expect_some<T>(&mut self, opt: &Option<T>, field_name: &str) {
-        if opt.is_none() {
-            self.add_error(format!("{} expected to be Some, was None", field_name))
-        }
+        let actual = if opt.is_some() { "Some" } else { "None" };
+        self.record(
+            field_name,
+            "Some".to_string(),
+            actual.to_string(),
+            opt.is_some(),
+        );
     }
+    #[track_caller]
     pub fn verify_user(&mut self, user_object: &StatusUserInfo, user_info: &UserInfo) {
-        self.string_slice_eq_assert(&user_info.first_name, &user_object.first_name);
-        self.string_slice_eq_assert(&user_info.last_name, &user_object.last_name);
+        self.eq(
+            "first name",
+            user_info.first_name.as_str(),
+            user_object.first_name.as_str(),
+        );
+        self.eq(
+            "last name",
+            user_info.last_name.as_str(),
+            user_object.last_name.as_str(),
+        );
     }
 }
 
@@ -63,19 +253,88 @@ impl Errors {
 mod tests {
     use super::*;
 
+    #[test]
+    fn status_only_for_ninja_panda_errors() {
+        let err = Error::NinjaPanda {
+            method: Method::POST,
+            url: "http://localhost:15000/api/v1/preauthkey".to_string(),
+            status: StatusCode::UNAUTHORIZED,
+            body: "expired".to_string(),
+        };
+        assert_eq!(Some(StatusCode::UNAUTHORIZED), err.status());
+        assert_eq!(
+            "POST http://localhost:15000/api/v1/preauthkey returned 401 Unauthorized: expired",
+            err.to_string()
+        );
+
+        let err = Error::Timeout {
+            what: "Running".to_string(),
+            elapsed: Duration::from_secs(1),
+        };
+        assert_eq!(None, err.status());
+        assert!(err.is_timeout());
+    }
+
+    #[test]
+    fn decode_error_keeps_payload() {
+        let source = serde_json::from_str::<u32>("nope").unwrap_err();
+        let err = Error::Decode {
+            what: "status --json".to_string(),
+            payload: "nope".to_string(),
+            source,
+        };
+        assert!(err.to_string().ends_with("\nnope"));
+    }
+
+    #[test]
+    fn state_timeout_lists_states_and_last_error() {
+        let err = Error::StateTimeout {
+            container: "ztclienthost001".to_string(),
+            wanted: "Running".to_string(),
+            observed: vec![BackendState::NoState, BackendState::NeedsLogin],
+            last_error: Some("exec timed out".to_string()),
+            elapsed: Duration::from_secs(2),
+        };
+        assert!(err.is_timeout());
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "timed out after 2s waiting for ztclienthost001 to be Running, saw {} -> {} \
+                 (last error: exec timed out)",
+                BackendState::NoState,
+                BackendState::NeedsLogin
+            )
+        );
+    }
+
     #[test]
     fn test_empty() {
         let err = Errors::new();
-        assert!(err.strings.is_empty());
+        assert!(err.failures().is_empty());
     }
 
     #[test]
     fn test_empty_and_pop() {
         let mut err = Errors::new();
-        assert!(err.strings.is_empty());
+        assert!(err.failures().is_empty());
         err.assert_pop();
     }
 
+    #[test]
+    fn checks_record_values_and_location() {
+        let mut err = Errors::new();
+        err.in_container("zt001");
+        err.num_eq_assert(3, 2, "peer count");
+        err.expect_some(&Some(1), "peers");
+        assert_eq!(err.checks.len(), 2);
+        let failures = err.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].expected, "3");
+        assert_eq!(failures[0].actual, "2");
+        assert_eq!(failures[0].container.as_deref(), Some("zt001"));
+        assert!(failures[0].location.starts_with(file!()), "{}", failures[0]);
+    }
+
     #[test]
     fn test_add_equal_strings_empty() {
         let mut err = Errors::new();
@@ -84,7 +343,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
+    #[should_panic(expected = "expected String2, got String1")]
     fn test_add_unequal_strings_panic() {
         let mut err = Errors::new();
         err.string_eq_assert("String2".to_string(), "String1".to_string());
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use bollard::{
+    container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
+    Docker,
+};
+use futures::StreamExt;
+
+use crate::{Error, Result};
+
+/// Everything a command run with `docker exec` wrote, with the two streams kept apart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the command was still running when we stopped reading (see [`exec_until`]).
+    pub exit_code: Option<i64>,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// stdout followed by stderr, for callers that only care about what was printed.
+    pub fn combined(&self) -> String {
+        format!("{}{}", self.stdout, self.stderr)
+    }
+}
+
+/// The bytes of both streams as they arrive.  A frame can end in the middle of a UTF-8
+/// character, so the frames are only decoded together.
+#[derive(Debug, Default)]
+struct OutputBuffer {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl OutputBuffer {
+    fn push(&mut self, frame: LogOutput) {
+        match frame {
+            LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                self.stdout.extend_from_slice(&message)
+            }
+            LogOutput::StdErr { message } => self.stderr.extend_from_slice(&message),
+            LogOutput::StdIn { .. } => {}
+        }
+    }
+
+    fn decode(&self) -> ExecOutput {
+        ExecOutput {
+            stdout: String::from_utf8_lossy(&self.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+            exit_code: None,
+        }
+    }
+}
+
+/// Runs `cmd` in `container_name`, drains all of its output and fetches the exit code.
+///
+/// With a `timeout`, returns [`Error::Timeout`] if the command hasn't finished in time.
+pub async fn exec_in_container(
+    docker: &Docker,
+    container_name: &str,
+    cmd: &[&str],
+    timeout: Option<Duration>,
+) -> Result<ExecOutput> {
+    exec_until(docker, container_name, cmd, timeout, |_| false).await
+}
+
+/// Like [`exec_in_container`], but stops reading as soon as `done` returns true for the output
+/// collected so far. This is for commands such as `ztclient connect` that print what we need
+/// and then block; the exec is left running and `exit_code` is `None`.
+pub async fn exec_until<F>(
+    docker: &Docker,
+    container_name: &str,
+    cmd: &[&str],
+    timeout: Option<Duration>,
+    mut done: F,
+) -> Result<ExecOutput>
+where
+    F: FnMut(&ExecOutput) -> bool,
+{
+    let exec = docker
+        .create_exec(
+            container_name,
+            CreateExecOptions {
+                cmd: Some(cmd.to_vec()),
+                attach_stderr: Some(true),
+                attach_stdout: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let mut buffer = OutputBuffer::default();
+    let drain = async {
+        if let StartExecResults::Attached {
+            output: mut stream, ..
+        } = docker.start_exec(&exec.id, None).await?
+        {
+            while let Some(frame) = stream.next().await {
+                buffer.push(frame?);
+                if done(&buffer.decode()) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok::<_, Error>(true)
+    };
+
+    let finished = match timeout {
+        Some(limit) => tokio::time::timeout(limit, drain)
+            .await
+            .map_err(|_| Error::Timeout {
+                what: format!("`{}` in {container_name}", cmd.join(" ")),
+                elapsed: limit,
+            })??,
+        None => drain.await?,
+    };
+
+    let mut output = buffer.decode();
+    if finished {
+        output.exit_code = docker.inspect_exec(&exec.id).await?.exit_code;
+    }
+    log::debug!(
+        "exec `{}` in {container_name}: exit {:?}",
+        cmd.join(" "),
+        output.exit_code
+    );
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_appended_per_stream() {
+        let mut buffer = OutputBuffer::default();
+        buffer.push(LogOutput::StdOut {
+            message: "first ".into(),
+        });
+        buffer.push(LogOutput::StdErr {
+            message: "oops\n".into(),
+        });
+        buffer.push(LogOutput::StdOut {
+            message: "second\n".into(),
+        });
+        let output = buffer.decode();
+        assert_eq!(output.stdout, "first second\n");
+        assert_eq!(output.stderr, "oops\n");
+        assert!(!output.success());
+    }
+
+    #[test]
+    fn characters_split_across_frames() {
+        let mut buffer = OutputBuffer::default();
+        let text = "caf\u{e9} \u{2713}\n".as_bytes();
+        for byte in text {
+            buffer.push(LogOutput::StdOut {
+                message: vec![*byte].into(),
+            });
+        }
+        assert_eq!(buffer.decode().stdout, "caf\u{e9} \u{2713}\n");
+    }
+}
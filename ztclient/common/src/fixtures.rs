@@ -0,0 +1,189 @@
+//! rstest fixtures shared by the integration tests. Import the ones a test needs, e.g.
+//! `use ztclient_common::fixtures::{ctx, docker};`, and name them as test arguments.
+//!
+//! Fixtures don't share instances with each other, so the higher-level ones own the
+//! [`TestContext`] they set things up with and deref to it.
+
+use std::ops::Deref;
+
+use bollard::Docker;
+use rstest::fixture;
+
+use crate::{
+    context::TestContext,
+    current_environment, get_running_json,
+    launch::{launch_clients, DEFAULT_PARALLELISM},
+    models::status::BackendState,
+    ninjapanda::NinjaPandaClient,
+    random_container_name,
+    ztclient::{numbered_client_names, user_for_client, wait_for_state_change},
+    Config, RuntimeInformation,
+};
+
+#[fixture]
+pub fn runtime_info() -> RuntimeInformation {
+    get_running_json().expect("Unable to read runtime.json, is environment created?")
+}
+
+#[fixture]
+pub fn docker() -> Docker {
+    Docker::connect_with_unix_defaults().unwrap()
+}
+
+#[fixture]
+pub fn config() -> Config {
+    dotenv::dotenv().ok();
+
+    match envy::from_env::<Config>() {
+        Ok(config) => config.for_environment(&current_environment()),
+        Err(error) => panic!("{:#?}", error),
+    }
+}
+
+#[fixture]
+pub fn np(runtime_info: RuntimeInformation) -> NinjaPandaClient {
+    NinjaPandaClient::from(&runtime_info)
+}
+
+#[fixture]
+pub fn ctx() -> TestContext {
+    TestContext::new().expect("Unable to build test context, is environment created?")
+}
+
+/// A namespace of its own, deleted again with everything in it when the test ends.
+pub struct Namespaced {
+    pub ctx: TestContext,
+    pub namespace: String,
+}
+
+impl Deref for Namespaced {
+    type Target = TestContext;
+
+    fn deref(&self) -> &TestContext {
+        &self.ctx
+    }
+}
+
+#[fixture]
+pub async fn namespaced(ctx: TestContext) -> Namespaced {
+    let namespace = format!("test{}", random_container_name());
+    ctx.create_namespace(&namespace)
+        .await
+        .expect("Unable to create namespace");
+    Namespaced { ctx, namespace }
+}
+
+/// A started client and the machine it registered as.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub container_name: String,
+    pub machine_id: String,
+}
+
+/// `num_clients` clients registered in a fresh namespace and in the Running state. Client `n`
+/// is registered as user `n % 9`, see [`user_for_client`].
+pub struct RunningClients {
+    pub ctx: TestContext,
+    pub namespace: String,
+    pub clients: Vec<Client>,
+}
+
+impl RunningClients {
+    pub fn container_names(&self) -> Vec<String> {
+        self.clients
+            .iter()
+            .map(|c| c.container_name.clone())
+            .collect()
+    }
+
+    pub fn machine_ids(&self) -> Vec<String> {
+        self.clients.iter().map(|c| c.machine_id.clone()).collect()
+    }
+}
+
+impl Deref for RunningClients {
+    type Target = TestContext;
+
+    fn deref(&self) -> &TestContext {
+        &self.ctx
+    }
+}
+
+/// Use `#[with(n)]` on the argument for other than two clients.  The clients are started
+/// [`DEFAULT_PARALLELISM`] at a time; if any of them fails, the panic lists every one that did.
+#[fixture]
+pub async fn running_clients(
+    #[default(2)] num_clients: usize,
+    #[future] namespaced: Namespaced,
+) -> RunningClients {
+    let Namespaced { ctx, namespace } = namespaced.await;
+    let names = numbered_client_names(&random_container_name(), num_clients, 0);
+    let (ctx_ref, namespace_ref) = (&ctx, namespace.as_str());
+    let machine_ids = launch_clients(&names, DEFAULT_PARALLELISM, |index, name| async move {
+        let machine_id = ctx_ref
+            .create_and_register_client(&name, namespace_ref, user_for_client(index))
+            .await?;
+        wait_for_state_change(&ctx_ref.docker, &name, BackendState::Running).await?;
+        Ok(machine_id)
+    })
+    .await
+    .into_result()
+    .unwrap_or_else(|e| panic!("Unable to start the clients: {e}"));
+    let clients = names
+        .into_iter()
+        .zip(machine_ids)
+        .map(|(container_name, machine_id)| Client {
+            container_name,
+            machine_id,
+        })
+        .collect();
+    RunningClients {
+        ctx,
+        namespace,
+        clients,
+    }
+}
+
+/// Two running clients where `source` may reach `destination` on TCP port 80, but not the other
+/// way around.
+pub struct PolicyPair {
+    pub ctx: TestContext,
+    pub namespace: String,
+    pub source: Client,
+    pub destination: Client,
+    pub policy_id: String,
+}
+
+impl Deref for PolicyPair {
+    type Target = TestContext;
+
+    fn deref(&self) -> &TestContext {
+        &self.ctx
+    }
+}
+
+#[fixture]
+pub async fn policy_pair(
+    #[future]
+    #[with(2)]
+    running_clients: RunningClients,
+) -> PolicyPair {
+    let RunningClients {
+        ctx,
+        namespace,
+        mut clients,
+    } = running_clients.await;
+    let destination = clients.pop().expect("two clients");
+    let source = clients.pop().expect("two clients");
+    let policy_id = ctx
+        .grant_one_directional_policy(&source.machine_id, &destination.machine_id, 80)
+        .await
+        .expect("Unable to create policy");
+    PolicyPair {
+        ctx,
+        namespace,
+        source,
+        destination,
+        policy_id,
+    }
+}
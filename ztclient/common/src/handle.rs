@@ -0,0 +1,569 @@
+//! A client container and the `ztclient` subcommands it can run, with their flags as option
+//! structs and their output parsed.
+//!
+//! ```no_run
+//! # async fn example(docker: &bollard::Docker) -> ztclient_common::Result<()> {
+//! use ztclient_common::handle::{ConfigureOptions, PingOptions, ZtClientHandle};
+//!
+//! let client = ZtClientHandle::new(docker, "ztclienthost001");
+//! client
+//!     .configure(&ConfigureOptions {
+//!         hostname: Some("renamed".to_string()),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! client.reconnect().await?;
+//! let addresses = client.show_ip(&Default::default()).await?;
+//! let ping = client.zt_ping("ztclienthost002", &PingOptions::once()).await?;
+//! println!("{addresses:?} {:?}", ping.latencies());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::IpAddr, time::Duration};
+
+use bollard::Docker;
+
+use crate::{
+    exec::{exec_in_container, ExecOutput},
+    ipn::{watch_ipn, IpnWatch, WatchOptions},
+    models::{
+        status::{BackendState, StatusResult},
+        ztcon::ConResult,
+        ztn::NetMap,
+    },
+    netmap::wait_for_netmap,
+    ztclient::{
+        connect_for_correlation_id, get_connect_actions, wait_for_peer, wait_for_state,
+        ztclient_netmap, ztclient_status_json, EXEC_TIMEOUT, NGINX_NP_URL, REGISTRATION_TIMEOUT,
+    },
+    Error, Result,
+};
+
+/// Flags of `ztclient connect`.  Unset options are left out, so the client uses its defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Base URL of Ninja Panda; [`NGINX_NP_URL`] if not given.
+    pub url: Option<String>,
+    pub auth_token: Option<String>,
+    pub hostname: Option<String>,
+    pub auto_connect: Option<bool>,
+    pub client_only: Option<bool>,
+    pub force_reauth: bool,
+    pub internet_gateway: Option<String>,
+    pub internet_gateway_allow_local_lan: Option<bool>,
+    /// One of `on`, `nodivert` or `off`.
+    pub netfilter_mode: Option<String>,
+    pub reset: bool,
+    pub snat_subnet_routes: Option<bool>,
+    pub use_dns: Option<bool>,
+    pub use_gateway: Option<bool>,
+    pub user: Option<String>,
+}
+
+/// Flags of `ztclient configure`.  Only the options that are set are changed.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigureOptions {
+    pub auto_connect: Option<bool>,
+    pub client_only: Option<bool>,
+    pub hostname: Option<String>,
+    pub use_dns: Option<bool>,
+    pub use_gateway: Option<bool>,
+}
+
+/// Flags of `ztclient login`.
+#[derive(Debug, Clone, Default)]
+pub struct LoginOptions {
+    pub auth_token: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShowNetFormat {
+    Json,
+    JsonLine,
+}
+
+/// Flags of `ztclient show-net`.  `--every` is left out since it never returns.
+#[derive(Debug, Clone, Default)]
+pub struct ShowNetOptions {
+    /// Human readable if not given.
+    pub format: Option<ShowNetFormat>,
+    pub verbose: bool,
+}
+
+/// Flags of `ztclient show-ip`.
+#[derive(Debug, Clone, Default)]
+pub struct ShowIpOptions {
+    pub only_one: bool,
+    pub only_v4: bool,
+    pub only_v6: bool,
+    /// The addresses of this peer instead of the client's own.
+    pub peer: Option<String>,
+}
+
+/// Flags of `ztclient zt-ping`.
+#[derive(Debug, Clone, Default)]
+pub struct PingOptions {
+    /// Stops after this many pongs; the client sends 10 if not given.
+    pub count: Option<u32>,
+    pub icmp: bool,
+    /// How long to wait for each pong; 5 seconds if not given.
+    pub timeout: Option<Duration>,
+}
+
+impl PingOptions {
+    pub fn once() -> PingOptions {
+        PingOptions {
+            count: Some(1),
+            ..Default::default()
+        }
+    }
+}
+
+/// One `pong from <name> (<address>) via <path> in <latency>` line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pong {
+    pub peer: String,
+    pub address: Option<IpAddr>,
+    pub via: Option<String>,
+    pub latency: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PingResult {
+    pub pongs: Vec<Pong>,
+    /// Lines other than pongs, such as timeouts.
+    pub other: Vec<String>,
+    pub success: bool,
+}
+
+impl PingResult {
+    pub fn parse(output: &ExecOutput) -> PingResult {
+        let mut result = PingResult {
+            success: output.success(),
+            ..Default::default()
+        };
+        for line in output.combined().lines().map(str::trim) {
+            match parse_pong(line) {
+                Some(pong) => result.pongs.push(pong),
+                None if line.is_empty() => {}
+                None => result.other.push(line.to_string()),
+            }
+        }
+        result
+    }
+
+    pub fn latencies(&self) -> Vec<Duration> {
+        self.pongs.iter().map(|p| p.latency).collect()
+    }
+}
+
+fn parse_pong(line: &str) -> Option<Pong> {
+    let rest = line.strip_prefix("pong from ")?;
+    let (rest, latency) = rest.rsplit_once(" in ")?;
+    let (rest, via) = match rest.split_once(" via ") {
+        Some((rest, via)) => (rest, Some(via.to_string())),
+        None => (rest, None),
+    };
+    let (peer, address) = match rest.split_once(" (") {
+        Some((peer, address)) => (peer, address.trim_end_matches(')').parse().ok()),
+        None => (rest, None),
+    };
+    Some(Pong {
+        peer: peer.to_string(),
+        address,
+        via,
+        latency: parse_latency(latency.trim())?,
+    })
+}
+
+/// Go's duration format, as far as pings go: `850µs`, `12ms`, `1.5s`.
+fn parse_latency(latency: &str) -> Option<Duration> {
+    for (unit, seconds) in [("µs", 1e-6), ("us", 1e-6), ("ms", 1e-3), ("s", 1.0)] {
+        if let Some(n) = latency.strip_suffix(unit) {
+            return n
+                .parse::<f64>()
+                .ok()
+                .map(|n| Duration::from_secs_f64(n * seconds));
+        }
+    }
+    None
+}
+
+/// What `ztclient show-ver` prints: the version on the first line, then `key: value` details such
+/// as commits.  With `--daemon`, the versions come as `Client:` and `Daemon:` lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Version {
+    pub version: String,
+    pub daemon: Option<String>,
+    pub details: Vec<(String, String)>,
+}
+
+impl Version {
+    pub fn parse(output: &str) -> Version {
+        let mut version = Version::default();
+        for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line.split_once(':') {
+                Some((key, value)) if key.eq_ignore_ascii_case("client") => {
+                    version.version = value.trim().to_string()
+                }
+                Some((key, value)) if key.eq_ignore_ascii_case("daemon") => {
+                    version.daemon = Some(value.trim().to_string())
+                }
+                _ if version.version.is_empty() => version.version = line.to_string(),
+                Some((key, value)) => version
+                    .details
+                    .push((key.trim().to_string(), value.trim().to_string())),
+                None => {}
+            }
+        }
+        version
+    }
+}
+
+/// The container of one client.  Commands that the client rejects are an [`Error::Exec`] with what
+/// it printed.
+#[derive(Debug, Clone)]
+pub struct ZtClientHandle {
+    docker: Docker,
+    container_name: String,
+}
+
+impl ZtClientHandle {
+    pub fn new(docker: &Docker, container_name: &str) -> ZtClientHandle {
+        ZtClientHandle {
+            docker: docker.clone(),
+            container_name: container_name.to_string(),
+        }
+    }
+
+    pub fn container_name(&self) -> &str {
+        &self.container_name
+    }
+
+    /// Runs `ztclient <args>` and returns what it printed, whatever the exit code.
+    pub async fn run(&self, args: &[&str], timeout: Duration) -> Result<ExecOutput> {
+        let mut cmd = vec!["ztclient"];
+        cmd.extend_from_slice(args);
+        exec_in_container(&self.docker, &self.container_name, &cmd, Some(timeout)).await
+    }
+
+    async fn run_ok(&self, args: &[&str], timeout: Duration) -> Result<ExecOutput> {
+        let output = self.run(args, timeout).await?;
+        if !output.success() {
+            return Err(Error::Exec {
+                container: self.container_name.clone(),
+                command: format!("ztclient {}", args.join(" ")),
+                message: format!("exit {:?}: {}", output.exit_code, output.combined().trim()),
+            });
+        }
+        Ok(output)
+    }
+
+    /// `ztclient connect` for clients that don't need to log in interactively, such as with an
+    /// auth token.
+    pub async fn connect(&self, options: &ConnectOptions) -> Result<ExecOutput> {
+        let args = connect_args(options);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_ok(&args, REGISTRATION_TIMEOUT).await
+    }
+
+    /// `ztclient connect` for an interactive login: returns the correlation ID of the login URL
+    /// once it is printed, or an empty string if the client was already registered.
+    pub async fn connect_for_login(&self, options: &ConnectOptions) -> Result<String> {
+        let mut args = vec!["ztclient".to_string()];
+        args.extend(connect_args(options));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        connect_for_correlation_id(&self.docker, &self.container_name, &args).await
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.run_ok(&["disconnect"], EXEC_TIMEOUT).await?;
+        Ok(())
+    }
+
+    /// `disconnect` followed by a plain `connect`, to pick up new settings.
+    pub async fn reconnect(&self) -> Result<()> {
+        self.disconnect().await?;
+        self.run_ok(&["connect"], EXEC_TIMEOUT).await?;
+        Ok(())
+    }
+
+    pub async fn configure(&self, options: &ConfigureOptions) -> Result<()> {
+        let mut args = vec!["configure".to_string()];
+        push_bool(&mut args, "auto-connect", options.auto_connect);
+        push_bool(&mut args, "client-only", options.client_only);
+        push_value(&mut args, "hostname", &options.hostname);
+        push_bool(&mut args, "use-dns", options.use_dns);
+        push_bool(&mut args, "use-gateway", options.use_gateway);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_ok(&args, EXEC_TIMEOUT).await?;
+        Ok(())
+    }
+
+    /// Configures a new hostname and reconnects so that Ninja Panda hears about it.
+    pub async fn rename(&self, hostname: &str) -> Result<()> {
+        self.configure(&ConfigureOptions {
+            hostname: Some(hostname.to_string()),
+            ..Default::default()
+        })
+        .await?;
+        self.reconnect().await
+    }
+
+    pub async fn login(&self, options: &LoginOptions) -> Result<ExecOutput> {
+        let mut args = vec!["login".to_string()];
+        push_value(&mut args, "auth-token", &options.auth_token);
+        push_value(&mut args, "url", &options.url);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_ok(&args, REGISTRATION_TIMEOUT).await
+    }
+
+    pub async fn logout(&self) -> Result<()> {
+        self.run_ok(&["logout"], EXEC_TIMEOUT).await?;
+        Ok(())
+    }
+
+    pub async fn show_net(&self, options: &ShowNetOptions) -> Result<String> {
+        let mut args = vec!["show-net".to_string()];
+        let format = options.format.map(|format| match format {
+            ShowNetFormat::Json => "json".to_string(),
+            ShowNetFormat::JsonLine => "json-line".to_string(),
+        });
+        push_value(&mut args, "format", &format);
+        if options.verbose {
+            args.push("--verbose".to_string());
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(self.run_ok(&args, EXEC_TIMEOUT).await?.stdout)
+    }
+
+    pub async fn show_ip(&self, options: &ShowIpOptions) -> Result<Vec<IpAddr>> {
+        let mut args = vec!["show-ip"];
+        for (set, flag) in [
+            (options.only_one, "--1"),
+            (options.only_v4, "--4"),
+            (options.only_v6, "--6"),
+        ] {
+            if set {
+                args.push(flag);
+            }
+        }
+        if let Some(peer) = &options.peer {
+            args.push(peer);
+        }
+        let output = self.run_ok(&args, EXEC_TIMEOUT).await?;
+        parse_addresses(&output.stdout).map_err(|line| Error::Exec {
+            container: self.container_name.clone(),
+            command: format!("ztclient {}", args.join(" ")),
+            message: format!("{line:?} is not an IP address"),
+        })
+    }
+
+    pub async fn status(&self) -> Result<StatusResult> {
+        ztclient_status_json(&self.docker, &self.container_name).await
+    }
+
+    /// `ztclient status` as printed for people.
+    pub async fn status_text(&self) -> Result<String> {
+        Ok(self.run(&["status"], EXEC_TIMEOUT).await?.combined())
+    }
+
+    /// Never an error for lost pings; see [`PingResult::success`].
+    pub async fn zt_ping(&self, peer: &str, options: &PingOptions) -> Result<PingResult> {
+        let mut args = vec!["zt-ping".to_string()];
+        let count = options.count.map(|c| c.to_string());
+        push_value(&mut args, "c", &count);
+        if options.icmp {
+            args.push("--icmp".to_string());
+        }
+        let timeout = options.timeout.map(|t| format!("{}ms", t.as_millis()));
+        push_value(&mut args, "timeout", &timeout);
+        args.push(peer.to_string());
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        let per_ping = options.timeout.unwrap_or(Duration::from_secs(5));
+        let limit = EXEC_TIMEOUT + per_ping * options.count.unwrap_or(10);
+        let output = self.run(&args, limit).await?;
+        Ok(PingResult::parse(&output))
+    }
+
+    /// What `ztclient zt-con` printed; a peer that lets the connection through answers with
+    /// [`crate::ztclient::ZT_CON_GREETING`].
+    pub async fn zt_con(&self, peer: &str, port: u16, udp: bool) -> Result<String> {
+        let port = port.to_string();
+        let args = match udp {
+            true => vec!["zt-con", "--u", peer, &port],
+            false => vec!["zt-con", peer, &port],
+        };
+        Ok(self.run(&args, EXEC_TIMEOUT).await?.combined())
+    }
+
+    pub async fn show_ver(&self, daemon: bool) -> Result<Version> {
+        let args = match daemon {
+            true => vec!["show-ver", "--daemon"],
+            false => vec!["show-ver"],
+        };
+        let output = self.run_ok(&args, EXEC_TIMEOUT).await?;
+        Ok(Version::parse(&output.stdout))
+    }
+
+    pub async fn legal(&self) -> Result<String> {
+        Ok(self.run_ok(&["legal"], EXEC_TIMEOUT).await?.combined())
+    }
+
+    pub async fn netmap(&self) -> Result<NetMap> {
+        ztclient_netmap(&self.docker, &self.container_name).await
+    }
+
+    pub async fn wait_for_state(
+        &self,
+        state: BackendState,
+        timeout: Duration,
+    ) -> Result<StatusResult> {
+        wait_for_state(&self.docker, &self.container_name, state, timeout).await
+    }
+
+    /// See [`crate::netmap::wait_for_netmap`].
+    pub async fn wait_for_netmap<F>(&self, predicate: F, timeout: Duration) -> Result<NetMap>
+    where
+        F: Fn(&NetMap) -> bool,
+    {
+        wait_for_netmap(&self.docker, &self.container_name, predicate, timeout).await
+    }
+
+    pub async fn wait_for_peer(&self, peer: &str) -> Result<ConResult> {
+        wait_for_peer(&self.docker, &self.container_name, peer).await
+    }
+
+    /// See [`crate::ipn::watch_ipn`].
+    pub async fn watch_ipn(&self, options: &WatchOptions) -> Result<IpnWatch> {
+        watch_ipn(&self.docker, &self.container_name, options).await
+    }
+}
+
+/// The arguments after `ztclient`, starting with the connect subcommand of the client version in
+/// use.
+fn connect_args(options: &ConnectOptions) -> Vec<String> {
+    let (url_arg_name, connect_arg_name) = get_connect_actions();
+    let url = options.url.as_deref().unwrap_or(NGINX_NP_URL);
+    let mut args = vec![
+        connect_arg_name.to_string(),
+        format!("--{url_arg_name}={url}"),
+    ];
+    push_value(&mut args, "auth-token", &options.auth_token);
+    push_value(&mut args, "hostname", &options.hostname);
+    push_bool(&mut args, "auto-connect", options.auto_connect);
+    push_bool(&mut args, "client-only", options.client_only);
+    if options.force_reauth {
+        args.push("--force-reauth".to_string());
+    }
+    push_value(&mut args, "internet-gateway", &options.internet_gateway);
+    push_bool(
+        &mut args,
+        "internet-gateway-allow-local-lan",
+        options.internet_gateway_allow_local_lan,
+    );
+    push_value(&mut args, "netfilter-mode", &options.netfilter_mode);
+    if options.reset {
+        args.push("--reset".to_string());
+    }
+    push_bool(&mut args, "snat-subnet-routes", options.snat_subnet_routes);
+    push_bool(&mut args, "use-dns", options.use_dns);
+    push_bool(&mut args, "use-gateway", options.use_gateway);
+    push_value(&mut args, "user", &options.user);
+    args
+}
+
+fn push_value(args: &mut Vec<String>, flag: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        args.push(format!("--{flag}={value}"));
+    }
+}
+
+fn push_bool(args: &mut Vec<String>, flag: &str, value: Option<bool>) {
+    if let Some(value) = value {
+        args.push(format!("--{flag}={value}"));
+    }
+}
+
+/// One address per line; the offending line if there is something else.
+fn parse_addresses(output: &str) -> std::result::Result<Vec<IpAddr>, String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| l.parse().map_err(|_| l.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pongs_are_parsed() {
+        let output = ExecOutput {
+            stdout: "pong from b (100.64.0.2) via 172.18.0.5:41641 in 12ms\n\
+                     pong from b (100.64.0.2) via RELAY(nyc) in 850µs\n\
+                     timeout waiting for ping reply\n"
+                .to_string(),
+            exit_code: Some(1),
+            ..Default::default()
+        };
+        let result = PingResult::parse(&output);
+        assert!(!result.success);
+        assert_eq!(
+            result.latencies(),
+            vec![Duration::from_millis(12), Duration::from_micros(850)]
+        );
+        assert_eq!(result.pongs[0].peer, "b");
+        assert_eq!(result.pongs[0].address, "100.64.0.2".parse().ok());
+        assert_eq!(result.pongs[1].via.as_deref(), Some("RELAY(nyc)"));
+        assert_eq!(result.other, vec!["timeout waiting for ping reply"]);
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        let version = Version::parse("0.5.0\n  ztclient commit: abc123\n  go version: go1.21\n");
+        assert_eq!(version.version, "0.5.0");
+        assert_eq!(version.daemon, None);
+        assert_eq!(
+            version.details,
+            vec![
+                ("ztclient commit".to_string(), "abc123".to_string()),
+                ("go version".to_string(), "go1.21".to_string())
+            ]
+        );
+        let version = Version::parse("Client: 0.5.0\nDaemon: 0.4.9\n");
+        assert_eq!(version.version, "0.5.0");
+        assert_eq!(version.daemon.as_deref(), Some("0.4.9"));
+    }
+
+    #[test]
+    fn addresses_and_flags() {
+        assert_eq!(
+            parse_addresses("100.64.0.1\nfd7a:115c:a1e0::1\n").unwrap(),
+            vec![
+                "100.64.0.1".parse::<IpAddr>().unwrap(),
+                "fd7a:115c:a1e0::1".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            parse_addresses("no addresses\n").unwrap_err(),
+            "no addresses"
+        );
+
+        let args = connect_args(&ConnectOptions {
+            hostname: Some("zt001".to_string()),
+            use_dns: Some(false),
+            force_reauth: true,
+            ..Default::default()
+        });
+        assert!(args.contains(&"--hostname=zt001".to_string()));
+        assert!(args.contains(&"--use-dns=false".to_string()));
+        assert!(args.contains(&"--force-reauth".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--client-only")));
+    }
+}
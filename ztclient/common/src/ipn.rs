@@ -0,0 +1,299 @@
+//! The notifications a client's backend pushes on its IPN bus, read from a long-running
+//! `ztclient examine watch-ipn`.  Waiting on these reacts to what the client is told as soon as
+//! it is told, where [`crate::ztclient::wait_for_status`] only sees it on the next poll.
+//!
+//! ```no_run
+//! # async fn example(docker: &bollard::Docker) -> ztclient_common::Result<()> {
+//! use std::time::Duration;
+//!
+//! use ztclient_common::ipn::{watch_ipn, WatchOptions};
+//!
+//! let mut watch = watch_ipn(docker, "ztclienthost001", &WatchOptions::default()).await?;
+//! let netmap = watch
+//!     .wait_for_peer_count(|peers| peers >= 2, Duration::from_secs(60))
+//!     .await?;
+//! println!("{}", netmap.self_node.name);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bollard::{
+    container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
+    Docker,
+};
+use futures::{stream::BoxStream, Stream, StreamExt};
+use serde_json::Deserializer;
+use tokio::time::Instant;
+
+use crate::{
+    models::{
+        status::BackendState,
+        ztn::{NetMap, ZtnMessage},
+    },
+    Error, Result,
+};
+
+/// Flags of `ztclient examine watch-ipn`.
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Starts with a notification holding the current state, prefs and netmap.
+    pub initial: bool,
+    /// Includes the netmap in notifications.
+    pub netmap: bool,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            initial: true,
+            netmap: true,
+        }
+    }
+}
+
+/// Splits the output of `watch-ipn` into notifications.  The client prints each one as indented
+/// JSON, and a frame from Docker can end anywhere in one.
+#[derive(Debug, Default)]
+struct MessageDecoder {
+    buffer: Vec<u8>,
+}
+
+impl MessageDecoder {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete notification, or `None` until more output arrives.
+    fn next_message(&mut self, container_name: &str) -> Option<Result<ZtnMessage>> {
+        let mut messages = Deserializer::from_slice(&self.buffer).into_iter::<ZtnMessage>();
+        let result = match messages.next() {
+            None => {
+                self.buffer.clear();
+                return None;
+            }
+            Some(Err(e)) if e.is_eof() => return None,
+            Some(Err(source)) => Err(Error::Decode {
+                what: format!("IPN notification from {container_name}"),
+                payload: String::from_utf8_lossy(&self.buffer).into_owned(),
+                source,
+            }),
+            Some(Ok(message)) => Ok(message),
+        };
+        let consumed = match &result {
+            Ok(_) => messages.byte_offset(),
+            Err(_) => self.buffer.len(),
+        };
+        self.buffer.drain(..consumed);
+        Some(result)
+    }
+}
+
+/// The notifications of one client, in the order it got them.  The stream ends when
+/// `watch-ipn` exits; a notification that can't be decoded is yielded as an error.
+pub struct IpnWatch {
+    container_name: String,
+    messages: BoxStream<'static, Result<ZtnMessage>>,
+}
+
+/// Starts `ztclient examine watch-ipn` in `container_name`.  Dropping the watch closes the
+/// command's output, which ends it the next time it prints.
+pub async fn watch_ipn(
+    docker: &Docker,
+    container_name: &str,
+    options: &WatchOptions,
+) -> Result<IpnWatch> {
+    let initial = format!("--initial={}", options.initial);
+    let netmap = format!("--netmap={}", options.netmap);
+    let cmd = vec![
+        "ztclient",
+        "examine",
+        "watch-ipn",
+        initial.as_str(),
+        netmap.as_str(),
+    ];
+    let exec = docker
+        .create_exec(
+            container_name,
+            CreateExecOptions {
+                cmd: Some(cmd.clone()),
+                attach_stderr: Some(true),
+                attach_stdout: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let StartExecResults::Attached { output, .. } = docker.start_exec(&exec.id, None).await? else {
+        return Err(Error::Exec {
+            container: container_name.to_string(),
+            command: cmd.join(" "),
+            message: "started detached".to_string(),
+        });
+    };
+    log::debug!("watching the IPN bus of {container_name}");
+
+    let name = container_name.to_string();
+    let messages = futures::stream::unfold(
+        (output, MessageDecoder::default(), false),
+        move |(mut output, mut decoder, mut failed)| {
+            let name = name.clone();
+            async move {
+                loop {
+                    if failed {
+                        return None;
+                    }
+                    if let Some(message) = decoder.next_message(&name) {
+                        return Some((message, (output, decoder, failed)));
+                    }
+                    match output.next().await? {
+                        Ok(LogOutput::StdOut { message }) | Ok(LogOutput::Console { message }) => {
+                            decoder.push(&message)
+                        }
+                        Ok(LogOutput::StdErr { message }) => {
+                            log::debug!(
+                                "watch-ipn in {name}: {}",
+                                String::from_utf8_lossy(&message)
+                            )
+                        }
+                        Ok(LogOutput::StdIn { .. }) => {}
+                        Err(e) => {
+                            failed = true;
+                            return Some((Err(e.into()), (output, decoder, failed)));
+                        }
+                    }
+                }
+            }
+        },
+    );
+    Ok(IpnWatch {
+        container_name: container_name.to_string(),
+        messages: messages.boxed(),
+    })
+}
+
+impl Stream for IpnWatch {
+    type Item = Result<ZtnMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_next_unpin(cx)
+    }
+}
+
+impl IpnWatch {
+    pub fn container_name(&self) -> &str {
+        &self.container_name
+    }
+
+    /// Reads notifications until `select` picks something out of one, for up to `timeout`.  `what`
+    /// describes what is waited for, for the error.
+    pub async fn next_matching<T, F>(
+        &mut self,
+        what: &str,
+        timeout: Duration,
+        mut select: F,
+    ) -> Result<T>
+    where
+        F: FnMut(&ZtnMessage) -> Option<T>,
+    {
+        let start = Instant::now();
+        let wait = async {
+            while let Some(message) = self.messages.next().await {
+                if let Some(found) = select(&message?) {
+                    return Ok(found);
+                }
+            }
+            Err(Error::Verification(format!(
+                "watch-ipn in {} ended before {what}",
+                self.container_name
+            )))
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| Error::Timeout {
+                what: format!("{what} on the IPN bus of {}", self.container_name),
+                elapsed: start.elapsed(),
+            })?
+    }
+
+    /// Waits for a netmap whose number of peers satisfies `count`.
+    pub async fn wait_for_peer_count<F>(&mut self, count: F, timeout: Duration) -> Result<NetMap>
+    where
+        F: Fn(usize) -> bool,
+    {
+        self.next_matching("a netmap with the wanted peers", timeout, |message| {
+            message
+                .net_map
+                .as_ref()
+                .filter(|netmap| count(netmap.peers.as_ref().map_or(0, Vec::len)))
+                .cloned()
+        })
+        .await
+    }
+
+    /// Waits for the backend to announce `state`.
+    pub async fn wait_for_state(&mut self, state: BackendState, timeout: Duration) -> Result<()> {
+        self.next_matching(&format!("state {state}"), timeout, |message| {
+            (message.state == Some(state)).then_some(())
+        })
+        .await
+    }
+
+    /// Waits for the URL the client wants the user to open to log in.
+    pub async fn wait_for_browse_to_url(&mut self, timeout: Duration) -> Result<String> {
+        self.next_matching("BrowseToURL", timeout, |message| {
+            message
+                .browse_to_url
+                .as_str()
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDENTED: &str = "{\n\t\"Version\": \"1.0\",\n\t\"State\": 2\n}\n";
+
+    #[test]
+    fn notifications_split_across_frames() {
+        let mut decoder = MessageDecoder::default();
+        let (first, rest) = INDENTED.split_at(12);
+        decoder.push(first.as_bytes());
+        assert!(decoder.next_message("c").is_none());
+        decoder.push(rest.as_bytes());
+        decoder.push(b"{\"BrowseToURL\": \"http://login\"}\n{\"State\"");
+
+        let message = decoder.next_message("c").unwrap().unwrap();
+        assert_eq!(message.version, "1.0");
+        assert_eq!(message.state, Some(BackendState::NeedsLogin));
+        let message = decoder.next_message("c").unwrap().unwrap();
+        assert_eq!(message.browse_to_url, "http://login");
+        assert!(decoder.next_message("c").is_none());
+
+        decoder.push(b": 6}\n\n");
+        let message = decoder.next_message("c").unwrap().unwrap();
+        assert_eq!(message.state, Some(BackendState::Running));
+        assert!(decoder.next_message("c").is_none());
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let mut decoder = MessageDecoder::default();
+        decoder.push(b"panic: no backend\n");
+        assert!(matches!(
+            decoder.next_message("c"),
+            Some(Err(Error::Decode { .. }))
+        ));
+        assert!(decoder.next_message("c").is_none());
+    }
+}
@@ -0,0 +1,401 @@
+//! Reading the events Ninja Panda publishes to Kafka, by running the console consumer in the
+//! Kafka container: either a one-off read of a topic with [`kafka_consumer`], or a
+//! [`KafkaSubscription`] that follows topics while a test waits for a specific event.
+
+use std::time::Duration;
+
+use bollard::{
+    container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
+    Docker,
+};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+
+use crate::{context::block_on_detached, exec::exec_in_container, Error, Result};
+
+const CONSOLE_CONSUMER: &str = "/opt/bitnami/kafka/bin/kafka-console-consumer.sh";
+const GET_OFFSETS: &str = "/opt/bitnami/kafka/bin/kafka-get-offsets.sh";
+/// How long the Kafka command line tools may take to do one thing.
+const KAFKA_TOOL_TIMEOUT: Duration = Duration::from_secs(60);
+/// A subscription's consumers exit on their own after this long without a message, in case the
+/// tester dies before it can stop them.
+const CONSUMER_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// Listener the broker advertises inside the compose network.
+const BOOTSTRAP_SERVER: &str = "kafka:9094";
+
+pub mod topics {
+    pub const MACHINE_REGISTER: &str = "machine.register";
+    pub const MACHINE_UPDATE: &str = "machine.update";
+}
+
+/// A message read from `topic`, decoded into `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaEvent<T> {
+    pub topic: String,
+    pub message: T,
+}
+
+/// Reads up to `max_messages` from the start of `topic` and returns the raw payloads. Gives up
+/// after ten idle seconds, so an empty topic returns an empty list rather than hanging.
+pub async fn kafka_consumer(
+    docker: &Docker,
+    kafka_container_name: &str,
+    topic: &str,
+    max_messages: usize,
+) -> Result<Vec<String>> {
+    let max_messages = max_messages.to_string();
+    let output = exec_in_container(
+        docker,
+        kafka_container_name,
+        &[
+            CONSOLE_CONSUMER,
+            "--bootstrap-server",
+            BOOTSTRAP_SERVER,
+            "--topic",
+            topic,
+            "--from-beginning",
+            "--max-messages",
+            max_messages.as_str(),
+            "--timeout-ms",
+            "10000",
+        ],
+        Some(KAFKA_TOOL_TIMEOUT),
+    )
+    .await?;
+    Ok(output
+        .stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Splits the console consumer's output into messages, one per line.  A frame from Docker can end
+/// anywhere in a line, even inside a UTF-8 character.
+#[derive(Debug, Default)]
+struct LineSplitter {
+    buffer: Vec<u8>,
+}
+
+impl LineSplitter {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete line that isn't blank, trimmed, or `None` until more output arrives.
+    fn next_line(&mut self) -> Option<String> {
+        loop {
+            let end = self.buffer.iter().position(|b| *b == b'\n')?;
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if !line.is_empty() {
+                return Some(line.to_string());
+            }
+        }
+    }
+}
+
+/// Follows one or more topics in the Kafka container, with one console consumer per partition.
+///
+/// Consumers start at the end each partition had when subscribing, so [`expect_event`] only
+/// sees events published after [`subscribe`] returned, not those of earlier tests or runs.  A
+/// topic that doesn't exist yet has no history and is read from its beginning once it appears.
+/// Dropping the subscription stops the consumers.
+///
+/// [`expect_event`]: KafkaSubscription::expect_event
+/// [`subscribe`]: KafkaSubscription::subscribe
+pub struct KafkaSubscription {
+    docker: Docker,
+    kafka_container_name: String,
+    receiver: mpsc::UnboundedReceiver<(String, String)>,
+    consumers: Vec<Consumer>,
+}
+
+/// A console consumer running in the Kafka container and the task forwarding what it prints.
+struct Consumer {
+    pid: String,
+    task: JoinHandle<()>,
+}
+
+/// Where a consumer starts: a partition and offset, or the beginning of the whole topic.
+type StartAt = Option<(u32, u64)>;
+
+/// The end offset of each partition, from `kafka-get-offsets.sh` lines such as
+/// `machine.register:0:42`.
+fn parse_end_offsets(topic: &str, output: &str) -> Vec<(u32, u64)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim().strip_prefix(topic)?.strip_prefix(':')?;
+            let (partition, offset) = rest.split_once(':')?;
+            Some((partition.parse().ok()?, offset.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Where to start reading `topic` so that only new messages are seen.
+async fn start_positions(
+    docker: &Docker,
+    kafka_container_name: &str,
+    topic: &str,
+) -> Result<Vec<StartAt>> {
+    let output = exec_in_container(
+        docker,
+        kafka_container_name,
+        &[
+            GET_OFFSETS,
+            "--bootstrap-server",
+            BOOTSTRAP_SERVER,
+            "--topic",
+            topic,
+            "--time",
+            "-1",
+        ],
+        Some(KAFKA_TOOL_TIMEOUT),
+    )
+    .await?;
+    let offsets = parse_end_offsets(topic, &output.stdout);
+    if !output.success() || offsets.is_empty() {
+        log::debug!(
+            "{topic} has no partitions yet: {}",
+            output.combined().trim()
+        );
+        return Ok(vec![None]);
+    }
+    Ok(offsets.into_iter().map(Some).collect())
+}
+
+impl KafkaSubscription {
+    pub async fn subscribe(
+        docker: &Docker,
+        kafka_container_name: &str,
+        topics: &[&str],
+    ) -> Result<KafkaSubscription> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // Dropping the subscription on an error stops the consumers started so far.
+        let mut subscription = KafkaSubscription {
+            docker: docker.clone(),
+            kafka_container_name: kafka_container_name.to_string(),
+            receiver,
+            consumers: Vec::new(),
+        };
+        for topic in topics {
+            for start_at in start_positions(docker, kafka_container_name, topic).await? {
+                let consumer = start_consumer(
+                    docker,
+                    kafka_container_name,
+                    topic,
+                    start_at,
+                    sender.clone(),
+                )
+                .await?;
+                subscription.consumers.push(consumer);
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Waits for the next message that decodes into `T` and satisfies `predicate`. Messages that
+    /// don't decode, or don't match, are skipped.  Fails with [`Error::Verification`] if every
+    /// consumer has exited, since then no more messages can arrive.
+    pub async fn expect_event<T, F>(
+        &mut self,
+        mut predicate: F,
+        timeout: Duration,
+    ) -> Result<KafkaEvent<T>>
+    where
+        T: DeserializeOwned,
+        F: FnMut(&KafkaEvent<T>) -> bool,
+    {
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let what = || format!("a matching {} event", std::any::type_name::<T>());
+        loop {
+            let (topic, payload) =
+                match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                    Ok(Some(received)) => received,
+                    Ok(None) => {
+                        return Err(Error::Verification(format!(
+                            "every Kafka consumer exited before {}",
+                            what()
+                        )))
+                    }
+                    Err(_) => {
+                        return Err(Error::Timeout {
+                            what: what(),
+                            elapsed: start.elapsed(),
+                        })
+                    }
+                };
+            match serde_json::from_str(&payload) {
+                Ok(message) => {
+                    let event = KafkaEvent { topic, message };
+                    if predicate(&event) {
+                        return Ok(event);
+                    }
+                }
+                Err(e) => log::debug!("skipping {topic} message that doesn't decode: {e}"),
+            }
+        }
+    }
+}
+
+/// Starts a console consumer for `topic` that sends each message it prints to `sender`.  The
+/// consumer is run through a shell that prints its PID first, so that it can be killed.
+async fn start_consumer(
+    docker: &Docker,
+    kafka_container_name: &str,
+    topic: &str,
+    start_at: StartAt,
+    sender: mpsc::UnboundedSender<(String, String)>,
+) -> Result<Consumer> {
+    let idle_ms = CONSUMER_IDLE_TIMEOUT.as_millis().to_string();
+    let mut cmd = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        "echo $$; exec \"$0\" \"$@\"".to_string(),
+        CONSOLE_CONSUMER.to_string(),
+        "--bootstrap-server".to_string(),
+        BOOTSTRAP_SERVER.to_string(),
+        "--topic".to_string(),
+        topic.to_string(),
+        "--timeout-ms".to_string(),
+        idle_ms,
+    ];
+    match start_at {
+        Some((partition, offset)) => cmd.extend([
+            "--partition".to_string(),
+            partition.to_string(),
+            "--offset".to_string(),
+            offset.to_string(),
+        ]),
+        None => cmd.push("--from-beginning".to_string()),
+    }
+    let exec_failed = |message: &str| Error::Exec {
+        container: kafka_container_name.to_string(),
+        command: cmd.join(" "),
+        message: message.to_string(),
+    };
+
+    let exec = docker
+        .create_exec(
+            kafka_container_name,
+            CreateExecOptions {
+                cmd: Some(cmd.clone()),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let StartExecResults::Attached { mut output, .. } = docker.start_exec(&exec.id, None).await?
+    else {
+        return Err(exec_failed("started detached"));
+    };
+
+    let mut lines = LineSplitter::default();
+    let read_pid = async {
+        loop {
+            if let Some(line) = lines.next_line() {
+                return Ok(line);
+            }
+            match output.next().await {
+                Some(Ok(LogOutput::StdOut { message })) => lines.push(&message),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(Error::from(e)),
+                None => return Err(exec_failed("exited before it started")),
+            }
+        }
+    };
+    let pid = tokio::time::timeout(KAFKA_TOOL_TIMEOUT, read_pid)
+        .await
+        .map_err(|_| exec_failed("did not start"))??;
+
+    let topic = topic.to_string();
+    let task = tokio::spawn(async move {
+        loop {
+            while let Some(line) = lines.next_line() {
+                if sender.send((topic.clone(), line)).is_err() {
+                    return;
+                }
+            }
+            match output.next().await {
+                Some(Ok(LogOutput::StdOut { message })) => lines.push(&message),
+                Some(Ok(LogOutput::StdErr { message })) => {
+                    log::debug!("{topic} consumer: {}", String::from_utf8_lossy(&message))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    log::warn!("{topic} consumer stopped: {e}");
+                    return;
+                }
+                None => return,
+            }
+        }
+    });
+    Ok(Consumer { pid, task })
+}
+
+impl Drop for KafkaSubscription {
+    fn drop(&mut self) {
+        for consumer in self.consumers.iter() {
+            consumer.task.abort();
+        }
+        if self.consumers.is_empty() {
+            return;
+        }
+        // Closing the output doesn't end a consumer that has nothing to print, so kill it.
+        let docker = self.docker.clone();
+        let kafka_container_name = self.kafka_container_name.clone();
+        let mut cmd = vec!["kill".to_string()];
+        cmd.extend(self.consumers.iter().map(|c| c.pid.clone()));
+        block_on_detached(async move {
+            let cmd: Vec<&str> = cmd.iter().map(String::as_str).collect();
+            let result = exec_in_container(
+                &docker,
+                &kafka_container_name,
+                &cmd,
+                Some(KAFKA_TOOL_TIMEOUT),
+            )
+            .await;
+            if let Err(e) = result {
+                log::warn!("Unable to stop the Kafka consumers: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_split_across_frames() {
+        let mut lines = LineSplitter::default();
+        lines.push(b"{\"machineId\":");
+        assert_eq!(lines.next_line(), None);
+        lines.push(b" \"1\"}\n\n  \n{\"name\": \"caf\xc3");
+        assert_eq!(lines.next_line().as_deref(), Some("{\"machineId\": \"1\"}"));
+        assert_eq!(lines.next_line(), None);
+        lines.push(b"\xa9\"}\r\n");
+        assert_eq!(
+            lines.next_line().as_deref(),
+            Some("{\"name\": \"caf\u{e9}\"}")
+        );
+        assert_eq!(lines.next_line(), None);
+        assert!(lines.buffer.is_empty());
+    }
+
+    #[test]
+    fn end_offsets_per_partition() {
+        let output = "machine.register:0:42\nmachine.register:1:7\nmachine.registered:0:3\n";
+        assert_eq!(
+            parse_end_offsets("machine.register", output),
+            vec![(0, 42), (1, 7)]
+        );
+        assert!(parse_end_offsets("machine.update", "Error: unknown topic\n").is_empty());
+    }
+}
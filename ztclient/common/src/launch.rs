@@ -0,0 +1,163 @@
+//! Starting and registering many clients at once.  Each client goes through container start,
+//! registration and callback on its own, at most `parallelism` of them at a time, and a failure
+//! only fails that client.
+
+use std::{fmt, future::Future};
+
+use futures::{stream, StreamExt};
+
+use crate::{Error, Result};
+
+/// Used when no `--parallelism` is given.
+pub const DEFAULT_PARALLELISM: usize = 8;
+
+/// What happened to one client.  `result` holds the machine ID, or whatever else the launch
+/// produced, on success.
+#[derive(Debug)]
+pub struct ClientOutcome {
+    pub container_name: String,
+    pub result: Result<String>,
+}
+
+/// The outcome of every client, in the order the container names were given.
+#[derive(Debug, Default)]
+pub struct LaunchReport {
+    pub outcomes: Vec<ClientOutcome>,
+}
+
+impl LaunchReport {
+    pub fn succeeded(&self) -> impl Iterator<Item = &ClientOutcome> {
+        self.outcomes.iter().filter(|o| o.result.is_ok())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &ClientOutcome> {
+        self.outcomes.iter().filter(|o| o.result.is_err())
+    }
+
+    pub fn all_succeeded(&self) -> bool {
+        self.failed().next().is_none()
+    }
+
+    /// The machine IDs (or other values) of the clients, or an error naming every client that
+    /// failed.
+    pub fn into_result(self) -> Result<Vec<String>> {
+        if !self.all_succeeded() {
+            return Err(Error::ClientsFailed(self.failure_summary()));
+        }
+        Ok(self
+            .outcomes
+            .into_iter()
+            .filter_map(|o| o.result.ok())
+            .collect())
+    }
+
+    fn failure_summary(&self) -> String {
+        let total = self.outcomes.len();
+        let failures: Vec<String> = self
+            .failed()
+            .filter_map(|o| {
+                let e = o.result.as_ref().err()?;
+                Some(format!("{}: {e}", o.container_name))
+            })
+            .collect();
+        format!(
+            "{} of {total} clients failed\n{}",
+            failures.len(),
+            failures.join("\n")
+        )
+    }
+}
+
+impl fmt::Display for LaunchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for outcome in self.outcomes.iter() {
+            match &outcome.result {
+                Ok(value) => writeln!(f, "{:<24} ok     {value}", outcome.container_name)?,
+                Err(e) => writeln!(f, "{:<24} FAILED {e}", outcome.container_name)?,
+            }
+        }
+        write!(
+            f,
+            "{} of {} clients succeeded",
+            self.succeeded().count(),
+            self.outcomes.len()
+        )
+    }
+}
+
+/// Runs `launch` for every container name, with at most `parallelism` running at the same time,
+/// and collects the outcome of each.  `launch` gets the name's index in `container_names` along
+/// with the name.  A `parallelism` of 0 is treated as 1.
+pub async fn launch_clients<F, Fut>(
+    container_names: &[String],
+    parallelism: usize,
+    launch: F,
+) -> LaunchReport
+where
+    F: Fn(usize, String) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let outcomes = stream::iter(container_names.iter().cloned().enumerate())
+        .map(|(index, container_name)| {
+            let future = launch(index, container_name.clone());
+            async move {
+                let result = future.await;
+                match &result {
+                    Ok(_) => log::info!("Launched {container_name}"),
+                    Err(e) => log::warn!("Unable to launch {container_name}: {e}"),
+                }
+                ClientOutcome {
+                    container_name,
+                    result,
+                }
+            }
+        })
+        .buffered(parallelism.max(1))
+        .collect()
+        .await;
+    LaunchReport { outcomes }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn parallelism_is_bounded_and_failures_are_kept() {
+        let names: Vec<String> = (1..=10).map(|x| format!("client{x:0>3}")).collect();
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+
+        let report = launch_clients(&names, 3, |_, name| {
+            let (running, most_running) = (&running, &most_running);
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                if name.ends_with('4') {
+                    Err(Error::Verification("no callback".to_string()))
+                } else {
+                    Ok(format!("machine:{name}"))
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(most_running.load(Ordering::SeqCst), 3);
+        assert_eq!(report.outcomes.len(), 10);
+        assert_eq!(report.outcomes[0].container_name, "client001");
+        assert_eq!(report.failed().count(), 1);
+        let e = report.into_result().unwrap_err().to_string();
+        assert!(e.contains("1 of 10 clients failed"), "{e}");
+        assert!(
+            e.contains("client004: verification failed: no callback"),
+            "{e}"
+        );
+    }
+}
@@ -1,23 +1,45 @@
-use anyhow::{bail, Result};
 use bollard::Docker;
 use containers::remove_container;
-use ninjapanda::delete_machine;
-use reqwest::Client;
+use launch::launch_clients;
+use ninjapanda::NinjaPandaClient;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::read_to_string};
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
 use ztclient::{start_ztclientd, ztclient_registration, ztclient_registration_nh};
 
-use crate::models::ExecuteCallbackResponse;
-
+pub mod acl;
 pub mod containers;
+pub mod context;
+pub mod environment;
 pub mod errors;
+pub mod exec;
+pub mod fixtures;
+pub mod handle;
 pub mod intgates;
+pub mod ipn;
+pub mod kafka;
+pub mod launch;
+pub mod loadtest;
 pub mod models;
+pub mod namespaces;
+pub mod netmap;
 pub mod ninjapanda;
+pub mod policy;
+pub mod poll;
+pub mod reachability;
+pub mod readiness;
+pub mod report;
+pub mod scenario;
+pub mod soak;
 pub mod users;
 pub mod ztclient;
 
-#[derive(Deserialize, Debug)]
+pub use errors::{Error, Result};
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub ninja_panda_container_name: String,
     pub postgres_container_name: String,
@@ -33,6 +55,67 @@ pub struct Config {
     pub kafka_container_name: String,
 }
 
+impl Config {
+    /// The same configuration with the container and network names of `environment_id`, see
+    /// [`scoped_name`].
+    pub fn for_environment(&self, environment_id: &str) -> Config {
+        Config {
+            ninja_panda_container_name: scoped_name(
+                &self.ninja_panda_container_name,
+                environment_id,
+            ),
+            postgres_container_name: scoped_name(&self.postgres_container_name, environment_id),
+            docker_network_name: scoped_name(&self.docker_network_name, environment_id),
+            kafka_container_name: scoped_name(&self.kafka_container_name, environment_id),
+            ..self.clone()
+        }
+    }
+}
+
+/// Environment used when neither `--environment` nor `ZTCLIENT_ENV` name one.
+pub const DEFAULT_ENVIRONMENT: &str = "default";
+/// Names the environment to use, so several stacks can share one host.
+pub const ENVIRONMENT_VAR: &str = "ZTCLIENT_ENV";
+/// Directory holding the runtime information files, which skips the directory walk.
+pub const RUNTIME_PATH_VAR: &str = "ZTCLIENT_RUNTIME";
+
+/// The environment named by `ZTCLIENT_ENV`, or the default one.
+pub fn current_environment() -> String {
+    std::env::var(ENVIRONMENT_VAR)
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| DEFAULT_ENVIRONMENT.to_string())
+}
+
+/// Container and network names get the environment ID as a suffix, except in the default
+/// environment, which keeps the names from `.env` and `docker-compose.yaml`.
+pub fn scoped_name(name: &str, environment_id: &str) -> String {
+    if environment_id == DEFAULT_ENVIRONMENT {
+        name.to_string()
+    } else {
+        format!("{name}-{environment_id}")
+    }
+}
+
+/// `running.json` for the default environment, `running-<id>.json` for the others.
+pub fn runtime_file_name(environment_id: &str) -> String {
+    if environment_id == DEFAULT_ENVIRONMENT {
+        "running.json".to_string()
+    } else {
+        format!("running-{environment_id}.json")
+    }
+}
+
+/// Where the runtime information of `environment_id` is written: the `ZTCLIENT_RUNTIME`
+/// directory if it is set, otherwise the current directory.
+pub fn runtime_file_path(environment_id: &str) -> PathBuf {
+    let file_name = runtime_file_name(environment_id);
+    match std::env::var_os(RUNTIME_PATH_VAR) {
+        Some(dir) => Path::new(&dir).join(file_name),
+        None => PathBuf::from(file_name),
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RuntimeInformation {
@@ -40,23 +123,81 @@ pub struct RuntimeInformation {
     pub ninja_panda_api_key: String,
     /// The URL to Ninja Panda's API
     pub ninja_panda_api_url: String,
+    /// Name of the environment this file describes
+    #[serde(default)]
+    pub environment_id: String,
+    /// Docker network the services and clients are attached to
+    #[serde(default)]
+    pub network_name: String,
+    /// The service containers, with their images and published ports
+    #[serde(default)]
+    pub containers: Vec<RuntimeContainer>,
+    /// Seconds since the epoch at which the environment was created
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeContainer {
+    pub name: String,
+    pub image: String,
+    /// Container port (e.g. `80/tcp`) to the host port it is published on
+    #[serde(default)]
+    pub ports: HashMap<String, String>,
+}
+
+impl RuntimeInformation {
+    /// The host port `container_name` publishes `container_port` (e.g. `80/tcp`) on.
+    pub fn published_port(&self, container_name: &str, container_port: &str) -> Option<&str> {
+        self.containers
+            .iter()
+            .find(|container| container.name == container_name)?
+            .ports
+            .get(container_port)
+            .map(String::as_str)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(self).map_err(std::io::Error::from)?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<RuntimeInformation> {
+        let file_text = read_to_string(path)?;
+        serde_json::from_str(file_text.as_str()).map_err(|source| Error::Decode {
+            what: path.display().to_string(),
+            payload: file_text.clone(),
+            source,
+        })
+    }
 }
 
-/// Read the running.json file at the root of the runtime tree after an environment has
-/// been started, and returns a Result with the runtime information structure that was
-/// parsed from the file.
+/// Reads the runtime information of the current environment (see [`current_environment`]) that
+/// was written when the environment was created.
 pub fn get_running_json() -> Result<RuntimeInformation> {
-    // In the case of our tests the path is two levels deep.
-    let paths = ["./running.json", "../../running.json"];
-    for path in paths.iter() {
-        let check_file = read_to_string(path);
-        if let Ok(file_text) = check_file {
-            let runtime_information: RuntimeInformation =
-                serde_json::from_str(file_text.as_str()).unwrap();
-            return Ok(runtime_information);
+    get_running_json_for(&current_environment())
+}
+
+/// Reads the runtime information of `environment_id` from the `ZTCLIENT_RUNTIME` directory if it
+/// is set.  Otherwise looks for its runtime file in the current directory and each of its
+/// parents, which covers both the repository root and the test crates.
+pub fn get_running_json_for(environment_id: &str) -> Result<RuntimeInformation> {
+    if std::env::var_os(RUNTIME_PATH_VAR).is_some() {
+        return RuntimeInformation::load(&runtime_file_path(environment_id));
+    }
+    let file_name = runtime_file_name(environment_id);
+    let start = std::env::current_dir()?;
+    let mut searched = Vec::new();
+    for dir in start.ancestors() {
+        let path = dir.join(&file_name);
+        if path.is_file() {
+            return RuntimeInformation::load(&path);
         }
+        searched.push(path.display().to_string());
     }
-    bail!("Unable to read runtime information");
+    Err(Error::RuntimeInformationNotFound(searched))
 }
 
 pub fn get_unique_timestamp() -> u128 {
@@ -65,14 +206,6 @@ pub fn get_unique_timestamp() -> u128 {
         .unwrap()
         .as_nanos()
 }
-pub struct ExecuteCallbackRequest<'a> {
-    pub correlation_id: &'a str,
-    pub api_key: &'a str,
-    pub namespace_name: &'a str,
-    pub ninja_panda_api_url: &'a str,
-    pub user_info_id: usize,
-}
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RegisterCallbackRequest {
@@ -80,40 +213,6 @@ pub struct RegisterCallbackRequest {
     pub user_info: users::UserInfo,
 }
 
-pub async fn execute_callback<'a>(
-    client: &reqwest::Client,
-    request: &ExecuteCallbackRequest<'a>,
-) -> Result<String> {
-    let user_info = users::get_user(request.user_info_id);
-    let reg_request = RegisterCallbackRequest {
-        namespace: request.namespace_name.to_owned(),
-        user_info,
-    };
-    let _json_request = serde_json::to_string(&reg_request).unwrap();
-
-    let url = format!(
-        "{}/api/v1/machine/register/callback/{}",
-        request.ninja_panda_api_url, request.correlation_id
-    );
-    let _res = client
-        .post(url)
-        .bearer_auth(request.api_key)
-        .json(&reg_request)
-        .send()
-        .await?;
-    assert_eq!(
-        200,
-        _res.status(),
-        "Register callback POST call did not return 200"
-    );
-    let _response: ExecuteCallbackResponse = _res.json().await.unwrap();
-    let machine_id = _response.machine.machine_id;
-
-    // TODO: When we add logging just log this body to the debug level.
-    // let body: serde_json::Value = res.json().await?;
-    Ok(machine_id)
-}
-
 /// These labels are added to the docker container.  These are what allow Docker Desktop to believe
 /// that this is a Compose project, and store all the containers in the hierarchical structure that
 /// comes when you use a compose.yaml file
@@ -141,104 +240,138 @@ pub fn random_names(n: usize) -> Vec<String> {
     v
 }
 
+/// Starts and registers the clients, `parallelism` at a time, and returns their machine IDs in
+/// the same order.  Every client is tried; the error names all the ones that failed.
 pub async fn start_and_register_n_clients(
     docker: &Docker,
-    runtime_info: &RuntimeInformation,
+    np: &NinjaPandaClient,
     config: &Config,
-    client: &Client,
     namespace_name: &str,
     user_id: usize,
-    container_names: &Vec<String>,
-) {
-    for container_name in container_names {
-        start_and_register_client(
-            docker,
-            runtime_info,
-            config,
-            client,
-            container_name,
-            namespace_name,
-            user_id,
-        )
-        .await
-    }
+    container_names: &[String],
+    parallelism: usize,
+) -> Result<Vec<String>> {
+    launch_clients(
+        container_names,
+        parallelism,
+        |_, container_name| async move {
+            start_and_register_client(docker, np, config, &container_name, namespace_name, user_id)
+                .await
+        },
+    )
+    .await
+    .into_result()
 }
 pub async fn start_and_register_client(
     docker: &Docker,
-    runtime_info: &RuntimeInformation,
+    np: &NinjaPandaClient,
     config: &Config,
-    client: &Client,
     container_name: &str,
     namespace_name: &str,
     user_id: usize,
-) {
-    start_ztclientd(docker, config, container_name)
+) -> Result<String> {
+    start_ztclientd(docker, config, container_name).await?;
+    let correlation_id = ztclient_registration(docker, container_name).await?;
+    np.execute_callback(&correlation_id, namespace_name, user_id)
         .await
-        .unwrap();
-    let correlation_id = ztclient_registration(docker, container_name).await.unwrap();
-    let request = ExecuteCallbackRequest {
-        correlation_id: correlation_id.as_str(),
-        api_key: &runtime_info.ninja_panda_api_key,
-        namespace_name,
-        user_info_id: user_id,
-        ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-    };
-    execute_callback(client, &request).await.unwrap();
 }
 
 pub async fn start_and_register_client_nh(
     docker: &Docker,
-    runtime_info: &RuntimeInformation,
+    np: &NinjaPandaClient,
     config: &Config,
-    client: &Client,
     container_name: &str,
     namespace_name: &str,
     user_id: usize,
-) {
-    start_ztclientd(docker, config, container_name)
-        .await
-        .unwrap();
-    let correlation_id = ztclient_registration_nh(docker, container_name)
+) -> Result<String> {
+    start_ztclientd(docker, config, container_name).await?;
+    let correlation_id = ztclient_registration_nh(docker, container_name).await?;
+    np.execute_callback(&correlation_id, namespace_name, user_id)
         .await
-        .unwrap();
-    let request = ExecuteCallbackRequest {
-        correlation_id: correlation_id.as_str(),
-        api_key: &runtime_info.ninja_panda_api_key,
-        namespace_name,
-        user_info_id: user_id,
-        ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-    };
-    execute_callback(client, &request).await.unwrap();
 }
 
 pub async fn container_cleanup(
     docker: &Docker,
     container_names: Vec<String>,
     machine_ids: Vec<String>,
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-) {
-    //TODO: I'd really like to have an env var or something to ke
-    if true {
-        return;
+    np: &NinjaPandaClient,
+) -> Result<()> {
+    if std::env::var(context::DEBUG_CONTAINERS_VAR).is_ok() {
+        return Ok(());
     }
 
     for x in container_names.iter() {
         remove_container(docker, x).await;
     }
     for machine_id in machine_ids {
-        delete_machine(&machine_id, runtime_info, client)
-            .await
-            .unwrap();
+        np.delete_machine(&machine_id).await?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn it_works() {
         let result = 4;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn old_running_json_still_loads() {
+        let text = r#"{"ninjaPandaApiKey":"key","ninjaPandaApiUrl":"http://localhost:15000"}"#;
+        let runtime_info: RuntimeInformation = serde_json::from_str(text).unwrap();
+        assert_eq!(runtime_info.ninja_panda_api_key, "key");
+        assert!(runtime_info.containers.is_empty());
+    }
+
+    #[test]
+    fn only_named_environments_are_scoped() {
+        assert_eq!(
+            scoped_name("ztclient_kafka", DEFAULT_ENVIRONMENT),
+            "ztclient_kafka"
+        );
+        assert_eq!(
+            scoped_name("ztclient_kafka", "ci-42"),
+            "ztclient_kafka-ci-42"
+        );
+        assert_eq!(runtime_file_name(DEFAULT_ENVIRONMENT), "running.json");
+        assert_eq!(runtime_file_name("ci-42"), "running-ci-42.json");
+    }
+
+    #[test]
+    fn runtime_dir_keeps_environments_apart() {
+        std::env::set_var(RUNTIME_PATH_VAR, "/tmp/ztclient");
+        let default = runtime_file_path(DEFAULT_ENVIRONMENT);
+        let named = runtime_file_path("ci-42");
+        std::env::remove_var(RUNTIME_PATH_VAR);
+        assert_eq!(default, Path::new("/tmp/ztclient/running.json"));
+        assert_eq!(named, Path::new("/tmp/ztclient/running-ci-42.json"));
+    }
+
+    #[test]
+    fn published_ports_by_container() {
+        let runtime_info = RuntimeInformation {
+            containers: vec![RuntimeContainer {
+                name: "ztclient_nginx-ci".to_string(),
+                image: "nginx:latest".to_string(),
+                ports: HashMap::from([("80/tcp".to_string(), "32768".to_string())]),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            runtime_info.published_port("ztclient_nginx-ci", "80/tcp"),
+            Some("32768")
+        );
+        assert_eq!(
+            runtime_info.published_port("ztclient_nginx-ci", "443/tcp"),
+            None
+        );
+        assert_eq!(
+            runtime_info.published_port("ztclient_nginx", "80/tcp"),
+            None
+        );
+    }
 }
@@ -0,0 +1,342 @@
+//! Registration and convergence latency under load.  Clients are started at a fixed rate, put in
+//! one peer policy once they are all running, and timed until their netmaps list every other
+//! client.
+
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bollard::Docker;
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    models::status::BackendState,
+    ninjapanda::NinjaPandaClient,
+    ztclient::{
+        create_and_register_client, numbered_client_names, user_for_client, wait_for_peer_count,
+        wait_for_state_change,
+    },
+    Config, Error, Result,
+};
+
+#[derive(Debug, Clone)]
+pub struct LoadTestSettings {
+    pub num_clients: usize,
+    /// Clients started per second.
+    pub rate: f64,
+    pub hostname_prefix: String,
+    pub namespace_name: String,
+    /// How long each client may take to see all its peers once the policy exists.
+    pub convergence_timeout: Duration,
+}
+
+impl LoadTestSettings {
+    /// The time between two client starts.  Fails unless `rate` is a positive number.
+    pub fn interval(&self) -> Result<Duration> {
+        let invalid = || {
+            Error::InvalidArgument(format!(
+                "the rate must be a positive number of clients per second, not {}",
+                self.rate
+            ))
+        };
+        if !self.rate.is_finite() {
+            return Err(invalid());
+        }
+        Duration::try_from_secs_f64(1.0 / self.rate).map_err(|_| invalid())
+    }
+}
+
+/// Timings of one client, in milliseconds.  `time_to_running_ms` counts from the moment the
+/// client was started, `time_to_converged_ms` from the moment the peer policy was created.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientSample {
+    pub container_name: String,
+    pub machine_id: Option<String>,
+    pub time_to_running_ms: Option<u64>,
+    pub time_to_converged_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Nearest-rank percentiles, in milliseconds, over the clients that got that far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub missing: usize,
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    pub fn from_samples(samples: impl IntoIterator<Item = Option<u64>>) -> LatencySummary {
+        let mut missing = 0;
+        let mut values: Vec<u64> = samples
+            .into_iter()
+            .filter_map(|v| {
+                if v.is_none() {
+                    missing += 1;
+                }
+                v
+            })
+            .collect();
+        values.sort_unstable();
+        let percentile = |p: usize| match values.len() {
+            0 => 0,
+            n => values[((p * n).div_ceil(100)).clamp(1, n) - 1],
+        };
+        LatencySummary {
+            count: values.len(),
+            missing,
+            min: values.first().copied().unwrap_or_default(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: values.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadTestReport {
+    pub num_clients: usize,
+    pub rate: f64,
+    /// Seconds since the epoch.
+    pub started_at: u64,
+    pub policy_id: Option<String>,
+    pub running: LatencySummary,
+    pub converged: LatencySummary,
+    pub samples: Vec<ClientSample>,
+}
+
+impl LoadTestReport {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "container_name,machine_id,time_to_running_ms,time_to_converged_ms,error\n",
+        );
+        for s in self.samples.iter() {
+            let field = |v: &Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},\"{}\"\n",
+                s.container_name,
+                s.machine_id.as_deref().unwrap_or_default(),
+                field(&s.time_to_running_ms),
+                field(&s.time_to_converged_ms),
+                s.error.as_deref().unwrap_or_default().replace('"', "\"\""),
+            ));
+        }
+        csv
+    }
+
+    /// Writes the samples as JSON if `path` ends in `.json`, as CSV otherwise.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self).map_err(std::io::Error::from)?,
+            _ => self.to_csv(),
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>6} {:>7} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            "MS", "COUNT", "MISSING", "MIN", "P50", "P90", "P95", "P99", "MAX"
+        )?;
+        for (name, s) in [("running", &self.running), ("converged", &self.converged)] {
+            writeln!(
+                f,
+                "{name:<12} {:>6} {:>7} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                s.count, s.missing, s.min, s.p50, s.p90, s.p95, s.p99, s.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+/// Runs the load test in `settings.namespace_name`.  Failures of single clients end up in their
+/// sample; only failing to create the namespace or the policy is an error.  The clients and the
+/// policy are left in place.
+pub async fn run_load_test(
+    docker: &Docker,
+    np: &NinjaPandaClient,
+    config: &Config,
+    settings: &LoadTestSettings,
+) -> Result<LoadTestReport> {
+    let interval = settings.interval()?;
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    np.create_namespace(&settings.namespace_name).await?;
+
+    let container_names = numbered_client_names(&settings.hostname_prefix, settings.num_clients, 0);
+    let start = Instant::now();
+    let mut samples = join_all(container_names.iter().enumerate().map(|(i, name)| {
+        let launch_at = start + interval.mul_f64(i as f64);
+        async move {
+            sleep_until(launch_at).await;
+            let mut sample = ClientSample {
+                container_name: name.clone(),
+                ..Default::default()
+            };
+            let result = async {
+                let machine_id = create_and_register_client(
+                    np,
+                    docker,
+                    config,
+                    name,
+                    &settings.namespace_name,
+                    user_for_client(i),
+                )
+                .await?;
+                sample.machine_id = Some(machine_id);
+                wait_for_state_change(docker, name, BackendState::Running).await?;
+                sample.time_to_running_ms = Some(millis(launch_at.elapsed()));
+                Ok::<_, Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                log::warn!("{name} did not reach {}: {e}", BackendState::Running);
+                sample.error = Some(e.to_string());
+            }
+            sample
+        }
+    }))
+    .await;
+
+    let running: Vec<&mut ClientSample> = samples
+        .iter_mut()
+        .filter(|s| s.time_to_running_ms.is_some())
+        .collect();
+    let machine_ids: Vec<String> = running
+        .iter()
+        .filter_map(|s| s.machine_id.as_ref())
+        .map(|id| format!("machine:{id}"))
+        .collect();
+    let policy_id = if machine_ids.len() > 1 {
+        let policy_id = np.make_all_machines_peers(&machine_ids).await?;
+        let policy_created = Instant::now();
+        let expected_peers = machine_ids.len() - 1;
+        join_all(running.into_iter().map(|sample| async move {
+            match wait_for_peer_count(
+                docker,
+                &sample.container_name,
+                settings.convergence_timeout,
+                |peers| peers >= expected_peers,
+            )
+            .await
+            {
+                Ok(_) => sample.time_to_converged_ms = Some(millis(policy_created.elapsed())),
+                Err(e) => {
+                    log::warn!("{} did not converge: {e}", sample.container_name);
+                    sample.error = Some(e.to_string());
+                }
+            }
+        }))
+        .await;
+        Some(policy_id)
+    } else {
+        None
+    };
+
+    Ok(LoadTestReport {
+        num_clients: settings.num_clients,
+        rate: settings.rate,
+        started_at,
+        policy_id,
+        running: LatencySummary::from_samples(samples.iter().map(|s| s.time_to_running_ms)),
+        converged: LatencySummary::from_samples(samples.iter().map(|s| s.time_to_converged_ms)),
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let summary = LatencySummary::from_samples((1..=100).map(Some).chain([None, None]));
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.missing, 2);
+        assert_eq!(
+            (
+                summary.min,
+                summary.p50,
+                summary.p90,
+                summary.p99,
+                summary.max
+            ),
+            (1, 50, 90, 99, 100)
+        );
+        assert_eq!(LatencySummary::from_samples([Some(7)]).p99, 7);
+        assert_eq!(LatencySummary::from_samples([None]).p50, 0);
+    }
+
+    #[test]
+    fn csv_has_a_row_per_client() {
+        let report = LoadTestReport {
+            num_clients: 2,
+            rate: 1.0,
+            started_at: 0,
+            policy_id: None,
+            running: LatencySummary::default(),
+            converged: LatencySummary::default(),
+            samples: vec![
+                ClientSample {
+                    container_name: "lt001".to_string(),
+                    machine_id: Some("42".to_string()),
+                    time_to_running_ms: Some(1500),
+                    time_to_converged_ms: Some(800),
+                    error: None,
+                },
+                ClientSample {
+                    container_name: "lt002".to_string(),
+                    error: Some("said \"no\"".to_string()),
+                    ..Default::default()
+                },
+            ],
+        };
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "lt001,42,1500,800,\"\"");
+        assert_eq!(lines[2], "lt002,,,,\"said \"\"no\"\"\"");
+    }
+
+    #[test]
+    fn rate_must_be_positive() {
+        let settings = |rate| LoadTestSettings {
+            num_clients: 1,
+            rate,
+            hostname_prefix: "lt".to_string(),
+            namespace_name: "lt".to_string(),
+            convergence_timeout: Duration::from_secs(1),
+        };
+        assert_eq!(
+            settings(4.0).interval().unwrap(),
+            Duration::from_millis(250)
+        );
+        for rate in [0.0, -0.0, -1.0, f64::NAN, f64::INFINITY, f64::MIN_POSITIVE] {
+            assert!(
+                matches!(settings(rate).interval(), Err(Error::InvalidArgument(_))),
+                "{rate}"
+            );
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -88,6 +89,29 @@ pub struct Namespace {
     pub default_machine_key_ttl: String,
 }
 
+impl Namespace {
+    /// When the namespace was created, if Ninja Panda said so in RFC 3339.
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.created_at)
+            .ok()
+            .map(|created| created.with_timezone(&Utc))
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceResponse {
+    pub namespace: Namespace,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListNamespacesResponse {
+    /// Left out of the response when there are no namespaces.
+    #[serde(default)]
+    pub namespaces: Vec<Namespace>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MachineLocation {
@@ -156,6 +180,28 @@ pub struct PreauthTokenResponse {
     pub pre_auth_key: PreauthToken,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpirePreauthTokenRequest {
+    pub namespace: String,
+    pub key: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokePreauthTokenRequest {
+    pub namespace: String,
+    pub key: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListPreauthTokensResponse {
+    /// Left out of the response when the namespace has no keys.
+    #[serde(default)]
+    pub pre_auth_keys: Vec<PreauthToken>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PreauthToken {
@@ -172,18 +218,137 @@ pub struct PreauthToken {
     pub acl: Option<Vec<String>>,
 }
 
+impl PreauthToken {
+    /// How many more machines may register with the key.  Ninja Panda counts `reuse_count` down
+    /// with every registration; `None` if the response leaves it out.
+    pub fn uses_remaining(&self) -> Option<u64> {
+        self.reuse_count.trim().parse().ok()
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.as_ref().is_some_and(|at| !at.is_empty())
+    }
+
+    /// Whether `machine` was registered with this key.
+    pub fn registered(&self, machine: &Machine) -> bool {
+        machine
+            .pre_auth_key
+            .as_ref()
+            .and_then(|key| key.get("preAuthKeyId"))
+            .and_then(Value::as_str)
+            .is_some_and(|id| id == self.pre_auth_key_id)
+    }
+
+    /// Works out [`PreauthToken::uses_remaining`] from every machine Ninja Panda knows instead,
+    /// for a key as it was created, with `reuse_count` still at its initial value.  Machines that
+    /// were deleted don't count.
+    pub fn uses_remaining_after(&self, machines: &[Machine]) -> Option<u64> {
+        let used = machines.iter().filter(|m| self.registered(m)).count() as u64;
+        self.uses_remaining().map(|max| max.saturating_sub(used))
+    }
+}
+
 pub mod status {
     use crate::users::UserInfo;
 
     use super::*;
 
+    /// The state of the client's backend.  `ztclient status --json` reports it by name and the
+    /// ztn bus by number, in the order of the variants here; both deserialize to this.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(try_from = "BackendStateRepr")]
+    pub enum BackendState {
+        #[default]
+        NoState,
+        InUse,
+        NeedsLogin,
+        NeedsMachineAuth,
+        Stopped,
+        Starting,
+        Running,
+    }
+
+    impl BackendState {
+        pub const ALL: [BackendState; 7] = [
+            BackendState::NoState,
+            BackendState::InUse,
+            BackendState::NeedsLogin,
+            BackendState::NeedsMachineAuth,
+            BackendState::Stopped,
+            BackendState::Starting,
+            BackendState::Running,
+        ];
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                BackendState::NoState => "NoState",
+                BackendState::InUse => "InUse",
+                BackendState::NeedsLogin => "NeedsLogin",
+                BackendState::NeedsMachineAuth => "NeedsMachineAuth",
+                BackendState::Stopped => "Stopped",
+                BackendState::Starting => "Starting",
+                BackendState::Running => "Running",
+            }
+        }
+    }
+
+    impl std::fmt::Display for BackendState {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+
+    impl std::str::FromStr for BackendState {
+        type Err = crate::Error;
+
+        fn from_str(state: &str) -> crate::Result<BackendState> {
+            BackendState::ALL
+                .into_iter()
+                .find(|s| s.as_str() == state)
+                .ok_or_else(|| {
+                    crate::Error::Verification(format!("{state:?} is not a known backend state"))
+                })
+        }
+    }
+
+    impl TryFrom<i64> for BackendState {
+        type Error = crate::Error;
+
+        fn try_from(state: i64) -> crate::Result<BackendState> {
+            usize::try_from(state)
+                .ok()
+                .and_then(|n| BackendState::ALL.get(n).copied())
+                .ok_or_else(|| {
+                    crate::Error::Verification(format!("{state} is not a known backend state"))
+                })
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BackendStateRepr {
+        Number(i64),
+        Name(String),
+    }
+
+    impl TryFrom<BackendStateRepr> for BackendState {
+        type Error = crate::Error;
+
+        fn try_from(repr: BackendStateRepr) -> crate::Result<BackendState> {
+            match repr {
+                BackendStateRepr::Number(n) => BackendState::try_from(n),
+                BackendStateRepr::Name(name) => name.parse(),
+            }
+        }
+    }
+
     #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct StatusResult {
         #[serde(rename = "Version")]
         pub version: String,
         #[serde(rename = "BackendState")]
-        pub backend_state: String,
+        pub backend_state: BackendState,
         #[serde(rename = "AuthURL")]
         pub auth_url: String,
         #[serde(rename = "ZTMeshIPs")]
@@ -206,22 +371,20 @@ pub mod status {
 
     impl StatusResult {
         pub fn is_tagged_user(&self) -> bool {
-            if let Some(user) = &self.user {
-                let obj = user.iter().nth(0).unwrap();
-                obj.1.login_name == "tagged-devices"
-            } else {
-                false
-            }
+            self.user
+                .as_ref()
+                .and_then(|user| user.values().next())
+                .is_some_and(|obj| obj.login_name == "tagged-devices")
         }
         pub fn assert_user(&self, user_info: &UserInfo) -> bool {
-            if let Some(user) = &self.user {
-                let obj = user.iter().nth(0).unwrap();
-                obj.1.login_name == user_info.email
-                    && obj.1.first_name == user_info.first_name
-                    && obj.1.last_name == user_info.last_name
-            } else {
-                false
-            }
+            self.user
+                .as_ref()
+                .and_then(|user| user.values().next())
+                .is_some_and(|obj| {
+                    obj.login_name == user_info.email
+                        && obj.first_name == user_info.first_name
+                        && obj.last_name == user_info.last_name
+                })
         }
     }
     #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -348,8 +511,12 @@ pub mod ztn {
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
 
+    use super::status::BackendState;
+
+    /// One notification from the client's IPN bus.  The client leaves out the fields that didn't
+    /// change, so every field may be missing.
     #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-    #[serde(rename_all = "camelCase")]
+    #[serde(rename_all = "camelCase", default)]
     pub struct ZtnMessage {
         #[serde(rename = "Version")]
         pub version: String,
@@ -358,7 +525,7 @@ pub mod ztn {
         #[serde(rename = "LoginFinished")]
         pub login_finished: Value,
         #[serde(rename = "State")]
-        pub state: Option<i64>,
+        pub state: Option<BackendState>,
         #[serde(rename = "Prefs")]
         pub prefs: Option<Prefs>,
         #[serde(rename = "NetMap")]
@@ -790,3 +957,52 @@ pub mod routes {
         pub is_primary: bool,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{status::BackendState, Machine, PreauthToken};
+
+    #[test]
+    fn backend_state_from_name_and_number() {
+        let state: BackendState = serde_json::from_str("\"NeedsMachineAuth\"").unwrap();
+        assert_eq!(state, BackendState::NeedsMachineAuth);
+        let state: BackendState = serde_json::from_str("6").unwrap();
+        assert_eq!(state, BackendState::Running);
+        assert_eq!(
+            serde_json::to_string(&BackendState::Running).unwrap(),
+            "\"Running\""
+        );
+        assert!(serde_json::from_str::<BackendState>("7").is_err());
+        assert!(serde_json::from_str::<BackendState>("\"Running \"").is_err());
+    }
+
+    #[test]
+    fn preauth_token_uses_remaining() {
+        let token = PreauthToken {
+            pre_auth_key_id: "7".to_string(),
+            reuse_count: "2".to_string(),
+            ..Default::default()
+        };
+        let machine = |id: &str| Machine {
+            pre_auth_key: Some(serde_json::json!({ "preAuthKeyId": id })),
+            ..Default::default()
+        };
+        assert_eq!(token.uses_remaining(), Some(2));
+        assert_eq!(token.uses_remaining_after(&[]), Some(2));
+        assert_eq!(
+            token.uses_remaining_after(&[machine("7"), machine("8"), Machine::default()]),
+            Some(1)
+        );
+        assert_eq!(
+            token.uses_remaining_after(&[machine("7"), machine("7"), machine("7")]),
+            Some(0)
+        );
+        let unreported = PreauthToken {
+            reuse_count: String::new(),
+            ..token
+        };
+        assert_eq!(unreported.uses_remaining(), None);
+        assert_eq!(unreported.uses_remaining_after(&[machine("7")]), None);
+        assert!(!unreported.is_revoked());
+    }
+}
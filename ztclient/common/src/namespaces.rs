@@ -0,0 +1,129 @@
+//! Finding and deleting the namespaces that tests left behind, such as `optm<timestamp>` or the
+//! `test<name>` ones of [`crate::fixtures::namespaced`] when a run was killed before teardown.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{models::Namespace, ninjapanda::NinjaPandaClient, Result};
+
+/// Which namespaces to prune: those whose name matches `pattern` and that were created more than
+/// `older_than` ago.  In the pattern, `*` matches any run of characters and everything else
+/// matches itself.
+#[derive(Debug, Clone)]
+pub struct NamespaceFilter {
+    pub pattern: String,
+    pub older_than: Duration,
+}
+
+impl NamespaceFilter {
+    /// A namespace whose creation time can't be read is never old enough.
+    pub fn matches(&self, namespace: &Namespace, now: DateTime<Utc>) -> bool {
+        let old_enough = namespace.created().is_some_and(|created| {
+            (now - created)
+                .to_std()
+                .is_ok_and(|age| age > self.older_than)
+        });
+        old_enough && wildcard_match(&self.pattern, &namespace.name)
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Deletes the namespaces that match `filter`, and first the machines in them, since a namespace
+/// can't be deleted while it has machines.  With `dry_run`, only lists them.  Returns the names
+/// of the namespaces, in the order Ninja Panda listed them; one that fails to delete stops the
+/// pruning.
+pub async fn prune_namespaces(
+    np: &NinjaPandaClient,
+    filter: &NamespaceFilter,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    let now = Utc::now();
+    let stale: Vec<Namespace> = np
+        .list_namespaces()
+        .await?
+        .into_iter()
+        .filter(|namespace| filter.matches(namespace, now))
+        .collect();
+    if dry_run || stale.is_empty() {
+        return Ok(stale.into_iter().map(|namespace| namespace.name).collect());
+    }
+
+    let machines = np.get_machines().await?;
+    let mut pruned = Vec::new();
+    for namespace in stale {
+        for machine in machines
+            .iter()
+            .filter(|m| m.namespace.name == namespace.name)
+        {
+            np.delete_machine(&machine.machine_id).await?;
+        }
+        np.delete_namespace(&namespace.name).await?;
+        log::info!(
+            "Deleted namespace {} from {}",
+            namespace.name,
+            namespace.created_at
+        );
+        pruned.push(namespace.name);
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("optm*", "optm1712345678"));
+        assert!(wildcard_match("optm*", "optm"));
+        assert!(!wildcard_match("optm*", "xoptm1"));
+        assert!(wildcard_match("*test*", "a_test_b"));
+        assert!(wildcard_match("t*t", "tt"));
+        assert!(wildcard_match("t*st", "tst"));
+        assert!(!wildcard_match("t*st", "ts"));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("exact", "exactly"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn only_old_matching_namespaces() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let namespace = |name: &str, created_at: &str| Namespace {
+            name: name.to_string(),
+            created_at: created_at.to_string(),
+            ..Default::default()
+        };
+        let filter = NamespaceFilter {
+            pattern: "optm*".to_string(),
+            older_than: Duration::from_secs(3600),
+        };
+        assert!(filter.matches(&namespace("optm1", "2024-05-01T10:00:00Z"), now));
+        assert!(!filter.matches(&namespace("optm2", "2024-05-01T11:30:00Z"), now));
+        assert!(!filter.matches(&namespace("patswitch", "2024-04-01T10:00:00Z"), now));
+        assert!(!filter.matches(&namespace("optm3", "yesterday"), now));
+        assert!(!filter.matches(&namespace("optm4", "2024-05-02T10:00:00Z"), now));
+    }
+}
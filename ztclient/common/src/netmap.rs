@@ -0,0 +1,325 @@
+//! What changed between two netmaps of a client, and waiting for a client's netmap to get to a
+//! given state.  [`wait_for_netmap`] says what was still changing when it gives up, so a test that
+//! times out shows how far the client got.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    time::Duration,
+};
+
+use bollard::Docker;
+
+use crate::{
+    models::ztn::{Dns, NetMap, PacketFilter, SelfNode},
+    poll::{poll_until, Backoff},
+    ztclient::ztclient_netmap,
+    Result,
+};
+
+/// A peer whose addresses or routes differ between the snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerChange {
+    pub peer: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl fmt::Display for PeerChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.peer)?;
+        for added in self.added.iter() {
+            write!(f, " +{added}")?;
+        }
+        for removed in self.removed.iter() {
+            write!(f, " -{removed}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The differences between two snapshots of the same client's netmap.  Peers are named by their
+/// `Name` and matched by `StableID`, so a renamed peer is a change, not a new peer.  Addresses are
+/// the `Addresses` of a node; routes are the rest of its `AllowedIPs` and its `PrimaryRoutes`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetMapDiff {
+    pub peers_added: Vec<String>,
+    pub peers_removed: Vec<String>,
+    /// Includes the client itself, under its own name.
+    pub address_changes: Vec<PeerChange>,
+    pub route_changes: Vec<PeerChange>,
+    pub packet_filter_added: Vec<PacketFilter>,
+    pub packet_filter_removed: Vec<PacketFilter>,
+    /// Before and after, if the DNS configuration changed.
+    pub dns: Option<(Dns, Dns)>,
+}
+
+impl NetMapDiff {
+    pub fn between(before: &NetMap, after: &NetMap) -> NetMapDiff {
+        let before_peers = nodes_by_id(before);
+        let after_peers = nodes_by_id(after);
+        let mut diff = NetMapDiff::default();
+
+        for (id, node) in after_peers.iter() {
+            match before_peers.get(id) {
+                None if *id != SELF => diff.peers_added.push(node.name.clone()),
+                None => {}
+                Some(old) => {
+                    if let Some(change) = changed(&node.name, addresses(old), addresses(node)) {
+                        diff.address_changes.push(change);
+                    }
+                    if let Some(change) = changed(&node.name, routes(old), routes(node)) {
+                        diff.route_changes.push(change);
+                    }
+                }
+            }
+        }
+        diff.peers_removed = before_peers
+            .iter()
+            .filter(|(id, _)| !after_peers.contains_key(*id))
+            .map(|(_, node)| node.name.clone())
+            .collect();
+
+        let before_filter = before.packet_filter.clone().unwrap_or_default();
+        let after_filter = after.packet_filter.clone().unwrap_or_default();
+        diff.packet_filter_added = after_filter
+            .iter()
+            .filter(|rule| !before_filter.contains(rule))
+            .cloned()
+            .collect();
+        diff.packet_filter_removed = before_filter
+            .iter()
+            .filter(|rule| !after_filter.contains(rule))
+            .cloned()
+            .collect();
+
+        if before.dns != after.dns {
+            diff.dns = Some((before.dns.clone(), after.dns.clone()));
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == NetMapDiff::default()
+    }
+}
+
+impl fmt::Display for NetMapDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no changes");
+        }
+        let mut parts = Vec::new();
+        if !self.peers_added.is_empty() {
+            parts.push(format!("peers added: {}", self.peers_added.join(", ")));
+        }
+        if !self.peers_removed.is_empty() {
+            parts.push(format!("peers removed: {}", self.peers_removed.join(", ")));
+        }
+        for change in self.address_changes.iter() {
+            parts.push(format!("addresses of {change}"));
+        }
+        for change in self.route_changes.iter() {
+            parts.push(format!("routes of {change}"));
+        }
+        for rule in self.packet_filter_added.iter() {
+            parts.push(format!("filter rule added: {}", describe_rule(rule)));
+        }
+        for rule in self.packet_filter_removed.iter() {
+            parts.push(format!("filter rule removed: {}", describe_rule(rule)));
+        }
+        if let Some((before, after)) = &self.dns {
+            parts.push(format!(
+                "DNS nameservers {:?} -> {:?}, domains {:?} -> {:?}",
+                before.nameservers, after.nameservers, before.domains, after.domains
+            ));
+        }
+        f.write_str(&parts.join("; "))
+    }
+}
+
+/// The key the client itself is stored under in [`nodes_by_id`].
+const SELF: &str = "";
+
+/// The client and its peers by `StableID`, or by name for nodes without one.
+fn nodes_by_id(netmap: &NetMap) -> BTreeMap<&str, &SelfNode> {
+    let mut nodes: BTreeMap<&str, &SelfNode> = netmap
+        .peers
+        .iter()
+        .flatten()
+        .map(|peer| {
+            let id = match peer.stable_id.is_empty() {
+                true => peer.name.as_str(),
+                false => peer.stable_id.as_str(),
+            };
+            (id, peer)
+        })
+        .collect();
+    nodes.insert(SELF, &netmap.self_node);
+    nodes
+}
+
+fn addresses(node: &SelfNode) -> BTreeSet<&str> {
+    node.addresses.iter().map(String::as_str).collect()
+}
+
+fn routes(node: &SelfNode) -> BTreeSet<&str> {
+    node.allowed_ips
+        .iter()
+        .chain(node.primary_routes.iter().flatten())
+        .map(String::as_str)
+        .filter(|route| !node.addresses.iter().any(|a| a == route))
+        .collect()
+}
+
+fn changed(peer: &str, before: BTreeSet<&str>, after: BTreeSet<&str>) -> Option<PeerChange> {
+    let change = PeerChange {
+        peer: peer.to_string(),
+        added: after.difference(&before).map(|s| s.to_string()).collect(),
+        removed: before.difference(&after).map(|s| s.to_string()).collect(),
+    };
+    (!change.added.is_empty() || !change.removed.is_empty()).then_some(change)
+}
+
+fn describe_rule(rule: &PacketFilter) -> String {
+    let destinations: Vec<String> = rule
+        .dsts
+        .iter()
+        .map(|d| format!("{}:{}-{}", d.net, d.ports.first, d.ports.last))
+        .collect();
+    format!(
+        "{} {} -> {}",
+        rule.ipproto,
+        rule.srcs.join(","),
+        destinations.join(",")
+    )
+}
+
+/// Polls the client's netmap until `predicate` holds and returns that netmap.  On timeout the
+/// error holds the last change seen between two polls, or says the netmap never changed.
+pub async fn wait_for_netmap<F>(
+    docker: &Docker,
+    container_name: &str,
+    predicate: F,
+    timeout: Duration,
+) -> Result<NetMap>
+where
+    F: Fn(&NetMap) -> bool,
+{
+    let mut previous: Option<NetMap> = None;
+    let mut last_change: Option<NetMapDiff> = None;
+    let result = poll_until(
+        timeout,
+        Backoff::default(),
+        || ztclient_netmap(docker, container_name),
+        |netmap| {
+            if let Some(previous) = &previous {
+                let diff = NetMapDiff::between(previous, netmap);
+                if !diff.is_empty() {
+                    last_change = Some(diff);
+                }
+            }
+            previous = Some(netmap.clone());
+            predicate(netmap)
+        },
+    )
+    .await;
+    result.map_err(|unfinished| {
+        let last_change = match last_change {
+            Some(diff) => format!("last change: {diff}"),
+            None => "the netmap did not change".to_string(),
+        };
+        unfinished.into_timeout(&format!(
+            "the netmap of {container_name} to match ({last_change})"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ztn::{Dst, Ports};
+
+    fn node(id: &str, name: &str, address: &str) -> SelfNode {
+        SelfNode {
+            stable_id: id.to_string(),
+            name: name.to_string(),
+            addresses: vec![address.to_string()],
+            allowed_ips: vec![address.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn rule(source: &str) -> PacketFilter {
+        PacketFilter {
+            ipproto: "[6]".to_string(),
+            srcs: vec![source.to_string()],
+            dsts: vec![Dst {
+                net: "100.64.0.1/32".to_string(),
+                ports: Ports {
+                    first: 80,
+                    last: 80,
+                },
+            }],
+            caps: vec![],
+        }
+    }
+
+    #[test]
+    fn identical_netmaps_have_no_diff() {
+        let netmap = NetMap {
+            self_node: node("s", "me", "100.64.0.1/32"),
+            peers: Some(vec![node("a", "a", "100.64.0.2/32")]),
+            ..Default::default()
+        };
+        let diff = NetMapDiff::between(&netmap, &netmap.clone());
+        assert!(diff.is_empty());
+        assert_eq!(diff.to_string(), "no changes");
+    }
+
+    #[test]
+    fn changes_are_listed() {
+        let before = NetMap {
+            self_node: node("s", "me", "100.64.0.1/32"),
+            peers: Some(vec![
+                node("a", "a", "100.64.0.2/32"),
+                node("b", "b", "100.64.0.3/32"),
+            ]),
+            packet_filter: Some(vec![rule("100.64.0.2/32")]),
+            ..Default::default()
+        };
+        let mut gateway = node("b", "b", "100.64.0.4/32");
+        gateway.allowed_ips.push("0.0.0.0/0".to_string());
+        let mut after = NetMap {
+            self_node: before.self_node.clone(),
+            peers: Some(vec![gateway, node("c", "c", "100.64.0.5/32")]),
+            packet_filter: Some(vec![rule("100.64.0.3/32")]),
+            ..Default::default()
+        };
+        after.dns.nameservers = vec!["100.100.100.100".to_string()];
+
+        let diff = NetMapDiff::between(&before, &after);
+        assert_eq!(diff.peers_added, vec!["c"]);
+        assert_eq!(diff.peers_removed, vec!["a"]);
+        assert_eq!(
+            diff.address_changes,
+            vec![PeerChange {
+                peer: "b".to_string(),
+                added: vec!["100.64.0.4/32".to_string()],
+                removed: vec!["100.64.0.3/32".to_string()],
+            }]
+        );
+        assert_eq!(diff.route_changes[0].added, vec!["0.0.0.0/0"]);
+        assert_eq!(diff.packet_filter_added, vec![rule("100.64.0.3/32")]);
+        assert_eq!(diff.packet_filter_removed, vec![rule("100.64.0.2/32")]);
+        assert!(diff.dns.is_some());
+
+        let text = diff.to_string();
+        assert!(text.contains("peers added: c; peers removed: a"), "{text}");
+        assert!(text.contains("routes of b: +0.0.0.0/0"), "{text}");
+        assert!(
+            text.contains("filter rule added: [6] 100.64.0.3/32 -> 100.64.0.1/32:80-80"),
+            "{text}"
+        );
+    }
+}
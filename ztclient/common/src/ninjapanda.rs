@@ -1,468 +1,505 @@
-use std::time::SystemTime;
+use std::time::Duration;
 
-use anyhow::Result;
+use bollard::Docker;
 
-use bollard::{
-    container::LogOutput,
-    exec::{CreateExecOptions, StartExecResults},
-    Docker,
-};
-
-use futures::StreamExt;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Method, RequestBuilder};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
+    exec::exec_in_container,
+    intgates::create_exit_route_request,
     models::{
-        Acl, AclPolicy, CreateAclPolicyRequest, CreateNamespaceRequest, CreatePreauthTokenRequest,
-        GetMachinesResponse, Group, Machine, PreauthTokenResponse, UpdateAclPolicyRequest,
+        routes::{CreateRouteRequest, CreateRouteResponse},
+        AclPolicy, CreateAclPolicyRequest, CreateNamespaceRequest, CreatePreauthTokenRequest,
+        ExecuteCallbackResponse, ExpirePreauthTokenRequest, GetMachinesResponse,
+        ListNamespacesResponse, ListPreauthTokensResponse, Machine, Namespace, NamespaceResponse,
+        PreauthToken, PreauthTokenResponse, RevokePreauthTokenRequest, UpdateAclPolicyRequest,
     },
-    random_container_name, RuntimeInformation,
+    policy::{PolicyBuilder, Protocol},
+    users, Error, RegisterCallbackRequest, Result, RuntimeInformation,
 };
 
+const ACL_POLICY_API: &str = "/api/v1/aclpolicy";
+const MACHINE_API: &str = "/api/v1/machine";
+const NAMESPACE_API: &str = "/api/v1/namespace";
 const PREAUTH_TOKEN_API: &str = "/api/v1/preauthkey";
 
-pub async fn start_ninjapanda(docker: &Docker, container_name: &str) -> Result<String> {
-    let apikeys_create = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                attach_stdout: Some(true),
-                cmd: Some(vec!["ninjapanda", "apikeys", "create"]),
-                ..Default::default()
-            },
-        )
-        .await?;
-    let output_result = docker.start_exec(apikeys_create.id.as_str(), None).await?;
-    let mut byteys = Vec::new();
-    let api_key = match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            while let Some(f) = dereffed.next().await {
-                if let Ok(LogOutput::StdOut { message }) = f {
-                    byteys.push(message);
-                }
-            }
-            let iter = byteys.iter().last();
-            std::str::from_utf8(iter.unwrap())
-                .unwrap()
-                .replace('\n', "")
-        }
-        StartExecResults::Detached => String::new(),
-    };
-    Ok(api_key)
+/// Everything but the unreserved characters of RFC 3986 is escaped in a path segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `segment` escaped so that it stays one segment of a URL path, whatever it contains.
+fn path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
 }
 
-/// Queries Ninja Panda to get all the machines that have a hostname that starts with one of the desired hostnames and return an array of machine IDs.
-pub async fn get_all_machine_ids(
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-    desired_hostnames: &[String],
-) -> Result<Vec<String>> {
-    let res = client
-        .get(format!(
-            "{}/api/v1/machine",
-            runtime_info.ninja_panda_api_url
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .send()
-        .await?;
-
-    let v: GetMachinesResponse = res.json().await?;
-    let v_str = serde_json::to_string_pretty(&v).unwrap();
-    println!("{}", v_str);
-    let machine_ids = v
-        .machines
-        .into_iter()
-        .filter(|m| {
-            let hostname = &m.hostname;
-            let count = desired_hostnames
-                .iter()
-                .filter(|x| hostname.starts_with(*x))
-                .count();
-            count > 0
-        })
-        .map(|m| format!("machine:{}", m.machine_id))
-        .collect();
-    dbg!("After filtering machine_ids", &machine_ids);
-    Ok(machine_ids)
+/// The machine key TTL of namespaces created with [`NinjaPandaClient::create_namespace`].
+pub const DEFAULT_MACHINE_KEY_TTL: Duration = Duration::from_secs(7_777_000_000);
+
+/// What [`NinjaPandaClient::create_namespace`] did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamespaceCreation {
+    Created(Namespace),
+    /// The namespace was there already and was left as it is, TTL included.
+    AlreadyExists,
 }
 
-/// Queries Ninja Panda to get all the machines that have a hostname that starts with one of the desired hostnames and return an array of machine IDs.
-pub async fn get_all_machines(
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-    desired_hostnames: Vec<String>,
-) -> Result<Vec<Machine>> {
-    let res = client
-        .get(format!(
-            "{}/api/v1/machine",
-            runtime_info.ninja_panda_api_url
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .send()
-        .await?;
-
-    let v: GetMachinesResponse = res.json().await?;
-    let final_machines = v
-        .machines
-        .into_iter()
-        .filter(|m| {
-            let hostname = &m.hostname;
-            let count = desired_hostnames
-                .iter()
-                .filter(|x| hostname.starts_with(*x))
-                .count();
-            count > 0
+pub async fn start_ninjapanda(docker: &Docker, container_name: &str) -> Result<String> {
+    let output = exec_in_container(
+        docker,
+        container_name,
+        &["ninjapanda", "apikeys", "create"],
+        Some(Duration::from_secs(30)),
+    )
+    .await?;
+    output
+        .stdout
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| Error::Exec {
+            container: container_name.to_string(),
+            command: "ninjapanda apikeys create".to_string(),
+            message: format!("no API key was printed: {}", output.stderr),
         })
-        .collect();
-    dbg!("After filtering machine_ids", &final_machines);
-    Ok(final_machines)
-}
-/// Create an ACL Policy that allows all machines to see each other
-pub async fn make_all_machines_peers(
-    runtime_info: &RuntimeInformation,
-    machine_ids: &[String],
-    client: &reqwest::Client,
-) -> Result<String> {
-    use uuid::Uuid;
-    let id = Uuid::new_v4();
-    let id_g1 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let id_g2 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let g1_name = format!("group:{}", id_g1);
-    let g2_name = format!("group:{}", id_g2);
-    let g1 = Group {
-        key: g1_name.to_string(),
-        values: machine_ids.to_vec(),
-    };
-    let g2 = Group {
-        key: g2_name.to_string(),
-        values: machine_ids.to_vec(),
-    };
-    let acl1 = Acl {
-        order: 0,
-        action: "accept".to_string(),
-        port: "*".to_string(),
-        protocol: "tcp".to_string(),
-        sources: vec![g2_name.to_string()],
-        destinations: vec![g1_name.to_string()],
-    };
-    let acl2 = Acl {
-        order: 1,
-        action: "accept".to_string(),
-        port: "*".to_string(),
-        protocol: "icmp".to_string(),
-        sources: vec![g2_name.to_string()],
-        destinations: vec![g1_name.to_string()],
-    };
-    let create_acl_policy = CreateAclPolicyRequest {
-        acl_policy: AclPolicy {
-            aclpolicy_id: id.to_string(),
-            order: "0".to_string(),
-            groups: vec![g1, g2],
-            acls: vec![acl1, acl2],
-        },
-    };
-    let cpr_json = serde_json::to_string_pretty(&create_acl_policy).unwrap();
-    println!("{}", cpr_json);
-
-    let res = client
-        .post(format!(
-            "{}/api/v1/aclpolicy",
-            runtime_info.ninja_panda_api_url
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .json(&create_acl_policy)
-        .send()
-        .await?;
-
-    let status = res.status();
-
-    assert!(
-        status.is_success(),
-        "Unable to create ACL Policies between all hosts"
-    );
-
-    res.text().await?;
-    Ok(id.to_string())
 }
 
-pub async fn make_all_machines_png(
-    runtime_info: &RuntimeInformation,
-    machine_ids: &[String],
-    client: &reqwest::Client,
-) -> Result<String> {
-    use uuid::Uuid;
-    let id = Uuid::new_v4();
-    let acl1 = Acl {
-        order: 0,
-        action: "accept".to_string(),
-        port: "*".to_string(),
-        protocol: "tcp".to_string(),
-        sources: machine_ids.to_vec(),
-        destinations: machine_ids.to_vec(),
-    };
-    let acl2 = Acl {
-        order: 2,
-        action: "accept".to_string(),
-        port: "*".to_string(),
-        protocol: "tcp".to_string(),
-        sources: machine_ids.to_vec(),
-        destinations: machine_ids.to_vec(),
-    };
-    let acl3: Acl = Acl {
-        order: 1,
-        action: "accept".to_string(),
-        port: "*".to_string(),
-        protocol: "tcp".to_string(),
-        sources: machine_ids.to_vec(),
-        destinations: machine_ids.to_vec(),
-    };
-    let create_acl_policy = CreateAclPolicyRequest {
-        acl_policy: AclPolicy {
-            aclpolicy_id: id.to_string(),
-            order: "0".to_string(),
-            groups: vec![],
-            acls: vec![acl1, acl2, acl3],
-        },
-    };
-    let _ignore = submit_policy_request(runtime_info, &create_acl_policy, &client).await;
-    Ok(id.to_string())
+/// Removes the `machine:` prefix that ACL groups use, if it is present.
+pub fn bare_machine_id(machine_id: &str) -> &str {
+    machine_id.strip_prefix("machine:").unwrap_or(machine_id)
 }
 
-pub async fn grant_one_directional_policy(
-    runtime_info: &RuntimeInformation,
-    machine_id1: &str,
-    machine_id2: &str,
-    port_number: u32,
-    client: &reqwest::Client,
-) -> Result<String> {
-    use uuid::Uuid;
-    let id = Uuid::new_v4();
-    let id_g1 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let id_g2 = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let g1_name = format!("group:{}", id_g1);
-    let g2_name = format!("group:{}", id_g2);
-    let g1 = Group {
-        key: g1_name.to_string(),
-        values: vec![format!("machine:{machine_id1}")],
-    };
-    let g2 = Group {
-        key: g2_name.to_string(),
-        values: vec![format!("machine:{machine_id2}")],
-    };
-    let acl1 = Acl {
-        order: 0,
-        action: "accept".to_string(),
-        port: port_number.to_string(),
-        protocol: "tcp".to_string(),
-        sources: vec![g1_name],
-        destinations: vec![g2_name],
-    };
-    let create_acl_policy = CreateAclPolicyRequest {
-        acl_policy: AclPolicy {
-            aclpolicy_id: id.to_string(),
-            order: "0".to_string(),
-            groups: vec![g1, g2],
-            acls: vec![acl1],
-        },
-    };
-
-    let _ignore = submit_policy_request(runtime_info, &create_acl_policy, &client).await;
-    Ok(id.to_string())
+/// Handle to the Ninja Panda REST API.  Owns the base URL, the API key and the HTTP client so
+/// that tests only need to pass this one value around.
+#[derive(Debug, Clone)]
+pub struct NinjaPandaClient {
+    base_url: String,
+    api_key: String,
+    http: reqwest::Client,
 }
 
-pub async fn submit_policy_request(
-    runtime_info: &RuntimeInformation,
-    policy: &CreateAclPolicyRequest,
-    client: &reqwest::Client,
-) -> Result<String> {
-    let policy_json = serde_json::to_string_pretty(&policy).expect("{}");
-    println!("{}", policy_json);
-
-    let res = client
-        .post(format!(
-            "{}/api/v1/aclpolicy",
-            runtime_info.ninja_panda_api_url
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .json(&policy)
-        .send()
-        .await?;
-
-    assert!(res.status().is_success(), "Could not create a policy");
-
-    let answ = res.text().await?;
-    dbg!(&answ);
-    Ok(String::new())
+impl From<&RuntimeInformation> for NinjaPandaClient {
+    fn from(runtime_info: &RuntimeInformation) -> Self {
+        NinjaPandaClient::new(
+            &runtime_info.ninja_panda_api_url,
+            &runtime_info.ninja_panda_api_key,
+        )
+    }
 }
 
-pub async fn delete_acl_policy(
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-    policy_id: &str,
-) -> Result<String> {
-    let res = client
-        .delete(format!(
-            "{}/api/v1/aclpolicy/{}",
-            runtime_info.ninja_panda_api_url, policy_id
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .send()
-        .await?;
-
-    assert!(res.status().is_success(), "Could not delete policy");
-    Ok(String::new())
-}
+impl NinjaPandaClient {
+    pub fn new(base_url: &str, api_key: &str) -> NinjaPandaClient {
+        NinjaPandaClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
 
-pub async fn zero_out_acl_policy(
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-    policy_id: &str,
-) -> Result<String> {
-    let blank_policy = UpdateAclPolicyRequest {
-        acl_policies: vec![AclPolicy {
-            aclpolicy_id: policy_id.to_string(),
-            order: "0".to_string(),
-            acls: vec![],
-            groups: vec![],
-        }],
-    };
-    let res = client
-        .put(format!(
-            "{}/api/v1/aclpolicy",
-            runtime_info.ninja_panda_api_url
-        ))
-        .json(&blank_policy)
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .send()
-        .await?;
-    let text = res.text().await.unwrap();
-    dbg!(text);
-    // assert!(res.status().is_success(), "Could not zero-out policy");
-
-    Ok(String::new())
-}
+    /// Replaces the HTTP client, e.g. to configure timeouts or proxies.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> NinjaPandaClient {
+        self.http = http;
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    fn request(&self, method: Method, path: &str) -> (RequestBuilder, String) {
+        let url = format!("{}{}", self.base_url, path);
+        let builder = self
+            .http
+            .request(method, url.as_str())
+            .bearer_auth(&self.api_key);
+        (builder, url)
+    }
 
-/// Creates the namespace in NinjaPanda.  If the namespace already exists, then this does nothing.
-pub async fn create_namespace(
-    namespace_name: &str,
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-) -> Result<()> {
-    {
-        let default_machine_key_ttl: String = "7777000000s".to_string();
-        let ninja_panda_api_url: &str = &runtime_info.ninja_panda_api_url;
-        let api_key: &str = &runtime_info.ninja_panda_api_key;
-        async move {
-            let create_namespace = CreateNamespaceRequest {
-                name: namespace_name.to_owned(),
-                default_machine_key_ttl,
-            };
-            let res = client
-                .post(format!("{ninja_panda_api_url}/api/v1/namespace",))
-                .bearer_auth(api_key)
-                .json(&create_namespace)
-                .send()
-                .await?;
-            let stri = res.text().await?;
-            dbg!(stri);
-            Ok(())
+    /// Sends the request and returns the body, failing on any non-success status.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&(impl Serialize + ?Sized)>,
+    ) -> Result<String> {
+        let (mut builder, url) = self.request(method.clone(), path);
+        if let Some(body) = body {
+            builder = builder.json(body);
         }
+        self.execute(method, url, builder).await
+    }
+
+    async fn execute(
+        &self,
+        method: Method,
+        url: String,
+        builder: RequestBuilder,
+    ) -> Result<String> {
+        let res = builder.send().await.map_err(|source| Error::Http {
+            method: method.clone(),
+            url: url.clone(),
+            source,
+        })?;
+        let status = res.status();
+        let text = res.text().await.map_err(|source| Error::Http {
+            method: method.clone(),
+            url: url.clone(),
+            source,
+        })?;
+        log::debug!("{method} {url} -> {status}: {text}");
+        if !status.is_success() {
+            return Err(Error::NinjaPanda {
+                method,
+                url,
+                status,
+                body: text,
+            });
+        }
+        Ok(text)
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&(impl Serialize + ?Sized)>,
+    ) -> Result<T> {
+        let text = self.send(method.clone(), path, body).await?;
+        self.decode(method, path, text)
+    }
+
+    /// GETs `path` with `query` encoded as its query string and decodes the response.
+    async fn get_json<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let (builder, url) = self.request(Method::GET, path);
+        let text = self.execute(Method::GET, url, builder.query(query)).await?;
+        self.decode(Method::GET, path, text)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, method: Method, path: &str, text: String) -> Result<T> {
+        serde_json::from_str(&text).map_err(|source| Error::Decode {
+            what: format!("response to {method} {}{}", self.base_url, path),
+            payload: text,
+            source,
+        })
+    }
+
+    /// Creates the namespace with [`DEFAULT_MACHINE_KEY_TTL`].  A namespace that already exists is
+    /// not an error.
+    pub async fn create_namespace(&self, namespace_name: &str) -> Result<NamespaceCreation> {
+        self.create_namespace_with_ttl(namespace_name, DEFAULT_MACHINE_KEY_TTL)
+            .await
+    }
+
+    /// Creates the namespace with machine keys that expire after `machine_key_ttl`, which
+    /// Ninja Panda takes in whole seconds.
+    pub async fn create_namespace_with_ttl(
+        &self,
+        namespace_name: &str,
+        machine_key_ttl: Duration,
+    ) -> Result<NamespaceCreation> {
+        let request = CreateNamespaceRequest {
+            name: namespace_name.to_owned(),
+            default_machine_key_ttl: format!("{}s", machine_key_ttl.as_secs()),
+        };
+        match self
+            .send_json(Method::POST, NAMESPACE_API, Some(&request))
+            .await
+        {
+            Ok(NamespaceResponse { namespace }) => Ok(NamespaceCreation::Created(namespace)),
+            Err(Error::NinjaPanda { body, .. })
+                if body.to_ascii_lowercase().contains("already exists") =>
+            {
+                Ok(NamespaceCreation::AlreadyExists)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn list_namespaces(&self) -> Result<Vec<Namespace>> {
+        let response: ListNamespacesResponse = self
+            .send_json(Method::GET, NAMESPACE_API, None::<&()>)
+            .await?;
+        Ok(response.namespaces)
+    }
+
+    pub async fn get_namespace(&self, namespace_name: &str) -> Result<Namespace> {
+        let path = format!("{NAMESPACE_API}/{}", path_segment(namespace_name));
+        let response: NamespaceResponse = self.send_json(Method::GET, &path, None::<&()>).await?;
+        Ok(response.namespace)
+    }
+
+    pub async fn rename_namespace(&self, old_name: &str, new_name: &str) -> Result<Namespace> {
+        let path = format!(
+            "{NAMESPACE_API}/{}/rename/{}",
+            path_segment(old_name),
+            path_segment(new_name)
+        );
+        let response: NamespaceResponse = self.send_json(Method::POST, &path, None::<&()>).await?;
+        Ok(response.namespace)
+    }
+
+    pub async fn delete_namespace(&self, namespace_name: &str) -> Result<()> {
+        let path = format!("{NAMESPACE_API}/{}", path_segment(namespace_name));
+        self.send(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// Returns every machine known to Ninja Panda.
+    pub async fn get_machines(&self) -> Result<Vec<Machine>> {
+        let response: GetMachinesResponse = self
+            .send_json(Method::GET, MACHINE_API, None::<&()>)
+            .await?;
+        Ok(response.machines)
+    }
+
+    /// Queries Ninja Panda to get all the machines that have a hostname that starts with one of
+    /// the desired hostnames.
+    pub async fn get_all_machines(&self, desired_hostnames: &[String]) -> Result<Vec<Machine>> {
+        let machines = self
+            .get_machines()
+            .await?
+            .into_iter()
+            .filter(|m| {
+                desired_hostnames
+                    .iter()
+                    .any(|x| m.hostname.starts_with(x.as_str()))
+            })
+            .collect();
+        Ok(machines)
+    }
+
+    /// Same as [`NinjaPandaClient::get_all_machines`], but returns the machine IDs in the
+    /// `machine:<id>` form that ACL policies expect.
+    pub async fn get_all_machine_ids(&self, desired_hostnames: &[String]) -> Result<Vec<String>> {
+        let machine_ids = self
+            .get_all_machines(desired_hostnames)
+            .await?
+            .into_iter()
+            .map(|m| format!("machine:{}", m.machine_id))
+            .collect();
+        Ok(machine_ids)
+    }
+
+    pub async fn delete_machine(&self, machine_id: &str) -> Result<()> {
+        let path = format!("{MACHINE_API}/{}", bare_machine_id(machine_id));
+        self.send(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// Completes an interactive registration by posting the user information for the
+    /// correlation ID printed by `ztclient connect`.  Returns the new machine ID.
+    pub async fn execute_callback(
+        &self,
+        correlation_id: &str,
+        namespace_name: &str,
+        user_info_id: usize,
+    ) -> Result<String> {
+        let request = RegisterCallbackRequest {
+            namespace: namespace_name.to_owned(),
+            user_info: users::get_user(user_info_id),
+        };
+        let path = format!("{MACHINE_API}/register/callback/{correlation_id}");
+        let response: ExecuteCallbackResponse =
+            self.send_json(Method::POST, &path, Some(&request)).await?;
+        Ok(response.machine.machine_id)
+    }
+
+    pub async fn create_acl_policy(&self, policy: &CreateAclPolicyRequest) -> Result<()> {
+        self.send(Method::POST, ACL_POLICY_API, Some(policy))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_acl_policies(&self, policies: &UpdateAclPolicyRequest) -> Result<()> {
+        self.send(Method::PUT, ACL_POLICY_API, Some(policies))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_acl_policy(&self, policy_id: &str) -> Result<()> {
+        let path = format!("{ACL_POLICY_API}/{policy_id}");
+        self.send(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// Replaces the policy with one that has no groups and no ACLs.
+    pub async fn zero_out_acl_policy(&self, policy_id: &str) -> Result<()> {
+        let blank_policy = UpdateAclPolicyRequest {
+            acl_policies: vec![AclPolicy {
+                aclpolicy_id: policy_id.to_string(),
+                order: "0".to_string(),
+                acls: vec![],
+                groups: vec![],
+            }],
+        };
+        self.update_acl_policies(&blank_policy).await
+    }
+
+    /// Create an ACL Policy that allows all machines to see each other
+    pub async fn make_all_machines_peers(&self, machine_ids: &[String]) -> Result<String> {
+        PolicyBuilder::new()
+            .machines("peers", machine_ids)
+            .allow("peers", "peers", Protocol::Tcp, ..)
+            .allow("peers", "peers", Protocol::Icmp, ..)
+            .create(self)
+            .await
+    }
+
+    /// Lets the machines reach each other over TCP.  Unlike
+    /// [`NinjaPandaClient::make_all_machines_peers`], there is no group: the rule lists the
+    /// machines itself.
+    pub async fn make_all_machines_png(&self, machine_ids: &[String]) -> Result<String> {
+        all_machines_png(machine_ids).create(self).await
+    }
+
+    /// Allows `machine_id1` to reach `machine_id2` on the given TCP port, but not the other way
+    /// around.  Returns the ID of the new policy.
+    pub async fn grant_one_directional_policy(
+        &self,
+        machine_id1: &str,
+        machine_id2: &str,
+        port_number: u32,
+    ) -> Result<String> {
+        let port = u16::try_from(port_number)
+            .map_err(|_| Error::Verification(format!("{port_number} is not a port")))?;
+        PolicyBuilder::new()
+            .machines("source", &[machine_id1.to_string()])
+            .machines("destination", &[machine_id2.to_string()])
+            .allow("source", "destination", Protocol::Tcp, port)
+            .create(self)
+            .await
     }
-    .await
-}
 
-pub async fn delete_machine(
-    machine_id: &str,
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-) -> Result<()> {
-    let fixed_machine_id = if machine_id.starts_with("machine:") {
-        machine_id.replace("machine:", "")
-    } else {
-        machine_id.to_string()
-    };
-    let url = format!(
-        "{}/api/v1/machine/{fixed_machine_id}",
-        runtime_info.ninja_panda_api_url
-    );
-    let _res = client
-        .delete(url)
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .send()
-        .await?;
-    Ok(())
+    pub async fn create_preauth_token(
+        &self,
+        request: &CreatePreauthTokenRequest,
+    ) -> Result<PreauthToken> {
+        let response: PreauthTokenResponse = self
+            .send_json(Method::POST, PREAUTH_TOKEN_API, Some(request))
+            .await?;
+        Ok(response.pre_auth_key)
+    }
+
+    /// Expires the key so that it can no longer be used to register machines.
+    pub async fn expire_preauth_token(&self, namespace_name: &str, key: &str) -> Result<()> {
+        let request = ExpirePreauthTokenRequest {
+            namespace: namespace_name.to_owned(),
+            key: key.to_owned(),
+        };
+        let path = format!("{PREAUTH_TOKEN_API}/expire");
+        self.send(Method::POST, &path, Some(&request)).await?;
+        Ok(())
+    }
+
+    /// Revokes the key.  Unlike an expired key, a revoked one is reported with `revoked_at` set.
+    pub async fn revoke_preauth_token(&self, namespace_name: &str, key: &str) -> Result<()> {
+        let request = RevokePreauthTokenRequest {
+            namespace: namespace_name.to_owned(),
+            key: key.to_owned(),
+        };
+        let path = format!("{PREAUTH_TOKEN_API}/revoke");
+        self.send(Method::POST, &path, Some(&request)).await?;
+        Ok(())
+    }
+
+    /// Returns every key of the namespace, including expired and revoked ones.
+    pub async fn list_preauth_tokens(&self, namespace_name: &str) -> Result<Vec<PreauthToken>> {
+        let response: ListPreauthTokensResponse = self
+            .get_json(PREAUTH_TOKEN_API, &[("namespace", namespace_name)])
+            .await?;
+        Ok(response.pre_auth_keys)
+    }
+
+    pub async fn get_preauth_token(&self, pre_auth_key_id: &str) -> Result<PreauthToken> {
+        let path = format!("{PREAUTH_TOKEN_API}/{pre_auth_key_id}");
+        let response: PreauthTokenResponse =
+            self.send_json(Method::GET, &path, None::<&()>).await?;
+        Ok(response.pre_auth_key)
+    }
+
+    /// How many more machines may register with `token`, as it was created.  Asks Ninja Panda for
+    /// the key's current count, and counts the machines registered with it when the count isn't
+    /// reported.  `None` if neither says.
+    pub async fn preauth_token_uses_remaining(&self, token: &PreauthToken) -> Result<Option<u64>> {
+        let current = self.get_preauth_token(&token.pre_auth_key_id).await?;
+        if let Some(remaining) = current.uses_remaining() {
+            return Ok(Some(remaining));
+        }
+        Ok(token.uses_remaining_after(&self.get_machines().await?))
+    }
+
+    pub async fn create_routes(
+        &self,
+        machine_id: &str,
+        request: &CreateRouteRequest,
+    ) -> Result<CreateRouteResponse> {
+        let path = format!("{MACHINE_API}/{}/routes", bare_machine_id(machine_id));
+        self.send_json(Method::POST, &path, Some(request)).await
+    }
+
+    /// Advertises and enables the IPv4 and IPv6 default routes on the machine.
+    pub async fn make_internet_gateway(&self, machine_id: &str) -> Result<CreateRouteResponse> {
+        self.create_routes(machine_id, &create_exit_route_request())
+            .await
+    }
 }
 
-pub async fn create_preauth_token(
-    client: &reqwest::Client,
-    runtime_information: &RuntimeInformation,
-    request: CreatePreauthTokenRequest,
-) -> Result<String> {
-    let url = format!(
-        "{}{}",
-        runtime_information.ninja_panda_api_url, PREAUTH_TOKEN_API
-    );
-
-    let api_token = client
-        .post(url)
-        .json(&request)
-        .bearer_auth(runtime_information.ninja_panda_api_key.as_str())
-        .send()
-        .await
-        .unwrap();
-    assert_eq!(200, api_token.status());
-
-    let api_token_response: PreauthTokenResponse = api_token.json().await.unwrap();
-    Ok(api_token_response.pre_auth_key.key)
+fn all_machines_png(machine_ids: &[String]) -> PolicyBuilder {
+    let machines: Vec<String> = machine_ids
+        .iter()
+        .map(|id| format!("machine:{}", bare_machine_id(id)))
+        .collect();
+    let machines: Vec<&str> = machines.iter().map(String::as_str).collect();
+    PolicyBuilder::new().allow_any(&machines, &machines, Protocol::Tcp, ..)
 }
 
-pub async fn make_internet_gateway(
-    runtime_info: &RuntimeInformation,
-    client: &reqwest::Client,
-    machine_id: &str,
-) -> Result<()> {
-    use crate::models::routes::{CreateRouteRequest, Route};
-
-    let route_request = CreateRouteRequest {
-        routes: vec![
-            Route {
-                prefix: "0.0.0.0/0".to_string(),
-                enabled: true,
-                advertised: true,
-                is_primary: true,
-                route_id: None,
-                machine_id: None,
-            },
-            Route {
-                prefix: "::/0".to_string(),
-                enabled: true,
-                advertised: true,
-                is_primary: true,
-                route_id: None,
-                machine_id: None,
-            },
-        ],
-    };
-    let _res = client
-        .post(format!(
-            "{}/api/v1/machine/{}/routes",
-            runtime_info.ninja_panda_api_url, machine_id,
-        ))
-        .bearer_auth(&runtime_info.ninja_panda_api_key)
-        .json(&route_request)
-        .send()
-        .await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_segments_are_escaped() {
+        assert_eq!(path_segment("optm-1_a.b~c"), "optm-1_a.b~c");
+        assert_eq!(path_segment("a/b?c#d e%"), "a%2Fb%3Fc%23d%20e%25");
+    }
+
+    #[test]
+    fn png_policy_has_no_groups() {
+        let policy = all_machines_png(&["machine:1".to_string(), "2".to_string()])
+            .build()
+            .unwrap();
+        assert!(policy.groups.is_empty());
+        assert_eq!(policy.acls.len(), 1);
+        let acl = &policy.acls[0];
+        assert_eq!((acl.protocol.as_str(), acl.port.as_str()), ("tcp", "*"));
+        assert_eq!(acl.sources, vec!["machine:1", "machine:2"]);
+        assert_eq!(acl.destinations, acl.sources);
+    }
+
+    #[test]
+    fn machine_prefix_is_stripped() {
+        assert_eq!("1234", bare_machine_id("machine:1234"));
+        assert_eq!("1234", bare_machine_id("1234"));
+    }
+
+    #[test]
+    fn client_from_runtime_info() {
+        let runtime_info = RuntimeInformation {
+            ninja_panda_api_key: "key".to_string(),
+            ninja_panda_api_url: "http://localhost:15000/".to_string(),
+            ..Default::default()
+        };
+        let client = NinjaPandaClient::from(&runtime_info);
+        assert_eq!("http://localhost:15000", client.base_url());
+        assert_eq!("key", client.api_key());
+    }
 }
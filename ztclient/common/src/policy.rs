@@ -0,0 +1,417 @@
+//! Building ACL policies without spelling out every [`Group`] and [`Acl`].
+//!
+//! ```no_run
+//! # async fn example(np: &ztclient_common::ninjapanda::NinjaPandaClient) -> ztclient_common::Result<()> {
+//! use ztclient_common::policy::{PolicyBuilder, Protocol};
+//!
+//! let clients = vec!["1234".to_string()];
+//! let servers = vec!["5678".to_string()];
+//! let policy = PolicyBuilder::new()
+//!     .machines("clients", &clients)
+//!     .machines("servers", &servers)
+//!     .allow("clients", "servers", Protocol::Tcp, 8000..=8080)
+//!     .allow("clients", "servers", Protocol::Icmp, ..);
+//! policy.create(np).await?;
+//!
+//! let policy = policy.without_rules().allow("clients", "servers", Protocol::Tcp, 443);
+//! policy.update(np).await?;
+//! policy.delete(np).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Groups are named in the builder by a label, and rendered with a key that is unique to the
+//! builder, so two policies built at the same moment never share a group.
+
+use std::{
+    fmt,
+    ops::{RangeFull, RangeInclusive},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    models::{Acl, AclPolicy, CreateAclPolicyRequest, Group, UpdateAclPolicyRequest},
+    ninjapanda::{bare_machine_id, NinjaPandaClient},
+    Error, Result,
+};
+
+/// Prefixes of the values that can be used in rules directly instead of a group label, besides
+/// `*` for every machine.
+const DIRECT_PREFIXES: [&str; 4] = ["group:", "machine:", "tag:", "user:"];
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 3] = [Protocol::Tcp, Protocol::Udp, Protocol::Icmp];
+}
+
+/// Accepts the names Ninja Panda uses and the IANA protocol numbers the client reports.
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(protocol: &str) -> Result<Protocol> {
+        match protocol.trim().to_ascii_lowercase().as_str() {
+            "tcp" | "6" => Ok(Protocol::Tcp),
+            "udp" | "17" => Ok(Protocol::Udp),
+            "icmp" | "1" => Ok(Protocol::Icmp),
+            _ => Err(Error::Verification(format!(
+                "{protocol:?} is not a known protocol"
+            ))),
+        }
+    }
+}
+
+/// The destination ports of a rule: `..` for any, a single port, or an inclusive range.  Parsed
+/// from `*`, `80` or `80-90`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Ports {
+    #[default]
+    Any,
+    Single(u16),
+    Range(u16, u16),
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ports::Any => f.write_str("*"),
+            Ports::Single(port) => write!(f, "{port}"),
+            Ports::Range(first, last) => write!(f, "{first}-{last}"),
+        }
+    }
+}
+
+impl Ports {
+    /// The first and last port, both included.
+    pub fn bounds(&self) -> (u16, u16) {
+        match *self {
+            Ports::Any => (0, u16::MAX),
+            Ports::Single(port) => (port, port),
+            Ports::Range(first, last) => (first, last),
+        }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        let (first, last) = self.bounds();
+        (first..=last).contains(&port)
+    }
+}
+
+impl FromStr for Ports {
+    type Err = Error;
+
+    fn from_str(ports: &str) -> Result<Ports> {
+        let port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| Error::Verification(format!("{ports:?} is not a port or port range")))
+        };
+        match ports.trim().split_once('-') {
+            _ if ports.trim() == "*" => Ok(Ports::Any),
+            Some((first, last)) => match (port(first)?, port(last)?) {
+                (first, last) if first > last => Err(Error::Verification(format!(
+                    "{ports:?} is a port range that ends before it starts"
+                ))),
+                (first, last) => Ok(Ports::Range(first, last)),
+            },
+            None => Ok(Ports::Single(port(ports)?)),
+        }
+    }
+}
+
+impl TryFrom<String> for Ports {
+    type Error = Error;
+
+    fn try_from(ports: String) -> Result<Ports> {
+        ports.parse()
+    }
+}
+
+impl From<u16> for Ports {
+    fn from(port: u16) -> Ports {
+        Ports::Single(port)
+    }
+}
+
+impl From<RangeInclusive<u16>> for Ports {
+    fn from(range: RangeInclusive<u16>) -> Ports {
+        Ports::Range(*range.start(), *range.end())
+    }
+}
+
+impl From<RangeFull> for Ports {
+    fn from(_: RangeFull) -> Ports {
+        Ports::Any
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    from: Vec<String>,
+    to: Vec<String>,
+    protocol: Protocol,
+    ports: Ports,
+}
+
+#[derive(Debug, Clone)]
+pub struct PolicyBuilder {
+    id: String,
+    order: u32,
+    /// (label, group) pairs, in the order they were added.
+    groups: Vec<(String, Group)>,
+    rules: Vec<Rule>,
+}
+
+impl Default for PolicyBuilder {
+    fn default() -> Self {
+        PolicyBuilder::new()
+    }
+}
+
+impl PolicyBuilder {
+    /// A policy with a new ID.
+    pub fn new() -> PolicyBuilder {
+        PolicyBuilder::with_id(&Uuid::new_v4().to_string())
+    }
+
+    /// A policy that replaces the existing one with this ID when updated.
+    pub fn with_id(policy_id: &str) -> PolicyBuilder {
+        PolicyBuilder {
+            id: policy_id.to_string(),
+            order: 0,
+            groups: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Where this policy goes among the others.  Rules within the policy are ordered as added.
+    pub fn order(mut self, order: u32) -> PolicyBuilder {
+        self.order = order;
+        self
+    }
+
+    /// A group of machines, given with or without the `machine:` prefix.
+    pub fn machines(self, label: &str, machine_ids: &[String]) -> PolicyBuilder {
+        let values = machine_ids
+            .iter()
+            .map(|id| format!("machine:{}", bare_machine_id(id)))
+            .collect();
+        self.group(label, values)
+    }
+
+    pub fn users(self, label: &str, users: &[&str]) -> PolicyBuilder {
+        let values = users.iter().map(|u| format!("user:{u}")).collect();
+        self.group(label, values)
+    }
+
+    pub fn tags(self, label: &str, tags: &[&str]) -> PolicyBuilder {
+        let values = tags.iter().map(|t| format!("tag:{t}")).collect();
+        self.group(label, values)
+    }
+
+    /// Adds to the group if the label is already in use.
+    fn group(mut self, label: &str, mut values: Vec<String>) -> PolicyBuilder {
+        match self.groups.iter_mut().find(|(l, _)| l == label) {
+            Some((_, group)) => group.values.append(&mut values),
+            None => {
+                let key = format!("group:{label}-{}", Uuid::new_v4().simple());
+                self.groups.push((label.to_string(), Group { key, values }));
+            }
+        }
+        self
+    }
+
+    /// Lets `from` reach `to`.  Both are group labels, or values such as `machine:<id>`,
+    /// `tag:<name>` or `*` used as they are.
+    pub fn allow(
+        self,
+        from: &str,
+        to: &str,
+        protocol: Protocol,
+        ports: impl Into<Ports>,
+    ) -> PolicyBuilder {
+        self.allow_any(&[from], &[to], protocol, ports)
+    }
+
+    /// Lets any of `from` reach any of `to` in a single rule, which lists them all instead of
+    /// referring to a group.  Takes the same labels and values as [`PolicyBuilder::allow`].
+    pub fn allow_any(
+        mut self,
+        from: &[&str],
+        to: &[&str],
+        protocol: Protocol,
+        ports: impl Into<Ports>,
+    ) -> PolicyBuilder {
+        let owned = |references: &[&str]| references.iter().map(|r| r.to_string()).collect();
+        self.rules.push(Rule {
+            from: owned(from),
+            to: owned(to),
+            protocol,
+            ports: ports.into(),
+        });
+        self
+    }
+
+    /// Keeps the groups but drops the rules, to build the next version of the policy.
+    pub fn without_rules(mut self) -> PolicyBuilder {
+        self.rules.clear();
+        self
+    }
+
+    fn resolve(&self, reference: &str) -> Result<String> {
+        if let Some((_, group)) = self.groups.iter().find(|(label, _)| label == reference) {
+            return Ok(group.key.clone());
+        }
+        if reference == "*" || DIRECT_PREFIXES.iter().any(|p| reference.starts_with(p)) {
+            return Ok(reference.to_string());
+        }
+        Err(Error::Verification(format!(
+            "policy {} refers to unknown group {reference}",
+            self.id
+        )))
+    }
+
+    fn resolve_all(&self, references: &[String]) -> Result<Vec<String>> {
+        references.iter().map(|r| self.resolve(r)).collect()
+    }
+
+    pub fn build(&self) -> Result<AclPolicy> {
+        let acls = (0..)
+            .zip(self.rules.iter())
+            .map(|(order, rule)| {
+                Ok(Acl {
+                    order,
+                    action: "accept".to_string(),
+                    port: rule.ports.to_string(),
+                    protocol: rule.protocol.to_string(),
+                    sources: self.resolve_all(&rule.from)?,
+                    destinations: self.resolve_all(&rule.to)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(AclPolicy {
+            aclpolicy_id: self.id.clone(),
+            order: self.order.to_string(),
+            groups: self.groups.iter().map(|(_, g)| g.clone()).collect(),
+            acls,
+        })
+    }
+
+    /// Creates the policy and returns its ID.
+    pub async fn create(&self, np: &NinjaPandaClient) -> Result<String> {
+        let request = CreateAclPolicyRequest {
+            acl_policy: self.build()?,
+        };
+        np.create_acl_policy(&request).await?;
+        Ok(self.id.clone())
+    }
+
+    /// Replaces the policy with this ID with what the builder holds now.
+    pub async fn update(&self, np: &NinjaPandaClient) -> Result<()> {
+        let request = UpdateAclPolicyRequest {
+            acl_policies: vec![self.build()?],
+        };
+        np.update_acl_policies(&request).await
+    }
+
+    pub async fn delete(&self, np: &NinjaPandaClient) -> Result<()> {
+        np.delete_acl_policy(&self.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_and_rules_render() {
+        let builder = PolicyBuilder::with_id("p1")
+            .order(3)
+            .machines("a", &["machine:1".to_string(), "2".to_string()])
+            .tags("b", &["web"])
+            .allow("a", "b", Protocol::Tcp, 80..=90)
+            .allow("b", "machine:2", Protocol::Icmp, ..)
+            .allow("a", "b", Protocol::Udp, 53);
+        let policy = builder.build().unwrap();
+
+        assert_eq!(policy.aclpolicy_id, "p1");
+        assert_eq!(policy.order, "3");
+        assert_eq!(policy.groups[0].values, vec!["machine:1", "machine:2"]);
+        assert_eq!(policy.groups[1].values, vec!["tag:web"]);
+        let (a, b) = (&policy.groups[0].key, &policy.groups[1].key);
+        let rendered: Vec<(i64, &str, &str, &str, &str)> = policy
+            .acls
+            .iter()
+            .map(|acl| {
+                (
+                    acl.order,
+                    acl.protocol.as_str(),
+                    acl.port.as_str(),
+                    acl.sources[0].as_str(),
+                    acl.destinations[0].as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![
+                (0, "tcp", "80-90", a.as_str(), b.as_str()),
+                (1, "icmp", "*", b.as_str(), "machine:2"),
+                (2, "udp", "53", a.as_str(), b.as_str()),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_keys_do_not_collide() {
+        let one = PolicyBuilder::new().machines("peers", &[]).build().unwrap();
+        let two = PolicyBuilder::new().machines("peers", &[]).build().unwrap();
+        assert_ne!(one.groups[0].key, two.groups[0].key);
+        assert!(one.groups[0].key.starts_with("group:peers-"));
+        assert_ne!(one.aclpolicy_id, two.aclpolicy_id);
+    }
+
+    #[test]
+    fn ports_parse() {
+        assert_eq!("*".parse::<Ports>().unwrap(), Ports::Any);
+        assert_eq!("443".parse::<Ports>().unwrap(), Ports::Single(443));
+        assert_eq!("80-90".parse::<Ports>().unwrap(), Ports::Range(80, 90));
+        assert!("http".parse::<Ports>().is_err());
+        assert!("90-80".parse::<Ports>().is_err());
+        assert_eq!("80-80".parse::<Ports>().unwrap(), Ports::Range(80, 80));
+    }
+
+    #[test]
+    fn unknown_labels_are_errors() {
+        let builder = PolicyBuilder::new().allow("nobody", "tag:web", Protocol::Tcp, 80);
+        assert!(builder.build().is_err());
+    }
+}
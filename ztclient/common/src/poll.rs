@@ -0,0 +1,187 @@
+//! Polling with a deadline and a growing interval, for waits on things the clients and Ninja
+//! Panda only get to eventually.
+
+use std::{future::Future, time::Duration};
+
+use tokio::time::{sleep, Instant};
+
+use crate::Error;
+
+/// How long to sleep between attempts: `initial`, then `multiplier` times longer each attempt, up
+/// to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::exponential(Duration::from_millis(250), Duration::from_secs(5))
+    }
+}
+
+impl Backoff {
+    pub fn exponential(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            multiplier: 2,
+        }
+    }
+
+    /// The same interval every time.
+    pub fn fixed(interval: Duration) -> Backoff {
+        Backoff {
+            initial: interval,
+            max: interval,
+            multiplier: 1,
+        }
+    }
+
+    /// The sleep after attempt `n`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.checked_pow(attempt).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Why [`poll_until`] gave up: what the last attempt returned and the last error, if any.
+#[derive(Debug)]
+pub struct Unfinished<T> {
+    pub last: Option<T>,
+    pub last_error: Option<Error>,
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+impl<T> Unfinished<T> {
+    /// An [`Error::Timeout`] waiting for `what`, mentioning the last error if there was one.
+    pub fn into_timeout(self, what: &str) -> Error {
+        let what = match &self.last_error {
+            Some(e) => format!("{what} (last error: {e})"),
+            None => what.to_string(),
+        };
+        Error::Timeout {
+            what,
+            elapsed: self.elapsed,
+        }
+    }
+}
+
+/// Calls `fetch` until `done` accepts what it returns, sleeping according to `backoff` in between,
+/// and gives up once `timeout` has passed, also when an attempt is still running then.  Errors
+/// from `fetch` are retried, since a client that is restarting can't answer for a while; the last
+/// one is kept for the caller.
+pub async fn poll_until<T, F, Fut, P>(
+    timeout: Duration,
+    backoff: Backoff,
+    mut fetch: F,
+    mut done: P,
+) -> std::result::Result<T, Unfinished<T>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = crate::Result<T>>,
+    P: FnMut(&T) -> bool,
+{
+    let start = Instant::now();
+    let mut unfinished = Unfinished {
+        last: None,
+        last_error: None,
+        attempts: 0,
+        elapsed: Duration::ZERO,
+    };
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        match tokio::time::timeout(remaining, fetch()).await {
+            Ok(Ok(value)) if done(&value) => return Ok(value),
+            Ok(Ok(value)) => unfinished.last = Some(value),
+            Ok(Err(e)) => {
+                log::debug!("Retrying after: {e}");
+                unfinished.last_error = Some(e);
+            }
+            Err(_) => {
+                unfinished.attempts += 1;
+                unfinished.elapsed = start.elapsed();
+                return Err(unfinished);
+            }
+        }
+        let delay = backoff.delay(unfinished.attempts);
+        unfinished.attempts += 1;
+        unfinished.elapsed = start.elapsed();
+        if unfinished.elapsed + delay > timeout {
+            return Err(unfinished);
+        }
+        sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let backoff = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<u128> = (0..6).map(|n| backoff.delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+        let fixed = Backoff::fixed(Duration::from_millis(500));
+        assert_eq!(fixed.delay(7), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn errors_are_retried_until_done() {
+        let mut calls = 0;
+        let result = poll_until(
+            Duration::from_secs(10),
+            Backoff::fixed(Duration::from_millis(10)),
+            || {
+                calls += 1;
+                let n = calls;
+                async move {
+                    match n {
+                        1 => Err(Error::Verification("not yet".to_string())),
+                        n => Ok(n),
+                    }
+                }
+            },
+            |n| *n >= 3,
+        )
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_with_the_last_value() {
+        let unfinished = poll_until(
+            Duration::from_millis(200),
+            Backoff::fixed(Duration::from_millis(50)),
+            || async { Ok("Starting") },
+            |state| *state == "Running",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(unfinished.last, Some("Starting"));
+        assert!(unfinished.last_error.is_none());
+        assert!(unfinished.attempts >= 3, "{}", unfinished.attempts);
+        assert!(unfinished.into_timeout("Running").is_timeout());
+    }
+
+    #[tokio::test]
+    async fn a_hanging_attempt_is_cut_off_at_the_deadline() {
+        let start = Instant::now();
+        let unfinished = poll_until(
+            Duration::from_millis(100),
+            Backoff::default(),
+            std::future::pending::<crate::Result<u32>>,
+            |_| true,
+        )
+        .await
+        .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(unfinished.attempts, 1);
+        assert!(unfinished.last.is_none());
+    }
+}
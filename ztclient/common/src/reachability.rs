@@ -0,0 +1,322 @@
+//! Which client can actually reach which, found by running `ztclient zt-con` and `ztclient
+//! zt-ping` from every client to every other one.  This is what confirms that an ACL policy is
+//! enforced, not just distributed; compare the matrix with [`crate::acl::AclEvaluation`] to check
+//! it against the policy.
+
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use bollard::Docker;
+use futures::{stream, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    acl::AclEvaluation,
+    handle::{PingOptions, ZtClientHandle},
+    policy::Protocol,
+    ztclient::{EXEC_TIMEOUT, ZT_CON_GREETING},
+    Error, Result,
+};
+
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One way of trying to reach a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Probe {
+    /// `ztclient zt-con <peer> <port>`, with `-u` for UDP.
+    Con { protocol: Protocol, port: u16 },
+    /// `ztclient zt-ping --c 1 --icmp <peer>`.
+    Ping,
+}
+
+impl Probe {
+    pub fn tcp(port: u16) -> Probe {
+        Probe::Con {
+            protocol: Protocol::Tcp,
+            port,
+        }
+    }
+
+    pub fn udp(port: u16) -> Probe {
+        Probe::Con {
+            protocol: Protocol::Udp,
+            port,
+        }
+    }
+
+    /// Whether the policy lets `from` do this to `to`.  Machine IDs as in
+    /// [`AclEvaluation::allows`]; a ping needs ICMP on any port.
+    pub fn expected(&self, evaluation: &AclEvaluation, from: &str, to: &str) -> bool {
+        match *self {
+            Probe::Con { protocol, port } => evaluation.allows(from, to, protocol, Some(port)),
+            Probe::Ping => evaluation.allows(from, to, Protocol::Icmp, None),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Con { protocol, port } => write!(f, "{protocol}/{port}"),
+            Probe::Ping => f.write_str("ping"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Outcome {
+    Allowed {
+        latency_ms: u64,
+    },
+    /// The probe finished without getting through; `output` is what it printed.
+    Denied {
+        latency_ms: u64,
+        output: String,
+    },
+    TimedOut,
+    /// The probe could not be run at all.
+    Failed {
+        error: String,
+    },
+}
+
+impl Outcome {
+    pub fn allowed(&self) -> bool {
+        matches!(self, Outcome::Allowed { .. })
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Allowed { latency_ms } => write!(f, "ok {latency_ms}ms"),
+            Outcome::Denied { .. } => f.write_str("denied"),
+            Outcome::TimedOut => f.write_str("timeout"),
+            Outcome::Failed { .. } => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cell {
+    pub from: String,
+    pub to: String,
+    pub probe: Probe,
+    pub outcome: Outcome,
+}
+
+/// The outcome of every probe from every client to every other client.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReachabilityMatrix {
+    pub clients: Vec<String>,
+    pub probes: Vec<Probe>,
+    /// Ordered by probe, then source, then destination.
+    pub cells: Vec<Cell>,
+}
+
+impl ReachabilityMatrix {
+    pub fn get(&self, from: &str, to: &str, probe: Probe) -> Option<&Outcome> {
+        self.cells
+            .iter()
+            .find(|c| c.from == from && c.to == to && c.probe == probe)
+            .map(|c| &c.outcome)
+    }
+
+    /// The cells whose outcome is not what `expected(from, to, probe)` says it should be.  Timing
+    /// out or failing counts as not getting through.
+    pub fn unexpected(&self, expected: impl Fn(&str, &str, Probe) -> bool) -> Vec<&Cell> {
+        self.cells
+            .iter()
+            .filter(|c| c.outcome.allowed() != expected(&c.from, &c.to, c.probe))
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// One table per probe, sources down the side and destinations across the top.
+impl fmt::Display for ReachabilityMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .clients
+            .iter()
+            .map(String::len)
+            .max()
+            .unwrap_or_default()
+            .max(10);
+        for probe in self.probes.iter() {
+            write!(f, "{:<width$}", probe.to_string())?;
+            for to in self.clients.iter() {
+                write!(f, " {to:>width$}")?;
+            }
+            writeln!(f)?;
+            for from in self.clients.iter() {
+                write!(f, "{from:<width$}")?;
+                for to in self.clients.iter() {
+                    let cell = match self.get(from, to, *probe) {
+                        Some(outcome) => outcome.to_string(),
+                        None => "-".to_string(),
+                    };
+                    write!(f, " {cell:>width$}")?;
+                }
+                writeln!(f)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every probe from every client to every other client, `parallelism` at a time.  A ping
+/// waits up to `timeout` for its pong.  A probe that hasn't finished within `timeout` plus the
+/// time a command in a client may take is recorded as [`Outcome::TimedOut`].  Peers are
+/// addressed by container name, which is also their hostname.
+pub async fn check_reachability(
+    docker: &Docker,
+    clients: &[String],
+    probes: &[Probe],
+    parallelism: usize,
+    timeout: Duration,
+) -> ReachabilityMatrix {
+    let work: Vec<(Probe, &String, &String)> = probes
+        .iter()
+        .flat_map(|probe| {
+            clients.iter().flat_map(move |from| {
+                clients
+                    .iter()
+                    .filter(move |to| *to != from)
+                    .map(move |to| (*probe, from, to))
+            })
+        })
+        .collect();
+    let cells = stream::iter(work)
+        .map(|(probe, from, to)| async move {
+            let outcome = run_probe(docker, from, to, probe, timeout).await;
+            log::debug!("{from} -> {to} {probe}: {outcome}");
+            Cell {
+                from: from.clone(),
+                to: to.clone(),
+                probe,
+                outcome,
+            }
+        })
+        .buffered(parallelism.max(1))
+        .collect()
+        .await;
+    ReachabilityMatrix {
+        clients: clients.to_vec(),
+        probes: probes.to_vec(),
+        cells,
+    }
+}
+
+async fn run_probe(
+    docker: &Docker,
+    from: &str,
+    to: &str,
+    probe: Probe,
+    timeout: Duration,
+) -> Outcome {
+    let client = ZtClientHandle::new(docker, from);
+    let start = Instant::now();
+    let attempt = async {
+        match probe {
+            Probe::Con { protocol, port } => {
+                let printed = client.zt_con(to, port, protocol == Protocol::Udp).await?;
+                let reached = printed.trim() == ZT_CON_GREETING;
+                Ok((reached, None, printed))
+            }
+            Probe::Ping => {
+                // The policy grants pings as ICMP, so that is what has to get through.
+                let options = PingOptions {
+                    icmp: true,
+                    timeout: Some(timeout),
+                    ..PingOptions::once()
+                };
+                let ping = client.zt_ping(to, &options).await?;
+                let latency = ping.latencies().first().copied();
+                Ok((
+                    ping.success && latency.is_some(),
+                    latency,
+                    ping.other.join("\n"),
+                ))
+            }
+        }
+    };
+    // Leave the command time to report a ping that got no pong, so that it counts as denied.
+    let (reached, latency, printed) =
+        match tokio::time::timeout(timeout + EXEC_TIMEOUT, attempt).await {
+            Ok(Ok(result)) => result,
+            Err(_) | Ok(Err(Error::Timeout { .. })) => return Outcome::TimedOut,
+            Ok(Err(e)) => {
+                return Outcome::Failed {
+                    error: e.to_string(),
+                }
+            }
+        };
+    let elapsed = start.elapsed().as_millis() as u64;
+    if !reached {
+        return Outcome::Denied {
+            latency_ms: elapsed,
+            output: printed.trim().to_string(),
+        };
+    }
+    Outcome::Allowed {
+        latency_ms: latency.map_or(elapsed, |l| l.as_millis() as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> ReachabilityMatrix {
+        let cell = |from: &str, to: &str, outcome| Cell {
+            from: from.to_string(),
+            to: to.to_string(),
+            probe: Probe::tcp(80),
+            outcome,
+        };
+        ReachabilityMatrix {
+            clients: vec!["a".to_string(), "b".to_string()],
+            probes: vec![Probe::tcp(80)],
+            cells: vec![
+                cell("a", "b", Outcome::Allowed { latency_ms: 12 }),
+                cell("b", "a", Outcome::TimedOut),
+            ],
+        }
+    }
+
+    #[test]
+    fn unexpected_cells() {
+        let matrix = matrix();
+        assert!(matrix.unexpected(|from, _, _| from == "a").is_empty());
+        let unexpected = matrix.unexpected(|_, _, _| true);
+        assert_eq!(unexpected.len(), 1);
+        assert_eq!(unexpected[0].from, "b");
+    }
+
+    #[test]
+    fn display_is_a_table_per_probe() {
+        let table = matrix().to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("tcp/80"), "{table}");
+        assert!(
+            lines[1].starts_with('a') && lines[1].ends_with("ok 12ms"),
+            "{table}"
+        );
+        assert!(
+            lines[2].contains("timeout") && lines[2].ends_with('-'),
+            "{table}"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Waiting for the environment to be usable: Docker health for the service containers, and an
+//! authenticated request through nginx for the Ninja Panda API.
+
+use std::time::Duration;
+
+use bollard::{
+    service::{ContainerStateStatusEnum, HealthStatusEnum},
+    Docker,
+};
+use tokio::time::{sleep, timeout, Instant};
+
+use crate::{ninjapanda::NinjaPandaClient, Error, Result};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Waits for the container's healthcheck to pass, or for it to be running if it doesn't have a
+/// healthcheck. Fails straight away if the container exits or is reported unhealthy, and with
+/// [`Error::Timeout`] naming the container and its last state if `deadline` passes first.
+pub async fn wait_for_container(
+    docker: &Docker,
+    container_name: &str,
+    deadline: Instant,
+) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let last_state = match docker.inspect_container(container_name, None).await {
+            Ok(inspect) => {
+                let state = inspect.state.unwrap_or_default();
+                let health = state.health.and_then(|h| h.status);
+                match (state.status, health) {
+                    (
+                        Some(ContainerStateStatusEnum::RUNNING),
+                        None | Some(HealthStatusEnum::NONE) | Some(HealthStatusEnum::HEALTHY),
+                    ) => break,
+                    (_, Some(HealthStatusEnum::UNHEALTHY)) => {
+                        return Err(Error::ServiceUnhealthy {
+                            container: container_name.to_string(),
+                            state: "unhealthy".to_string(),
+                        })
+                    }
+                    (
+                        Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD),
+                        _,
+                    ) => {
+                        return Err(Error::ServiceUnhealthy {
+                            container: container_name.to_string(),
+                            state: format!("exited with {:?}", state.exit_code),
+                        })
+                    }
+                    (status, health) => format!("{status:?}/{health:?}"),
+                }
+            }
+            Err(e) => e.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(Error::Timeout {
+                what: format!("{container_name} to become healthy (last seen: {last_state})"),
+                elapsed: start.elapsed(),
+            });
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    log::info!("{container_name} ready after {:?}", start.elapsed());
+    Ok(())
+}
+
+/// Waits until Ninja Panda answers an authenticated request made with `np`'s URL and API key.
+pub async fn wait_for_ninja_panda_api(np: &NinjaPandaClient, deadline: Instant) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        // A request that hangs must not carry the wait past the deadline.
+        let attempt = timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            np.get_machines(),
+        );
+        let last_error = match attempt.await {
+            Ok(Ok(_)) => break,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "no response before the deadline".to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(Error::Timeout {
+                what: format!(
+                    "the Ninja Panda API at {} (last error: {last_error})",
+                    np.base_url()
+                ),
+                elapsed: start.elapsed(),
+            });
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    log::info!("Ninja Panda API ready after {:?}", start.elapsed());
+    Ok(())
+}
@@ -0,0 +1,354 @@
+//! JUnit XML and JSON summaries of what a run checked, for CI to show which check failed on which
+//! container.  [`Errors`] checks from the integration tests end up here through
+//! [`write_test_report`]; the tester subcommands build a [`TestReport`] from their own results.
+
+use std::{fmt::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    errors::Errors,
+    launch::LaunchReport,
+    loadtest::LoadTestReport,
+    reachability::{Outcome, ReachabilityMatrix},
+    scenario::ScenarioReport,
+    Result,
+};
+
+/// When set, [`Errors::assert_pop`] writes a JUnit report of the test's checks into this
+/// directory, one file per test.
+pub const REPORT_DIR_VAR: &str = "ZTCLIENT_REPORT_DIR";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Failure {
+    pub message: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TestCase {
+    pub name: String,
+    /// The container the case is about, if any.  JUnit readers group cases by this.
+    pub classname: String,
+    pub failure: Option<Failure>,
+}
+
+impl TestCase {
+    pub fn passed(name: &str, classname: &str) -> TestCase {
+        TestCase {
+            name: name.to_string(),
+            classname: classname.to_string(),
+            failure: None,
+        }
+    }
+
+    pub fn failed(name: &str, classname: &str, message: String) -> TestCase {
+        TestCase {
+            failure: Some(Failure {
+                message,
+                ..Default::default()
+            }),
+            ..TestCase::passed(name, classname)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TestSuite {
+    pub name: String,
+    pub cases: Vec<TestCase>,
+}
+
+impl TestSuite {
+    pub fn new(name: &str) -> TestSuite {
+        TestSuite {
+            name: name.to_string(),
+            cases: Vec::new(),
+        }
+    }
+
+    pub fn failures(&self) -> usize {
+        self.cases.iter().filter(|c| c.failure.is_some()).count()
+    }
+
+    /// A suite with a single case for a command that either worked or didn't.
+    pub fn from_result<T, E: std::fmt::Display>(
+        name: &str,
+        result: &std::result::Result<T, E>,
+    ) -> TestSuite {
+        let case = match result {
+            Ok(_) => TestCase::passed(name, name),
+            Err(e) => TestCase::failed(name, name, e.to_string()),
+        };
+        TestSuite {
+            name: name.to_string(),
+            cases: vec![case],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TestReport {
+    pub suites: Vec<TestSuite>,
+}
+
+impl TestReport {
+    pub fn push(&mut self, suite: TestSuite) {
+        self.suites.push(suite);
+    }
+
+    pub fn to_junit_xml(&self) -> String {
+        let cases: usize = self.suites.iter().map(|s| s.cases.len()).sum();
+        let failures: usize = self.suites.iter().map(TestSuite::failures).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites tests=\"{cases}\" failures=\"{failures}\">"
+        );
+        for suite in self.suites.iter() {
+            let _ = writeln!(
+                xml,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">",
+                escape(&suite.name),
+                suite.cases.len(),
+                suite.failures()
+            );
+            for case in suite.cases.iter() {
+                let _ = write!(
+                    xml,
+                    "    <testcase name=\"{}\" classname=\"{}\"",
+                    escape(&case.name),
+                    escape(&case.classname)
+                );
+                let Some(failure) = &case.failure else {
+                    xml.push_str("/>\n");
+                    continue;
+                };
+                let mut details = String::new();
+                if let Some(expected) = &failure.expected {
+                    let _ = writeln!(details, "expected: {expected}");
+                }
+                if let Some(actual) = &failure.actual {
+                    let _ = writeln!(details, "actual: {actual}");
+                }
+                if let Some(location) = &failure.location {
+                    let _ = writeln!(details, "at {location}");
+                }
+                let _ = writeln!(
+                    xml,
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                    escape(&failure.message),
+                    escape(&details)
+                );
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+
+    /// Writes the report as JSON if `path` ends in `.json`, as JUnit XML otherwise.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self).map_err(std::io::Error::from)?,
+            _ => self.to_junit_xml(),
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// Control characters other than tab, newline and carriage return can't appear in XML 1.0 even
+/// escaped, so they are replaced.  Client output has them, e.g. in ANSI colour codes.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Errors {
+    /// Every check as a case, named after the check and classed by its container.
+    pub fn to_suite(&self, name: &str) -> TestSuite {
+        let cases = self
+            .checks
+            .iter()
+            .map(|check| TestCase {
+                name: check.name.clone(),
+                classname: check.container.clone().unwrap_or_else(|| name.to_string()),
+                failure: (!check.passed).then(|| Failure {
+                    message: check.to_string(),
+                    expected: Some(check.expected.clone()),
+                    actual: Some(check.actual.clone()),
+                    location: Some(check.location.clone()),
+                }),
+            })
+            .collect();
+        TestSuite {
+            name: name.to_string(),
+            cases,
+        }
+    }
+}
+
+/// Writes the checks to [`REPORT_DIR_VAR`], if it is set, in a file named after the test, which
+/// is what `cargo test` names the thread running it.  Failing to write only logs a warning so it
+/// can't hide the test result.
+pub fn write_test_report(errors: &Errors) {
+    let Ok(dir) = std::env::var(REPORT_DIR_VAR) else {
+        return;
+    };
+    let thread = std::thread::current();
+    let test_name = thread.name().unwrap_or("unnamed");
+    let report = TestReport {
+        suites: vec![errors.to_suite(test_name)],
+    };
+    let path = Path::new(&dir).join(format!("{}.xml", test_name.replace("::", ".")));
+    if let Err(e) = std::fs::create_dir_all(&dir)
+        .map_err(Into::into)
+        .and_then(|_| report.save(&path))
+    {
+        log::warn!("Unable to write {}: {e}", path.display());
+    }
+}
+
+impl From<&ScenarioReport> for TestSuite {
+    fn from(report: &ScenarioReport) -> TestSuite {
+        let cases = report
+            .results
+            .iter()
+            .map(|result| {
+                let name = result.check.to_string();
+                match &result.error {
+                    None => TestCase::passed(&name, &report.name),
+                    Some(e) => TestCase::failed(&name, &report.name, e.clone()),
+                }
+            })
+            .collect();
+        TestSuite {
+            name: report.name.clone(),
+            cases,
+        }
+    }
+}
+
+impl From<&LaunchReport> for TestSuite {
+    fn from(report: &LaunchReport) -> TestSuite {
+        let cases = report
+            .outcomes
+            .iter()
+            .map(|outcome| match &outcome.result {
+                Ok(_) => TestCase::passed("launch", &outcome.container_name),
+                Err(e) => TestCase::failed("launch", &outcome.container_name, e.to_string()),
+            })
+            .collect();
+        TestSuite {
+            name: "launch".to_string(),
+            cases,
+        }
+    }
+}
+
+impl From<&LoadTestReport> for TestSuite {
+    fn from(report: &LoadTestReport) -> TestSuite {
+        let cases = report
+            .samples
+            .iter()
+            .map(|sample| match &sample.error {
+                None => TestCase::passed("load-test", &sample.container_name),
+                Some(e) => TestCase::failed("load-test", &sample.container_name, e.clone()),
+            })
+            .collect();
+        TestSuite {
+            name: "load-test".to_string(),
+            cases,
+        }
+    }
+}
+
+/// A case per probe, classed by the client it ran on.  Being denied is a result like any other;
+/// only probes that could not be run fail.
+impl From<&ReachabilityMatrix> for TestSuite {
+    fn from(matrix: &ReachabilityMatrix) -> TestSuite {
+        let cases = matrix
+            .cells
+            .iter()
+            .map(|cell| {
+                let name = format!("{} to {}: {}", cell.probe, cell.to, cell.outcome);
+                match &cell.outcome {
+                    Outcome::Failed { error } => TestCase::failed(&name, &cell.from, error.clone()),
+                    _ => TestCase::passed(&name, &cell.from),
+                }
+            })
+            .collect();
+        TestSuite {
+            name: "reachability".to_string(),
+            cases,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_replaced() {
+        assert_eq!(
+            escape("\u{1b}[31mno <peers>\u{1b}[0m\t\"a&b\"\r\n\0"),
+            "\u{fffd}[31mno &lt;peers&gt;\u{fffd}[0m\t&quot;a&amp;b&quot;\r\n\u{fffd}"
+        );
+    }
+
+    #[test]
+    fn junit_lists_failures_with_details() {
+        let mut errors = Errors::new();
+        errors.in_container("zt<1>");
+        errors.eq("peer count", 1, 1);
+        errors.eq("hostname", "a", "b");
+        let report = TestReport {
+            suites: vec![errors.to_suite("policies::test01")],
+        };
+
+        let xml = report.to_junit_xml();
+        assert!(
+            xml.contains("<testsuites tests=\"2\" failures=\"1\">"),
+            "{xml}"
+        );
+        assert!(
+            xml.contains("<testcase name=\"peer count\" classname=\"zt&lt;1&gt;\"/>"),
+            "{xml}"
+        );
+        assert!(
+            xml.contains("expected: &quot;a&quot;\nactual: &quot;b&quot;"),
+            "{xml}"
+        );
+        assert!(xml.contains(&format!("at {}:", file!())), "{xml}");
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["suites"][0]["cases"][1]["failure"]["actual"], "\"b\"");
+    }
+
+    #[test]
+    fn command_results_become_single_cases() {
+        let ok: std::result::Result<(), String> = Ok(());
+        assert_eq!(TestSuite::from_result("env-status", &ok).failures(), 0);
+        let failed: std::result::Result<(), String> = Err("no runtime file".to_string());
+        let suite = TestSuite::from_result("env-status", &failed);
+        assert_eq!(
+            suite.cases[0].failure.as_ref().unwrap().message,
+            "no runtime file"
+        );
+    }
+}
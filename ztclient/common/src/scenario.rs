@@ -0,0 +1,530 @@
+//! Declarative test topologies.  A scenario file, in YAML or TOML, declares namespaces, clients,
+//! ACL rules and the checks that should hold once they are set up:
+//!
+//! ```yaml
+//! name: one-way-port-80
+//! namespaces: [web]
+//! clients:
+//!   - { name: a, namespace: web, user: 1 }
+//!   - { name: b, namespace: web, user: 2, registration: preauth }
+//! policies:
+//!   - acls:
+//!       - { from: [a], to: [b], port: "80" }
+//! checks:
+//!   - peers: { client: a, expect: [b] }
+//!   - packet_filter: { client: b, present: true }
+//!   - reachable: { from: a, to: b, port: 80 }
+//!   - unreachable: { from: b, to: a, port: 80 }
+//! ```
+//!
+//! Names in the file are suffixed with a per-run ID, so the same scenario can run more than once
+//! and alongside other tests.  Everything is created through a [`TestContext`] and removed again
+//! when it is dropped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    context::TestContext,
+    models::{status::BackendState, CreatePreauthTokenRequest},
+    policy::{PolicyBuilder, Ports, Protocol},
+    random_container_name,
+    ztclient::{
+        preauth_token_registration_as, wait_for_state_change, zt_con,
+        ztclient_alternate_hostname_registration, ztclient_netmap, NGINX_NP_URL, ZT_CON_GREETING,
+    },
+    Error, Result,
+};
+
+const CHECK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn default_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    pub clients: Vec<ClientSpec>,
+    #[serde(default)]
+    pub policies: Vec<PolicySpec>,
+    /// Written as `- peers: {...}` rather than with YAML tags.
+    #[serde(
+        default,
+        deserialize_with = "serde_yaml::with::singleton_map_recursive::deserialize"
+    )]
+    pub checks: Vec<Check>,
+    /// Seconds each check may take to start passing.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Registration {
+    /// `ztclient connect` completed through the register callback, as the client's user.
+    #[default]
+    Callback,
+    /// `ztclient connect --auth-token` with a preauth key created for the client's namespace.
+    Preauth,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSpec {
+    pub name: String,
+    pub namespace: String,
+    /// Index into [`crate::users::get_user`].  Not used with preauth registration.
+    #[serde(default)]
+    pub user: usize,
+    /// Defaults to the client's name.
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub registration: Registration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicySpec {
+    pub acls: Vec<AclSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclSpec {
+    /// Client names.
+    pub from: Vec<String>,
+    /// Client names.
+    pub to: Vec<String>,
+    /// `*`, `80` or `80-90`; any port by default.
+    #[serde(default)]
+    pub port: Ports,
+    /// `tcp` by default.
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// The client's netmap lists exactly these clients as peers.
+    Peers { client: String, expect: Vec<String> },
+    /// The client's netmap does, or doesn't, have a packet filter.
+    PacketFilter { client: String, present: bool },
+    /// `zt-con` from one client to the other gets an answer.
+    Reachable { from: String, to: String, port: u32 },
+    /// `zt-con` from one client to the other gets no answer.
+    Unreachable { from: String, to: String, port: u32 },
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Peers { client, expect } => write!(f, "{client} has peers {expect:?}"),
+            Check::PacketFilter { client, present } => {
+                let has = if *present { "has" } else { "has no" };
+                write!(f, "{client} {has} packet filter")
+            }
+            Check::Reachable { from, to, port } => write!(f, "{from} reaches {to}:{port}"),
+            Check::Unreachable { from, to, port } => {
+                write!(f, "{from} does not reach {to}:{port}")
+            }
+        }
+    }
+}
+
+impl Scenario {
+    /// Reads a scenario, as TOML if the file ends in `.toml` and as YAML otherwise, and validates
+    /// it.
+    pub fn load(path: &Path) -> Result<Scenario> {
+        let contents = std::fs::read_to_string(path)?;
+        let scenario = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Scenario::from_toml(&contents),
+            _ => Scenario::from_yaml(&contents),
+        }
+        .map_err(|e| match e {
+            Error::Scenario(message) => Error::Scenario(format!("{}: {message}", path.display())),
+            e => e,
+        })?;
+        Ok(scenario)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Scenario> {
+        let scenario: Scenario =
+            serde_yaml::from_str(contents).map_err(|e| Error::Scenario(e.to_string()))?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Scenario> {
+        let scenario: Scenario =
+            toml::from_str(contents).map_err(|e| Error::Scenario(e.to_string()))?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    /// Checks that every name the scenario refers to is declared, and declared once.
+    pub fn validate(&self) -> Result<()> {
+        let fail = |message: String| Err(Error::Scenario(format!("{}: {message}", self.name)));
+        let valid_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        };
+
+        let mut namespaces = HashSet::new();
+        for namespace in self.namespaces.iter() {
+            if !valid_name(namespace) || !namespaces.insert(namespace.as_str()) {
+                return fail(format!(
+                    "namespace {namespace:?} is invalid or declared twice"
+                ));
+            }
+        }
+        let mut clients = HashSet::new();
+        for client in self.clients.iter() {
+            if !valid_name(&client.name) || !clients.insert(client.name.as_str()) {
+                return fail(format!(
+                    "client {:?} is invalid or declared twice",
+                    client.name
+                ));
+            }
+            if let Some(hostname) = client.hostname.as_deref().filter(|h| !valid_name(h)) {
+                return fail(format!("hostname {hostname:?} is invalid"));
+            }
+            if !namespaces.contains(client.namespace.as_str()) {
+                return fail(format!(
+                    "client {} is in undeclared namespace {}",
+                    client.name, client.namespace
+                ));
+            }
+        }
+
+        let mut referenced: Vec<&str> = Vec::new();
+        for acl in self.policies.iter().flat_map(|p| p.acls.iter()) {
+            referenced.extend(acl.from.iter().chain(acl.to.iter()).map(String::as_str));
+        }
+        for check in self.checks.iter() {
+            match check {
+                Check::Peers { client, expect } => {
+                    referenced.push(client);
+                    referenced.extend(expect.iter().map(String::as_str));
+                }
+                Check::PacketFilter { client, .. } => referenced.push(client),
+                Check::Reachable { from, to, .. } | Check::Unreachable { from, to, .. } => {
+                    referenced.extend([from.as_str(), to.as_str()])
+                }
+            }
+        }
+        if let Some(unknown) = referenced.iter().find(|name| !clients.contains(*name)) {
+            return fail(format!("undeclared client {unknown}"));
+        }
+        Ok(())
+    }
+}
+
+/// How one check went.
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub check: Check,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub results: Vec<CheckResult>,
+}
+
+impl ScenarioReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|r| r.error.is_none())
+    }
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Scenario {}", self.name)?;
+        for result in self.results.iter() {
+            match &result.error {
+                None => writeln!(f, "  ok     {}", result.check)?,
+                Some(e) => writeln!(f, "  FAILED {}: {e}", result.check)?,
+            }
+        }
+        let failed = self.results.iter().filter(|r| r.error.is_some()).count();
+        write!(f, "{} checks, {failed} failed", self.results.len())
+    }
+}
+
+/// The names a scenario's clients got in this run.
+struct RunningClient {
+    container_name: String,
+    hostname: String,
+    machine_id: String,
+}
+
+/// Sets up the scenario with `ctx` and runs its checks.  Setup failures are errors; check
+/// failures end up in the report.
+pub async fn run_scenario(ctx: &TestContext, scenario: &Scenario) -> Result<ScenarioReport> {
+    let run_id = random_container_name();
+    let scoped = |name: &str| format!("{name}-{run_id}");
+
+    for namespace in scenario.namespaces.iter() {
+        ctx.create_namespace(&scoped(namespace)).await?;
+    }
+
+    let mut clients = HashMap::new();
+    for spec in scenario.clients.iter() {
+        let client = start_client(ctx, spec, &scoped).await?;
+        clients.insert(spec.name.clone(), client);
+    }
+    for client in clients.values() {
+        wait_for_state_change(&ctx.docker, &client.container_name, BackendState::Running).await?;
+    }
+
+    for (index, policy) in scenario.policies.iter().enumerate() {
+        let mut builder = PolicyBuilder::new().order(index as u32);
+        for (n, acl) in policy.acls.iter().enumerate() {
+            let machines = |names: &[String]| -> Vec<String> {
+                names
+                    .iter()
+                    .map(|n| clients[n].machine_id.clone())
+                    .collect()
+            };
+            let (from, to) = (format!("acl{n}-from"), format!("acl{n}-to"));
+            builder = builder
+                .machines(&from, &machines(&acl.from))
+                .machines(&to, &machines(&acl.to))
+                .allow(&from, &to, acl.protocol, acl.port);
+        }
+        let policy_id = builder.create(&ctx.np).await?;
+        ctx.track_acl_policy(&policy_id);
+    }
+
+    let timeout = Duration::from_secs(scenario.timeout);
+    let mut results = Vec::new();
+    for check in scenario.checks.iter() {
+        log::info!("Checking that {check}");
+        let deadline = Instant::now() + timeout;
+        let error = loop {
+            match run_check(ctx, &clients, check).await {
+                Ok(()) => break None,
+                Err(e) if Instant::now() >= deadline => break Some(e.to_string()),
+                Err(e) => log::debug!("{check} not yet: {e}"),
+            }
+            sleep(CHECK_POLL_INTERVAL).await;
+        };
+        results.push(CheckResult {
+            check: check.clone(),
+            error,
+        });
+    }
+    Ok(ScenarioReport {
+        name: scenario.name.clone(),
+        results,
+    })
+}
+
+async fn start_client(
+    ctx: &TestContext,
+    spec: &ClientSpec,
+    scoped: &impl Fn(&str) -> String,
+) -> Result<RunningClient> {
+    let container_name = scoped(&spec.name);
+    let hostname = scoped(spec.hostname.as_deref().unwrap_or(&spec.name));
+    let namespace_name = scoped(&spec.namespace);
+    ctx.start_client(&container_name).await?;
+    let machine_id = match spec.registration {
+        Registration::Callback => {
+            let correlation_id =
+                ztclient_alternate_hostname_registration(&ctx.docker, &container_name, &hostname)
+                    .await?;
+            ctx.np
+                .execute_callback(&correlation_id, &namespace_name, spec.user)
+                .await?
+        }
+        Registration::Preauth => {
+            let token = ctx
+                .create_preauth_token(&CreatePreauthTokenRequest {
+                    namespace: namespace_name.clone(),
+                    ..Default::default()
+                })
+                .await?;
+            let stderr = preauth_token_registration_as(
+                &ctx.docker,
+                &container_name,
+                &token.key,
+                NGINX_NP_URL,
+                &hostname,
+            )
+            .await?;
+            if !stderr.trim().is_empty() {
+                return Err(Error::Verification(format!(
+                    "{container_name} did not register with a preauth key: {stderr}"
+                )));
+            }
+            ctx.np
+                .get_machines()
+                .await?
+                .into_iter()
+                .find(|m| m.hostname == hostname && m.namespace.name == namespace_name)
+                .map(|m| m.machine_id)
+                .ok_or_else(|| Error::Verification(format!("no machine for {hostname}")))?
+        }
+    };
+    ctx.track_machine(&machine_id);
+    Ok(RunningClient {
+        container_name,
+        hostname,
+        machine_id,
+    })
+}
+
+async fn run_check(
+    ctx: &TestContext,
+    clients: &HashMap<String, RunningClient>,
+    check: &Check,
+) -> Result<()> {
+    // Names were validated against the declared clients.
+    let client = |name: &str| &clients[name];
+    match check {
+        Check::Peers {
+            client: name,
+            expect,
+        } => {
+            let netmap = ztclient_netmap(&ctx.docker, &client(name).container_name).await?;
+            let mut actual: Vec<String> = netmap
+                .peers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|peer| peer.name.split('.').next().unwrap_or_default().to_string())
+                .collect();
+            let mut expected: Vec<String> =
+                expect.iter().map(|n| client(n).hostname.clone()).collect();
+            actual.sort();
+            expected.sort();
+            if actual != expected {
+                return Err(Error::Verification(format!(
+                    "peers are {actual:?}, expected {expected:?}"
+                )));
+            }
+        }
+        Check::PacketFilter {
+            client: name,
+            present,
+        } => {
+            let netmap = ztclient_netmap(&ctx.docker, &client(name).container_name).await?;
+            if netmap.packet_filter.is_some() != *present {
+                return Err(Error::Verification(format!(
+                    "packet filter is {:?}",
+                    netmap.packet_filter
+                )));
+            }
+        }
+        Check::Reachable { from, to, port } | Check::Unreachable { from, to, port } => {
+            let response = zt_con(
+                &ctx.docker,
+                &client(from).container_name,
+                &client(to).hostname,
+                *port,
+            )
+            .await?;
+            let reached = response.trim() == ZT_CON_GREETING;
+            if reached != matches!(check, Check::Reachable { .. }) {
+                return Err(Error::Verification(format!("zt-con answered {response:?}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+name: one-way-port-80
+namespaces: [web]
+clients:
+  - { name: a, namespace: web, user: 1 }
+  - { name: b, namespace: web, registration: preauth, hostname: server }
+policies:
+  - acls:
+      - { from: [a], to: [b], port: "80" }
+checks:
+  - peers: { client: a, expect: [b] }
+  - packet_filter: { client: b, present: true }
+  - unreachable: { from: b, to: a, port: 80 }
+"#;
+
+    const TOML: &str = r#"
+name = "one-way-port-80"
+namespaces = ["web"]
+timeout = 30
+
+[[clients]]
+name = "a"
+namespace = "web"
+user = 1
+
+[[clients]]
+name = "b"
+namespace = "web"
+registration = "preauth"
+
+[[policies]]
+acls = [{ from = ["a"], to = ["b"], port = "80" }]
+
+[[checks]]
+reachable = { from = "a", to = "b", port = 80 }
+"#;
+
+    #[test]
+    fn yaml_and_toml_scenarios() {
+        let scenario = Scenario::from_yaml(YAML).unwrap();
+        assert_eq!(scenario.clients[1].registration, Registration::Preauth);
+        assert_eq!(scenario.clients[1].hostname.as_deref(), Some("server"));
+        assert_eq!(scenario.policies[0].acls[0].protocol, Protocol::Tcp);
+        assert_eq!(scenario.policies[0].acls[0].port, Ports::Single(80));
+        assert_eq!(scenario.timeout, 60);
+        assert_eq!(
+            scenario.checks[2],
+            Check::Unreachable {
+                from: "b".to_string(),
+                to: "a".to_string(),
+                port: 80
+            }
+        );
+
+        let scenario = Scenario::from_toml(TOML).unwrap();
+        assert_eq!(scenario.timeout, 30);
+        assert_eq!(scenario.clients[0].registration, Registration::Callback);
+        assert_eq!(scenario.checks[0].to_string(), "a reaches b:80");
+    }
+
+    #[test]
+    fn shipped_scenarios_are_valid() {
+        Scenario::from_yaml(include_str!("../../tester/scenarios/one-way-port-80.yaml")).unwrap();
+        Scenario::from_toml(include_str!("../../tester/scenarios/preauth-mesh.toml")).unwrap();
+    }
+
+    #[test]
+    fn undeclared_names_are_rejected() {
+        let e = Scenario::from_yaml(&YAML.replace("expect: [b]", "expect: [c]")).unwrap_err();
+        assert!(e.to_string().contains("undeclared client c"), "{e}");
+        let e = Scenario::from_yaml(&YAML.replace("namespace: web, user", "namespace: db, user"))
+            .unwrap_err();
+        assert!(e.to_string().contains("undeclared namespace db"), "{e}");
+        assert!(Scenario::from_yaml(&YAML.replace("name: a,", "name: A,")).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! Churn against a pool of peered clients.  Each step picks a client and an action at random from
+//! a seeded generator, performs it and checks that Ninja Panda and the clients still agree with
+//! the policy.  The same seed and pool size replay the same steps.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use bollard::Docker;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    handle::ZtClientHandle,
+    launch::launch_clients,
+    models::status::BackendState,
+    ninjapanda::{bare_machine_id, NinjaPandaClient},
+    ztclient::{
+        create_and_register_client, numbered_client_names, user_for_client, wait_for_peer_count,
+        wait_for_state_change, ztclient_alternate_hostname_registration,
+    },
+    Config, Error, Result,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoakAction {
+    /// `ztclient disconnect` followed by `ztclient connect`.
+    Reconnect,
+    /// `ztclient logout` followed by an interactive registration as the same user.
+    Relogin,
+    /// `ztclient configure --hostname` to a new name, then reconnect.
+    Rename,
+    /// Restarts the client's container.
+    Restart,
+}
+
+impl SoakAction {
+    pub const ALL: [SoakAction; 4] = [
+        SoakAction::Reconnect,
+        SoakAction::Relogin,
+        SoakAction::Rename,
+        SoakAction::Restart,
+    ];
+}
+
+impl fmt::Display for SoakAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SoakAction::Reconnect => "reconnect",
+            SoakAction::Relogin => "relogin",
+            SoakAction::Rename => "rename",
+            SoakAction::Restart => "restart",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The sequence of (client index, action) steps for a seed.
+pub struct SoakPlan {
+    rng: StdRng,
+    pool_size: usize,
+}
+
+impl SoakPlan {
+    pub fn new(seed: u64, pool_size: usize) -> SoakPlan {
+        SoakPlan {
+            rng: StdRng::seed_from_u64(seed),
+            pool_size,
+        }
+    }
+}
+
+impl Iterator for SoakPlan {
+    type Item = (usize, SoakAction);
+
+    fn next(&mut self) -> Option<(usize, SoakAction)> {
+        let client = self.rng.gen_range(0..self.pool_size);
+        let action = SoakAction::ALL[self.rng.gen_range(0..SoakAction::ALL.len())];
+        Some((client, action))
+    }
+}
+
+/// A seed for runs that weren't given one.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+#[derive(Debug, Clone)]
+pub struct SoakSettings {
+    pub seed: u64,
+    pub pool_size: usize,
+    /// Stop after this many steps, or run until `duration` is up.
+    pub max_steps: Option<usize>,
+    pub duration: Option<Duration>,
+    pub hostname_prefix: String,
+    /// Should hold nothing but the soak clients, since every machine in it is checked.
+    pub namespace_name: String,
+    /// How long clients may take to get back to Running and to the expected peers after a step.
+    pub settle_timeout: Duration,
+    pub parallelism: usize,
+}
+
+impl SoakSettings {
+    /// The invariants are about clients seeing each other, so the pool needs at least two.
+    pub fn validate(&self) -> Result<()> {
+        if self.pool_size < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "the soak pool needs at least 2 clients, not {}",
+                self.pool_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SoakClient {
+    container_name: String,
+    hostname: String,
+    /// Bare, without the `machine:` prefix.
+    machine_id: String,
+    user_info_id: usize,
+}
+
+/// What a soak run left behind, so that it can be cleaned up.
+#[derive(Debug, Default)]
+pub struct SoakOutcome {
+    pub steps: usize,
+    pub container_names: Vec<String>,
+    pub machine_ids: Vec<String>,
+    pub policy_id: Option<String>,
+}
+
+/// Brings up the pool, peers it, and runs steps until the limit is reached.  Stops with an
+/// [`Error::Verification`] naming the step and the seed on the first broken invariant; the clients
+/// are left in place so they can be inspected.
+pub async fn run_soak(
+    docker: &Docker,
+    np: &NinjaPandaClient,
+    config: &Config,
+    settings: &SoakSettings,
+) -> Result<SoakOutcome> {
+    settings.validate()?;
+    log::info!(
+        "Soaking {} clients with seed {}",
+        settings.pool_size,
+        settings.seed
+    );
+    np.create_namespace(&settings.namespace_name).await?;
+    let container_names = numbered_client_names(&settings.hostname_prefix, settings.pool_size, 0);
+    let namespace_name = settings.namespace_name.as_str();
+    let machine_ids = launch_clients(
+        &container_names,
+        settings.parallelism,
+        |index, name| async move {
+            let user_info_id = user_for_client(index);
+            create_and_register_client(np, docker, config, &name, namespace_name, user_info_id)
+                .await
+        },
+    )
+    .await
+    .into_result()?;
+    let mut clients: Vec<SoakClient> = container_names
+        .iter()
+        .zip(machine_ids)
+        .enumerate()
+        .map(|(index, (name, machine_id))| SoakClient {
+            container_name: name.clone(),
+            hostname: name.clone(),
+            machine_id,
+            user_info_id: user_for_client(index),
+        })
+        .collect();
+    let mut outcome = SoakOutcome {
+        container_names: container_names.clone(),
+        ..Default::default()
+    };
+    outcome.policy_id = Some(peer_all(np, &clients).await?);
+
+    let violation = |step: usize, what: String| {
+        Error::Verification(format!(
+            "step {step}: {what}; rerun with --seed {} --pool-size {}",
+            settings.seed, settings.pool_size
+        ))
+    };
+    check_invariants(docker, np, settings, &clients)
+        .await
+        .map_err(|e| violation(0, format!("before any churn: {e}")))?;
+
+    let start = Instant::now();
+    for (step, (index, action)) in (1..).zip(SoakPlan::new(settings.seed, settings.pool_size)) {
+        if settings.max_steps.is_some_and(|max| step > max)
+            || settings.duration.is_some_and(|d| start.elapsed() >= d)
+        {
+            break;
+        }
+        let client = &mut clients[index];
+        let what = format!("{action} on {}", client.container_name);
+        log::info!("Step {step}: {what}");
+        let old_machine_id = client.machine_id.clone();
+        perform(docker, np, settings, client, action, step)
+            .await
+            .map_err(|e| violation(step, format!("{what} failed: {e}")))?;
+        if clients[index].machine_id != old_machine_id {
+            // The policy names machines, so a new registration has to be put in it.
+            if let Some(policy_id) = outcome.policy_id.take() {
+                np.delete_acl_policy(&policy_id).await?;
+            }
+            outcome.policy_id = Some(peer_all(np, &clients).await?);
+        }
+        check_invariants(docker, np, settings, &clients)
+            .await
+            .map_err(|e| violation(step, format!("after {what}: {e}")))?;
+        outcome.steps = step;
+    }
+    outcome.machine_ids = clients.into_iter().map(|c| c.machine_id).collect();
+    Ok(outcome)
+}
+
+async fn peer_all(np: &NinjaPandaClient, clients: &[SoakClient]) -> Result<String> {
+    let machine_ids: Vec<String> = clients
+        .iter()
+        .map(|c| format!("machine:{}", c.machine_id))
+        .collect();
+    np.make_all_machines_peers(&machine_ids).await
+}
+
+async fn perform(
+    docker: &Docker,
+    np: &NinjaPandaClient,
+    settings: &SoakSettings,
+    client: &mut SoakClient,
+    action: SoakAction,
+    step: usize,
+) -> Result<()> {
+    let name = client.container_name.as_str();
+    let handle = ZtClientHandle::new(docker, name);
+    match action {
+        SoakAction::Reconnect => handle.reconnect().await?,
+        SoakAction::Relogin => {
+            handle.logout().await?;
+            let correlation_id =
+                ztclient_alternate_hostname_registration(docker, name, &client.hostname).await?;
+            let machine_id = np
+                .execute_callback(
+                    &correlation_id,
+                    &settings.namespace_name,
+                    client.user_info_id,
+                )
+                .await?;
+            client.machine_id = bare_machine_id(&machine_id).to_string();
+        }
+        SoakAction::Rename => {
+            let hostname = format!("{name}-r{step}");
+            handle.rename(&hostname).await?;
+            client.hostname = hostname;
+        }
+        SoakAction::Restart => docker.restart_container(name, None).await?,
+    }
+    Ok(())
+}
+
+/// Every client is Running and sees the other clients as peers, and Ninja Panda has exactly one
+/// machine per client in the namespace.
+async fn check_invariants(
+    docker: &Docker,
+    np: &NinjaPandaClient,
+    settings: &SoakSettings,
+    clients: &[SoakClient],
+) -> Result<()> {
+    for client in clients.iter() {
+        wait_for_state_change(docker, &client.container_name, BackendState::Running).await?;
+    }
+
+    let machine_ids: Vec<String> = np
+        .get_machines()
+        .await?
+        .into_iter()
+        .filter(|m| m.namespace.name == settings.namespace_name)
+        .map(|m| m.machine_id)
+        .collect();
+    let expected: Vec<&str> = clients.iter().map(|c| c.machine_id.as_str()).collect();
+    if let Some(problem) = machine_problem(&expected, &machine_ids) {
+        return Err(Error::Verification(problem));
+    }
+
+    let expected_peers = clients.len() - 1;
+    let deadline = Instant::now() + settings.settle_timeout;
+    for client in clients.iter() {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        wait_for_peer_count(docker, &client.container_name, timeout, |peers| {
+            peers == expected_peers
+        })
+        .await
+        .map_err(|e| {
+            Error::Verification(format!(
+                "{} does not have {expected_peers} peers: {e}",
+                client.container_name
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Compares the machines Ninja Panda has with the ones the clients registered as.
+fn machine_problem(expected: &[&str], actual: &[String]) -> Option<String> {
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = actual.iter().find(|id| !seen.insert(id.as_str())) {
+        return Some(format!("machine {duplicate} is listed more than once"));
+    }
+    if let Some(missing) = expected.iter().find(|id| !seen.contains(*id)) {
+        return Some(format!("machine {missing} is missing"));
+    }
+    if actual.len() != expected.len() {
+        let extra: Vec<&String> = actual
+            .iter()
+            .filter(|id| !expected.contains(&id.as_str()))
+            .collect();
+        return Some(format!("unexpected machines {extra:?}"));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_steps() {
+        let steps: Vec<_> = SoakPlan::new(42, 5).take(50).collect();
+        assert_eq!(steps, SoakPlan::new(42, 5).take(50).collect::<Vec<_>>());
+        assert_ne!(steps, SoakPlan::new(43, 5).take(50).collect::<Vec<_>>());
+        assert!(steps.iter().all(|(client, _)| *client < 5));
+    }
+
+    #[test]
+    fn pool_needs_two_clients() {
+        let settings = |pool_size| SoakSettings {
+            seed: 1,
+            pool_size,
+            max_steps: Some(1),
+            duration: None,
+            hostname_prefix: "soak".to_string(),
+            namespace_name: "soak".to_string(),
+            settle_timeout: Duration::from_secs(1),
+            parallelism: 1,
+        };
+        assert!(matches!(
+            settings(0).validate(),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            settings(1).validate(),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(settings(2).validate().is_ok());
+    }
+
+    #[test]
+    fn machine_problems() {
+        let actual = |ids: &[&str]| ids.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(machine_problem(&["1", "2"], &actual(&["2", "1"])), None);
+        assert!(machine_problem(&["1", "2"], &actual(&["1", "2", "2"]))
+            .unwrap()
+            .contains("more than once"));
+        assert!(machine_problem(&["1", "2"], &actual(&["1"]))
+            .unwrap()
+            .contains("2 is missing"));
+        assert!(machine_problem(&["1"], &actual(&["1", "3"]))
+            .unwrap()
+            .contains("\"3\""));
+    }
+}
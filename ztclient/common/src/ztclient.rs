@@ -1,36 +1,41 @@
-use anyhow::{bail, Context, Result};
+use std::time::Duration;
 
 use bollard::{
-    container::{AttachContainerOptions, CreateContainerOptions, LogOutput, StartContainerOptions},
-    exec::{CreateExecOptions, StartExecResults},
+    container::{AttachContainerOptions, CreateContainerOptions, StartContainerOptions},
     network::ConnectNetworkOptions,
     secret::HostConfig,
     service::ContainerCreateResponse,
     Docker,
 };
 
-use futures::StreamExt;
-use reqwest::Client;
 use serde_json::from_str;
-use tokio::time::{self, sleep};
 
 use crate::{
-    execute_callback, get_labels,
-    models::{status::StatusResult, ztcon::ConResult, ztn::NetMap},
-    ninjapanda::create_namespace,
+    exec::{exec_in_container, exec_until, ExecOutput},
+    get_labels,
+    launch::launch_clients,
+    models::{
+        status::{BackendState, StatusResult},
+        ztcon::ConResult,
+        ztn::NetMap,
+    },
+    ninjapanda::NinjaPandaClient,
+    poll::{poll_until, Backoff},
     users::get_user,
-    Config, ExecuteCallbackRequest, RuntimeInformation,
+    Config, Error, Result,
 };
 
 use crate::containers::remove_container;
 
 const OLD_MODE: &str = "USE_OLD_MODE";
 pub const NGINX_NP_URL: &str = "http://ztclient_nginx:80";
+pub(crate) const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+/// What a client's listener on port 80 answers to `zt-con`.
+pub const ZT_CON_GREETING: &str = "HELLO";
 
-pub mod states {
-    pub const RUNNING_STATE: &str = "Running";
-    pub const NEEDS_LOGIN_STATE: &str = "NeedsLogin";
-}
+/// How long [`wait_for_state_change`] waits.
+pub const STATE_TIMEOUT: Duration = Duration::from_secs(120);
 
 pub async fn start_ztclientd(
     docker: &Docker,
@@ -103,7 +108,7 @@ pub async fn start_ztclientd(
     Ok(container)
 }
 
-fn get_connect_actions() -> (&'static str, &'static str) {
+pub(crate) fn get_connect_actions() -> (&'static str, &'static str) {
     let old_value = std::env::var(OLD_MODE).unwrap_or("".to_string());
     // ("login-server", "up");
     // for 0.5.0 it's url, connect
@@ -115,91 +120,29 @@ fn get_connect_actions() -> (&'static str, &'static str) {
 pub async fn ztclient_registration(docker: &Docker, container_name: &str) -> Result<String> {
     let (url_arg_name, connect_arg_name) = get_connect_actions();
     let server_url = format!("--{url_arg_name}={NGINX_NP_URL}");
-
     let client_hostname = format!("--hostname={}", container_name);
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec![
-                    "ztclient",
-                    connect_arg_name,
-                    server_url.as_str(),
-                    client_hostname.as_str(),
-                ]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let auth_url = if let Some(auth_url) = vect.last() {
-        let line = std::str::from_utf8(auth_url).unwrap();
-        let index = line.find("state=").expect("state= must be there");
-        &line[index + 6..line.len()]
-    } else {
-        ""
-    };
-    Ok(String::from(auth_url))
+    connect_for_correlation_id(
+        docker,
+        container_name,
+        &[
+            "ztclient",
+            connect_arg_name,
+            server_url.as_str(),
+            client_hostname.as_str(),
+        ],
+    )
+    .await
 }
 
 pub async fn ztclient_registration_nh(docker: &Docker, container_name: &str) -> Result<String> {
     let (url_arg_name, connect_arg_name) = get_connect_actions();
     let server_url = format!("--{url_arg_name}={NGINX_NP_URL}");
-
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec!["ztclient", connect_arg_name, server_url.as_str()]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let auth_url = if let Some(auth_url) = vect.last() {
-        let line = std::str::from_utf8(auth_url).unwrap();
-        let index = line.find("state=").expect("state= must be there");
-        &line[index + 6..line.len()]
-    } else {
-        ""
-    };
-    Ok(String::from(auth_url))
+    connect_for_correlation_id(
+        docker,
+        container_name,
+        &["ztclient", connect_arg_name, server_url.as_str()],
+    )
+    .await
 }
 
 pub async fn ztclient_register_forcereauth(
@@ -208,50 +151,19 @@ pub async fn ztclient_register_forcereauth(
 ) -> Result<String> {
     let (url_arg_name, connect_arg_name) = get_connect_actions();
     let server_url = format!("--{url_arg_name}={NGINX_NP_URL}");
-
     let client_hostname = format!("--hostname={}", container_name);
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec![
-                    "ztclient",
-                    connect_arg_name,
-                    server_url.as_str(),
-                    client_hostname.as_str(),
-                    "--force-reauth",
-                ]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let auth_url = if let Some(auth_url) = vect.last() {
-        let line = std::str::from_utf8(auth_url).unwrap();
-        let index = line.find("state=").expect("state= must be there");
-        &line[index + 6..line.len()]
-    } else {
-        ""
-    };
-    Ok(String::from(auth_url))
+    connect_for_correlation_id(
+        docker,
+        container_name,
+        &[
+            "ztclient",
+            connect_arg_name,
+            server_url.as_str(),
+            client_hostname.as_str(),
+            "--force-reauth",
+        ],
+    )
+    .await
 }
 
 pub async fn ztclient_alternate_hostname_registration(
@@ -261,106 +173,75 @@ pub async fn ztclient_alternate_hostname_registration(
 ) -> Result<String> {
     let (url_arg_name, connect_arg_name) = get_connect_actions();
     let server_url = format!("--{url_arg_name}={NGINX_NP_URL}");
-
     let client_hostname = format!("--hostname={}", hostname);
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec![
-                    "ztclient",
-                    connect_arg_name,
-                    server_url.as_str(),
-                    client_hostname.as_str(),
-                ]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let auth_url = if let Some(auth_url) = vect.last() {
-        let line = std::str::from_utf8(auth_url).unwrap();
-        let index = line.find("state=").expect("state= must be there");
-        &line[index + 6..line.len()]
-    } else {
-        ""
-    };
-    Ok(String::from(auth_url))
+    connect_for_correlation_id(
+        docker,
+        container_name,
+        &[
+            "ztclient",
+            connect_arg_name,
+            server_url.as_str(),
+            client_hostname.as_str(),
+        ],
+    )
+    .await
 }
 
 pub async fn ztclient_status_json(docker: &Docker, container_name: &str) -> Result<StatusResult> {
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec!["ztclient", "status", "--json"]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdOut { message })) => {
-                    vect.push(message);
-                    ""
-                }
-
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-
-    let json = if let Some(json) = vect.last() {
-        let line = std::str::from_utf8(json).unwrap();
-        let as_json: StatusResult = serde_json::from_str(line)
-            .with_context(|| "Unable to unmarshall")
-            .unwrap();
-        as_json
-    } else {
-        bail!("Not a ztclient status json root")
-    };
-    Ok(json)
+    let output = exec_in_container(
+        docker,
+        container_name,
+        &["ztclient", "status", "--json"],
+        Some(EXEC_TIMEOUT),
+    )
+    .await?;
+    if output.stdout.trim().is_empty() {
+        return Err(Error::Exec {
+            container: container_name.to_string(),
+            command: "ztclient status --json".to_string(),
+            message: format!("no output: {}", output.stderr),
+        });
+    }
+    from_str(&output.stdout).map_err(|source| Error::Decode {
+        what: format!("`ztclient status --json` output from {container_name}"),
+        payload: output.stdout,
+        source,
+    })
 }
 
+/// Registers with a preauth token and returns whatever `ztclient` printed on stderr, which is
+/// empty on success.
 pub async fn preauth_token_registration(
     docker: &Docker,
     container_name: &str,
     preauth_token: &str,
     np_url: &str,
+) -> Result<String> {
+    preauth_token_registration_as(
+        docker,
+        container_name,
+        preauth_token,
+        np_url,
+        container_name,
+    )
+    .await
+}
+
+/// Same as [`preauth_token_registration`], but registers under `hostname` instead of the
+/// container name.
+pub async fn preauth_token_registration_as(
+    docker: &Docker,
+    container_name: &str,
+    preauth_token: &str,
+    np_url: &str,
+    hostname: &str,
 ) -> Result<String> {
     let (url_arg_name, connect_arg_name) = get_connect_actions();
     let server_url = format!("--{url_arg_name}={np_url}");
 
-    let client_hostname = format!("--hostname={}", container_name);
+    let client_hostname = format!("--hostname={}", hostname);
 
-    let commands = vec![
+    let commands = [
         "ztclient",
         connect_arg_name,
         server_url.as_str(),
@@ -368,208 +249,77 @@ pub async fn preauth_token_registration(
         preauth_token,
         client_hostname.as_str(),
     ];
-    dbg!(&commands);
-
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(commands),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let output = if let Some(auth_url) = vect.last() {
-        std::str::from_utf8(auth_url).unwrap()
-    } else {
-        ""
-    };
-    Ok(String::from(output))
+    let output = exec_in_container(
+        docker,
+        container_name,
+        &commands,
+        Some(REGISTRATION_TIMEOUT),
+    )
+    .await?;
+    Ok(output.stderr)
 }
 
 pub async fn ztclient_status(docker: &Docker, container_name: &str) -> Result<String> {
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec!["ztclient", "status"]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                Some(Ok(LogOutput::StdOut { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let full_string: String = vect
-        .iter()
-        .map(|x| std::str::from_utf8(x).unwrap())
-        .map(str::to_string)
-        .collect::<Vec<String>>()
-        .join("\n");
-    Ok(full_string)
+    let output = exec_in_container(
+        docker,
+        container_name,
+        &["ztclient", "status"],
+        Some(EXEC_TIMEOUT),
+    )
+    .await?;
+    Ok(output.combined())
 }
 
-pub async fn ztclient_logout(docker: &Docker, container_name: &str) -> Result<Vec<String>> {
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(vec!["ztclient", "logout"]),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                Some(Ok(LogOutput::StdOut { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                _ => "",
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let full_string: Vec<String> = vect
-        .iter()
-        .map(|x| std::str::from_utf8(x).unwrap())
-        .map(str::to_string)
-        .collect();
-    Ok(full_string)
+pub async fn ztclient_logout(docker: &Docker, container_name: &str) -> Result<ExecOutput> {
+    exec_in_container(
+        docker,
+        container_name,
+        &["ztclient", "logout"],
+        Some(EXEC_TIMEOUT),
+    )
+    .await
 }
 
 pub async fn ztclient_execute(
     docker: &Docker,
     container_name: &str,
     commands: Vec<&str>,
-) -> Result<Vec<String>> {
-    let res = docker
-        .create_exec(
-            container_name,
-            CreateExecOptions {
-                cmd: Some(commands),
-                attach_stderr: Some(true),
-                attach_stdout: Some(true),
-                ..Default::default()
-            },
-        )
-        .await;
-    let result = res.unwrap();
-    let output_result = docker.start_exec(result.id.as_str(), None).await?;
-    let mut vect = Vec::new();
-    match output_result {
-        StartExecResults::Attached { output, input: _ } => {
-            let mut dereffed = output;
-            let output_line = dereffed.next().await;
-            match output_line {
-                Some(Ok(LogOutput::StdErr { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                Some(Ok(LogOutput::StdOut { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                Some(Ok(LogOutput::Console { message })) => {
-                    vect.push(message);
-                    ""
-                }
-                Some(Ok(LogOutput::StdIn { message })) => {
-                    dbg!("Logoutput stdin");
-                    vect.push(message);
-                    ""
-                }
-                Some(Err(x)) => {
-                    dbg!(&x);
-                    ""
-                }
-                None => {
-                    dbg!("None case")
-                }
-            };
-        }
-        StartExecResults::Detached => (),
-    };
-    let full_string: Vec<String> = vect
-        .iter()
-        .map(|x| std::str::from_utf8(x).unwrap())
-        .map(str::to_string)
-        .collect();
-    Ok(full_string)
+) -> Result<ExecOutput> {
+    exec_in_container(docker, container_name, &commands, Some(EXEC_TIMEOUT)).await
+}
+
+/// Runs `ztclient zt-con` from one client to another and returns what it printed.  The clients
+/// answer with [`ZT_CON_GREETING`] when the connection is allowed.
+pub async fn zt_con(
+    docker: &Docker,
+    container_name: &str,
+    peer_name: &str,
+    port_number: u32,
+) -> Result<String> {
+    let port = port_number.to_string();
+    let output = exec_in_container(
+        docker,
+        container_name,
+        &["ztclient", "zt-con", peer_name, &port],
+        Some(EXEC_TIMEOUT),
+    )
+    .await?;
+    Ok(output.combined())
 }
 
 pub async fn create_and_register_client(
-    runtime_info: &RuntimeInformation,
+    np: &NinjaPandaClient,
     docker: &Docker,
     config: &Config,
-    client: &Client,
     container_name: &str,
     namespace_name: &str,
     user_info_id: usize,
 ) -> Result<String> {
-    start_ztclientd(docker, config, container_name)
-        .await
-        .unwrap();
-    let correlation_id = ztclient_registration(docker, container_name).await.unwrap();
-    let request = ExecuteCallbackRequest {
-        correlation_id: correlation_id.as_str(),
-        api_key: &runtime_info.ninja_panda_api_key,
-        namespace_name,
-        user_info_id,
-        ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-    };
-    let machine_id = execute_callback(client, &request).await.unwrap();
+    start_ztclientd(docker, config, container_name).await?;
+    let correlation_id = ztclient_registration(docker, container_name).await?;
+    let machine_id = np
+        .execute_callback(&correlation_id, namespace_name, user_info_id)
+        .await?;
     Ok(machine_id)
 }
 
@@ -579,113 +329,264 @@ pub async fn create_client_with_preauth_token(
     container_name: &str,
     preauth_token: &str,
 ) -> Result<String> {
-    start_ztclientd(docker, config, container_name)
-        .await
-        .unwrap();
-    let preauth_result =
-        preauth_token_registration(docker, container_name, preauth_token, NGINX_NP_URL)
-            .await
-            .unwrap();
-    Ok(preauth_result)
+    start_ztclientd(docker, config, container_name).await?;
+    preauth_token_registration(docker, container_name, preauth_token, NGINX_NP_URL).await
 }
 
+/// Runs `ztclient examine wait-for-peer`, which returns once `peer_name` answers, and gives up
+/// after [`STATE_TIMEOUT`].
 pub async fn wait_for_peer(
     docker: &Docker,
     container_name: &str,
     peer_name: &str,
 ) -> Result<ConResult> {
-    let exec = ztclient_execute(
+    let exec = exec_in_container(
         docker,
         container_name,
-        vec!["ztclient", "examine", "wait-for-peer", "--peer", peer_name],
+        &["ztclient", "examine", "wait-for-peer", "--peer", peer_name],
+        Some(STATE_TIMEOUT),
     )
-    .await
-    .unwrap();
-    let str1 = exec.into_iter().next().unwrap();
-    let con_res: ConResult = from_str(str1.as_str())
-        .with_context(|| "Unable to unmarshal a ZTCon Result")
-        .unwrap();
-    Ok(con_res)
+    .await?;
+    from_str(&exec.stdout).map_err(|source| Error::Decode {
+        what: format!("wait-for-peer result from {container_name}"),
+        payload: exec.stdout,
+        source,
+    })
 }
 
+/// Waits for the client to reach `new_state`, for up to [`STATE_TIMEOUT`].
 pub async fn wait_for_state_change(
     docker: &Docker,
     container_name: &str,
-    new_state: &str,
-) -> StatusResult {
-    let mut counter = 0;
-    let status: StatusResult = loop {
-        let status = ztclient_status_json(docker, container_name).await.unwrap();
-        if status.backend_state == new_state {
-            break status;
-        }
-        counter += 1;
-        if counter >= 1000 {
-            panic!("Counter taking too long!");
-        }
-        let sleep_time = time::Duration::from_millis(500);
-        sleep(sleep_time).await;
-    };
-    status
+    new_state: BackendState,
+) -> Result<StatusResult> {
+    wait_for_state(docker, container_name, new_state, STATE_TIMEOUT).await
 }
 
-pub async fn ztclient_netmap(docker: &Docker, container_name: &str) -> NetMap {
+pub async fn wait_for_state(
+    docker: &Docker,
+    container_name: &str,
+    state: BackendState,
+    timeout: Duration,
+) -> Result<StatusResult> {
+    wait_for_status(
+        docker,
+        container_name,
+        state.as_str(),
+        timeout,
+        Backoff::default(),
+        |status| status.backend_state == state,
+    )
+    .await
+}
+
+/// Polls `ztclient status --json` until `predicate` holds.  On timeout, returns an
+/// [`Error::StateTimeout`] with the backend states seen on the way, `wanted` being how the caller
+/// describes what it waited for.
+pub async fn wait_for_status<P>(
+    docker: &Docker,
+    container_name: &str,
+    wanted: &str,
+    timeout: Duration,
+    backoff: Backoff,
+    predicate: P,
+) -> Result<StatusResult>
+where
+    P: Fn(&StatusResult) -> bool,
+{
+    let mut observed: Vec<BackendState> = Vec::new();
+    let result = poll_until(
+        timeout,
+        backoff,
+        || ztclient_status_json(docker, container_name),
+        |status| {
+            if observed.last() != Some(&status.backend_state) {
+                observed.push(status.backend_state);
+            }
+            predicate(status)
+        },
+    )
+    .await;
+    result.map_err(|unfinished| Error::StateTimeout {
+        container: container_name.to_string(),
+        wanted: wanted.to_string(),
+        observed,
+        last_error: unfinished.last_error.map(|e| e.to_string()),
+        elapsed: unfinished.elapsed,
+    })
+}
+
+/// Polls the client's netmap until `predicate` holds for its number of peers.
+pub async fn wait_for_peer_count<P>(
+    docker: &Docker,
+    container_name: &str,
+    timeout: Duration,
+    predicate: P,
+) -> Result<NetMap>
+where
+    P: Fn(usize) -> bool,
+{
+    let mut peers = 0;
+    poll_until(
+        timeout,
+        Backoff::default(),
+        || ztclient_netmap(docker, container_name),
+        |netmap| {
+            peers = netmap.peers.as_ref().map(Vec::len).unwrap_or_default();
+            predicate(peers)
+        },
+    )
+    .await
+    .map_err(|unfinished| {
+        unfinished.into_timeout(&format!("the peers of {container_name}, has {peers}"))
+    })
+}
+
+pub async fn ztclient_netmap(docker: &Docker, container_name: &str) -> Result<NetMap> {
     let command = vec!["ztclient", "examine", "netmap"];
 
-    let strings = ztclient_execute(docker, container_name, command)
-        .await
-        .unwrap();
-    let netmap_str = strings.first().unwrap();
-    let ztn_netmap: NetMap = from_str(netmap_str).expect("Unable to unmarshall NetMap");
-    ztn_netmap
+    let output = ztclient_execute(docker, container_name, command).await?;
+    from_str(&output.stdout).map_err(|source| Error::Decode {
+        what: format!("netmap from {container_name}"),
+        payload: output.stdout,
+        source,
+    })
 }
 
+/// Numbered container names, `prefix001` and up, starting after `offset`.
+pub fn numbered_client_names(
+    hostname_prefix: &str,
+    num_clients: usize,
+    offset: usize,
+) -> Vec<String> {
+    (1..num_clients + 1)
+        .map(|x| format!("{}{:0>3}", hostname_prefix, x + offset))
+        .collect()
+}
+
+/// The user the client at `index` in [`numbered_client_names`] is registered as.  Client `n`,
+/// counted from 1 and without the offset, is registered as user `n % 9`.
+pub fn user_for_client(index: usize) -> usize {
+    (index + 1) % 9
+}
+
+/// Starts, registers and waits for `num_clients` clients, `parallelism` at a time, and checks that
+/// each is logged in as the expected user.  Every client is tried; the error names all the ones
+/// that failed.
 pub async fn create_running_clients(
-    runtime_info: &RuntimeInformation,
+    np: &NinjaPandaClient,
     docker: &Docker,
     config: &Config,
-    client: &Client,
     hostname_prefix: String,
     num_clients: usize,
     namespace_name: &str,
-) -> Vec<String> {
-    create_namespace(namespace_name, runtime_info, client)
-        .await
-        .unwrap();
-
-    for x in 1..num_clients + 1 {
-        let name = format!("{}{:0>3}", hostname_prefix, x);
-        start_ztclientd(docker, config, name.as_str())
-            .await
-            .unwrap();
-        let correlation_id = ztclient_registration(docker, name.as_str()).await.unwrap();
-        let request = ExecuteCallbackRequest {
-            correlation_id: correlation_id.as_str(),
-            api_key: &runtime_info.ninja_panda_api_key,
-            namespace_name,
-            user_info_id: (x % 9),
-            ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-        };
-        execute_callback(client, &request).await.unwrap();
+    parallelism: usize,
+) -> Result<Vec<String>> {
+    np.create_namespace(namespace_name).await?;
+
+    let container_names = numbered_client_names(&hostname_prefix, num_clients, 0);
+    launch_clients(&container_names, parallelism, |index, name| async move {
+        let user_info_id = user_for_client(index);
+        create_and_register_client(np, docker, config, &name, namespace_name, user_info_id).await?;
+        verify_running_as(docker, &name, user_info_id).await?;
+        Ok(name)
+    })
+    .await
+    .into_result()
+}
+
+/// Waits for the client to be running and checks that it is logged in as `get_user(user_info_id)`.
+async fn verify_running_as(docker: &Docker, name: &str, user_info_id: usize) -> Result<()> {
+    let user_info = get_user(user_info_id);
+
+    let status = wait_for_state_change(docker, name, BackendState::Running).await?;
+    let assigned_user_id = status.self_field.user_id;
+
+    let user_object = status
+        .user
+        .as_ref()
+        .and_then(|user_map| user_map.get(&assigned_user_id.to_string()))
+        .ok_or_else(|| {
+            Error::Verification(format!("{name} has no user with ID {assigned_user_id}"))
+        })?;
+    if user_object.first_name != user_info.first_name
+        || user_object.last_name != user_info.last_name
+    {
+        return Err(Error::Verification(format!(
+            "{name} is logged in as {} {}, expected {} {}",
+            user_object.first_name,
+            user_object.last_name,
+            user_info.first_name,
+            user_info.last_name
+        )));
     }
+    Ok(())
+}
 
-    // Check that the userInfo is correct for newly created nodes that have no peers.
-    for x in 1..num_clients + 1 {
-        let name = format!("{}{:0>3}", hostname_prefix, x);
-        let user_info = get_user(x % 9);
+/// Runs `ztclient connect` and reads its stderr until the login URL has been printed. The command
+/// keeps running until the machine is authorised, so we can't wait for it to exit.
+pub(crate) async fn connect_for_correlation_id(
+    docker: &Docker,
+    container_name: &str,
+    cmd: &[&str],
+) -> Result<String> {
+    let output = exec_until(
+        docker,
+        container_name,
+        cmd,
+        Some(REGISTRATION_TIMEOUT),
+        |output| {
+            output
+                .stderr
+                .find("state=")
+                .is_some_and(|index| output.stderr[index..].contains('\n'))
+        },
+    )
+    .await?;
+    correlation_id_from_output(container_name, &output.stderr)
+}
 
-        let status = wait_for_state_change(docker, name.as_str(), "Running").await;
-        let assigned_user_id = status.self_field.user_id;
+/// Pulls the registration correlation ID out of the `...?state=<id>` URL that `ztclient connect`
+/// prints on stderr. An already registered client prints nothing, which gives an empty ID.
+fn correlation_id_from_output(container_name: &str, output: &str) -> Result<String> {
+    if output.trim().is_empty() {
+        return Ok(String::new());
+    }
+    match output.find("state=") {
+        Some(index) => Ok(output[index + 6..]
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string()),
+        None => Err(Error::Exec {
+            container: container_name.to_string(),
+            command: "ztclient connect".to_string(),
+            message: format!("no state= in registration output: {output}"),
+        }),
+    }
+}
 
-        let user_map = status.user.unwrap();
-        let user_object = user_map.get(&assigned_user_id.to_string()).unwrap();
-        user_object.assert_eq(&user_info);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correlation_id_is_trimmed() {
+        let output = "To authenticate, visit:\n\n\thttp://np/login?state=abc-123\n\n";
+        assert_eq!(
+            correlation_id_from_output("zt001", output).unwrap(),
+            "abc-123"
+        );
+        assert_eq!(correlation_id_from_output("zt001", "").unwrap(), "");
+        assert!(correlation_id_from_output("zt001", "bad flag\n").is_err());
     }
 
-    let mut container_names = Vec::new();
-    for x in 1..num_clients + 1 {
-        let name = format!("{}{:0>3}", hostname_prefix, x);
-        container_names.push(name);
+    #[test]
+    fn users_follow_client_numbers() {
+        let names = numbered_client_names("zt", 2, 9);
+        assert_eq!(names, vec!["zt010", "zt011"]);
+        assert_eq!(user_for_client(0), 1);
+        assert_eq!(user_for_client(1), 2);
+        assert_eq!(user_for_client(8), 0);
     }
-    container_names
 }
@@ -1,20 +1,60 @@
 use anyhow::{Context, Result};
-use bollard::{network::CreateNetworkOptions, Docker};
+use bollard::{container::RemoveContainerOptions, network::CreateNetworkOptions, Docker};
 use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
 
-use std::{fs::File, io::Write};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
 use ztclient_common::{
-    execute_callback, get_running_json,
-    ninjapanda::{create_namespace, start_ninjapanda},
-    ztclient::{preauth_token_registration, start_ztclientd, ztclient_registration},
-    Config, ExecuteCallbackRequest, RuntimeInformation,
+    container_cleanup,
+    containers::{list_labelled_containers, list_service_containers, summary_name},
+    context::TestContext,
+    environment::{
+        bring_up, describe_containers, find_conf_dir, free_host_port, EnvironmentSettings, Stack,
+    },
+    get_running_json_for,
+    launch::{launch_clients, LaunchReport, DEFAULT_PARALLELISM},
+    loadtest::{run_load_test, LoadTestSettings},
+    models::Machine,
+    namespaces::{prune_namespaces, NamespaceFilter},
+    ninjapanda::{start_ninjapanda, NinjaPandaClient},
+    random_container_name,
+    reachability::{check_reachability, Probe},
+    readiness::{wait_for_container, wait_for_ninja_panda_api},
+    report::{TestReport, TestSuite},
+    runtime_file_path,
+    scenario::{run_scenario, Scenario},
+    soak::{random_seed, run_soak, SoakSettings},
+    ztclient::{
+        create_and_register_client, create_running_clients, numbered_client_names,
+        preauth_token_registration, start_ztclientd, user_for_client, ztclient_status_json,
+    },
+    Config, RuntimeInformation, DEFAULT_ENVIRONMENT, ENVIRONMENT_VAR,
 };
 
 #[derive(Parser, Debug)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    #[arg(
+        long,
+        global = true,
+        env = ENVIRONMENT_VAR,
+        default_value = DEFAULT_ENVIRONMENT,
+        help = "Name of the environment, so that several stacks can run on one host"
+    )]
+    pub environment: String,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Write a summary of what the command checked, as JSON if the file ends in .json and JUnit XML otherwise"
+    )]
+    pub report: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -25,6 +65,37 @@ pub enum Command {
     RegisterClients(RegisterClientsArgs),
     /// Launch and register a specified number of clients with a pre-auth key
     LaunchClients(LaunchClientsArgs),
+    /// Remove the clients and services this tool started, their machines and the runtime file
+    DestroyEnvironment(DestroyEnvironmentArgs),
+    /// List the clients this tool launched with their backend state and machine ID
+    EnvStatus(EnvStatusArgs),
+    /// Register clients at a given rate, peer them all and measure time to Running and to convergence
+    LoadTest(LoadTestArgs),
+    /// Keep randomly reconnecting, re-registering, renaming and restarting clients, checking invariants after each step
+    Soak(SoakArgs),
+    /// Set up the namespaces, clients and policies a scenario file declares and run its checks
+    RunScenario(RunScenarioArgs),
+    /// Try zt-con and zt-ping from every client to every other client and print who reached whom
+    Reachability(ReachabilityArgs),
+    /// Delete the namespaces matching a pattern that are older than a given age, with their machines
+    PruneNamespaces(PruneNamespacesArgs),
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::CreateEnvironment(_) => "create-environment",
+            Command::RegisterClients(_) => "register-clients",
+            Command::LaunchClients(_) => "launch-clients",
+            Command::DestroyEnvironment(_) => "destroy-environment",
+            Command::EnvStatus(_) => "env-status",
+            Command::LoadTest(_) => "load-test",
+            Command::Soak(_) => "soak",
+            Command::RunScenario(_) => "run-scenario",
+            Command::Reachability(_) => "reachability",
+            Command::PruneNamespaces(_) => "prune-namespaces",
+        }
+    }
 }
 
 #[derive(Debug, Default, Args)]
@@ -38,7 +109,7 @@ pub struct CreateEnvironmentArgs {
 
     #[arg(
         long,
-        help = "The port at which to expose the Ninja Panda API",
+        help = "The port at which to expose the Ninja Panda API; named environments get a free one",
         default_value = "15000"
     )]
     ninja_panda_port: u32,
@@ -52,7 +123,7 @@ pub struct CreateEnvironmentArgs {
 
     #[arg(
         long,
-        help = "The port at which to expose the Postgres SQL database",
+        help = "The port at which to expose the Postgres SQL database; named environments get a free one",
         default_value = "15100"
     )]
     database_port: u32,
@@ -63,31 +134,114 @@ pub struct CreateEnvironmentArgs {
         default_value = "ztclienthost"
     )]
     hostname_prefix: String,
+
+    #[arg(
+        long,
+        help = "Namespace to register the clients in",
+        default_value = "optm"
+    )]
+    namespace_name: String,
+
+    #[arg(
+        long,
+        help = "Seconds to wait for the services and the Ninja Panda API to become ready",
+        default_value = "300"
+    )]
+    ready_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Use the services that are already running (e.g. from docker compose) instead of starting them"
+    )]
+    existing_stack: bool,
+
+    #[arg(
+        short = 'p',
+        long,
+        help = "How many clients to start and register at the same time",
+        default_value_t = DEFAULT_PARALLELISM
+    )]
+    parallelism: usize,
 }
 
 impl CreateEnvironmentArgs {
     fn validate(self) -> Self {
         self
     }
-    async fn execute(&self, config: &Config) -> Result<()> {
-        let opts = self;
-        let mut running_json_file = File::create("running.json").unwrap();
-
+    async fn execute(&self, base_config: &Config, environment_id: &str) -> Result<()> {
         // Initialize Docker client
         let docker = Docker::connect_with_unix_defaults()?;
         log::info!("Obtained connection to Docker daemon");
+        let config = &base_config.for_environment(environment_id);
+
+        let settings = EnvironmentSettings {
+            ninja_panda_port: self.ninja_panda_port,
+            database_port: self.database_port,
+            kafka_port: if environment_id == DEFAULT_ENVIRONMENT {
+                9092
+            } else {
+                free_host_port()?
+            },
+            backend_settle_time: Duration::from_secs(self.sleep_time),
+            conf_dir: if self.existing_stack {
+                PathBuf::new()
+            } else {
+                find_conf_dir()?
+            },
+            environment_id: environment_id.to_string(),
+        };
+        let stack = Stack::new(base_config, &settings);
+        let ready_deadline = Instant::now() + Duration::from_secs(self.ready_timeout);
+        if !self.existing_stack {
+            bring_up(
+                &docker,
+                &config.docker_network_name,
+                &stack,
+                settings.backend_settle_time,
+            )
+            .await?;
+        }
+        for container_name in stack.container_names().iter() {
+            wait_for_container(&docker, container_name, ready_deadline).await?;
+        }
 
         log::info!("Starting primary NinjaPanda and will get API key");
         let api_key = start_ninjapanda(&docker, config.ninja_panda_container_name.as_str()).await?;
 
-        let ninja_panda_api_url: String = format!("http://localhost:{}", opts.ninja_panda_port);
-
-        let runtime_info = RuntimeInformation {
+        let mut runtime_info = RuntimeInformation {
             ninja_panda_api_key: api_key.clone(),
-            ninja_panda_api_url: ninja_panda_api_url.clone(),
+            environment_id: environment_id.to_string(),
+            network_name: config.docker_network_name.clone(),
+            containers: describe_containers(&docker, &stack.container_names()).await?,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            ..Default::default()
         };
-        let ri_bytes = serde_json::to_vec(&runtime_info).unwrap();
-        running_json_file.write_all(&ri_bytes).unwrap();
+        // Named environments leave the port to Docker, so ask nginx where it ended up.
+        let api_port = runtime_info
+            .published_port(&stack.proxy.container_name, "80/tcp")
+            .with_context(|| format!("{} does not publish port 80", stack.proxy.container_name))?;
+        runtime_info.ninja_panda_api_url = format!("http://localhost:{api_port}");
+        // Only hand the environment to the tests once the API answers through nginx.
+        let np = NinjaPandaClient::from(&runtime_info);
+        wait_for_ninja_panda_api(&np, ready_deadline).await?;
+
+        let runtime_path = runtime_file_path(environment_id);
+        runtime_info.save(&runtime_path)?;
+        log::info!("Wrote {}", runtime_path.display());
+
+        if self.num_clients > 0 {
+            let clients = create_running_clients(
+                &np,
+                &docker,
+                config,
+                self.hostname_prefix.clone(),
+                self.num_clients as usize,
+                &self.namespace_name,
+                self.parallelism,
+            )
+            .await?;
+            log::info!("Launched clients {clients:?}");
+        }
         Ok(())
     }
 }
@@ -128,10 +282,18 @@ pub struct LaunchClientsArgs {
         help = "Machine hostname prefix for the new client hostnames"
     )]
     hostname_prefix: String,
+
+    #[arg(
+        short = 'p',
+        long,
+        help = "How many clients to start and register at the same time",
+        default_value_t = DEFAULT_PARALLELISM
+    )]
+    parallelism: usize,
 }
 
 impl LaunchClientsArgs {
-    async fn execute(&self, config: &Config) -> Result<()> {
+    async fn execute(&self, config: &Config, test_report: &mut TestReport) -> Result<()> {
         // Initialize Docker client
         let docker = Docker::connect_with_unix_defaults()?;
 
@@ -148,17 +310,23 @@ impl LaunchClientsArgs {
 
         log::info!("Re-created docker network for all the containers");
 
-        for x in 1..self.num_clients + 1 {
-            let container_name = format!("{}{:0>3}", self.hostname_prefix, x + self.offset);
-
-            start_ztclientd(&docker, config, &container_name)
-                .await
-                .unwrap();
-            preauth_token_registration(&docker, &container_name, &self.pre_auth_token, &self.url)
-                .await
-                .unwrap();
-        }
-        Ok(())
+        let container_names = numbered_client_names(
+            &self.hostname_prefix,
+            self.num_clients as usize,
+            self.offset as usize,
+        );
+        let docker = &docker;
+        let report = launch_clients(
+            &container_names,
+            self.parallelism,
+            |_, container_name| async move {
+                start_ztclientd(docker, config, &container_name).await?;
+                preauth_token_registration(docker, &container_name, &self.pre_auth_token, &self.url)
+                    .await
+            },
+        )
+        .await;
+        finish_launch(report, test_report)
     }
 }
 
@@ -195,10 +363,23 @@ pub struct RegisterClientsArgs {
         default_value = "optm"
     )]
     namespace_name: String,
+
+    #[arg(
+        short = 'p',
+        long,
+        help = "How many clients to start and register at the same time",
+        default_value_t = DEFAULT_PARALLELISM
+    )]
+    parallelism: usize,
 }
 
 impl RegisterClientsArgs {
-    async fn execute(&self, config: &Config) -> Result<()> {
+    async fn execute(
+        &self,
+        config: &Config,
+        environment_id: &str,
+        test_report: &mut TestReport,
+    ) -> Result<()> {
         // Initialize Docker client
         let docker = Docker::connect_with_unix_defaults()?;
 
@@ -215,35 +396,567 @@ impl RegisterClientsArgs {
 
         log::info!("Re-created docker network for all the containers");
 
-        let runtime_info = get_running_json()
-            .with_context(|| "Unable to open runtime information")
-            .unwrap();
+        let runtime_info = get_running_json_for(environment_id)
+            .with_context(|| "Unable to open runtime information")?;
 
-        let client = reqwest::Client::new();
+        let np = NinjaPandaClient::from(&runtime_info);
         // We really do not care if the namespace exists already.
-        create_namespace(&self.namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(&self.namespace_name).await?;
+
+        let container_names = numbered_client_names(
+            &self.hostname_prefix,
+            self.num_clients as usize,
+            self.offset as usize,
+        );
+        let (docker, np) = (&docker, &np);
+        let report = launch_clients(
+            &container_names,
+            self.parallelism,
+            |index, container_name| async move {
+                create_and_register_client(
+                    np,
+                    docker,
+                    config,
+                    &container_name,
+                    &self.namespace_name,
+                    user_for_client(index),
+                )
+                .await
+            },
+        )
+        .await;
+        finish_launch(report, test_report)
+    }
+}
 
-        for x in 1..self.num_clients + 1 {
-            let container_name = format!("{}{:0>3}", self.hostname_prefix, x + self.offset);
+/// Prints how each client fared and fails if any of them did.
+fn finish_launch(report: LaunchReport, test_report: &mut TestReport) -> Result<()> {
+    println!("{report}");
+    test_report.push(TestSuite::from(&report));
+    if !report.all_succeeded() {
+        anyhow::bail!("{} clients failed", report.failed().count());
+    }
+    Ok(())
+}
+/// Looks up the Ninja Panda machines for the given client hostnames.  Without runtime information
+/// there is no API to ask, so an empty list is returned.
+async fn machines_for_clients(
+    environment_id: &str,
+    client_names: &[String],
+) -> Result<Vec<Machine>> {
+    let runtime_info = match get_running_json_for(environment_id) {
+        Ok(runtime_info) => runtime_info,
+        Err(e) => {
+            log::warn!("Not looking up machines: {e}");
+            return Ok(Vec::new());
+        }
+    };
+    let np = NinjaPandaClient::from(&runtime_info);
+    let machines = np
+        .get_all_machines(client_names)
+        .await?
+        .into_iter()
+        .filter(|m| client_names.contains(&m.hostname))
+        .collect();
+    Ok(machines)
+}
 
-            start_ztclientd(&docker, config, &container_name)
-                .await
-                .unwrap();
-            let correlation_id = ztclient_registration(&docker, container_name.as_str()).await?;
-            let request = ExecuteCallbackRequest {
-                correlation_id: correlation_id.as_str(),
-                api_key: runtime_info.ninja_panda_api_key.as_str(),
-                namespace_name: self.namespace_name.as_str(),
-                user_info_id: (x as usize % 9),
-                ninja_panda_api_url: runtime_info.ninja_panda_api_url.as_str(),
+#[derive(Debug, Default, Args)]
+pub struct DestroyEnvironmentArgs {}
+
+impl DestroyEnvironmentArgs {
+    async fn execute(&self, config: &Config, environment_id: &str) -> Result<()> {
+        let docker = Docker::connect_with_unix_defaults()?;
+
+        let client_names: Vec<String> =
+            list_labelled_containers(&docker, &config.docker_network_name)
+                .await?
+                .iter()
+                .filter_map(summary_name)
+                .collect();
+
+        let machines = machines_for_clients(environment_id, &client_names).await?;
+        if !machines.is_empty() {
+            let np = NinjaPandaClient::from(&get_running_json_for(environment_id)?);
+            for machine in machines.iter() {
+                log::info!(
+                    "Deleting machine {} ({})",
+                    machine.machine_id,
+                    machine.hostname
+                );
+                np.delete_machine(&machine.machine_id).await?;
+            }
+        }
+
+        let service_names: Vec<String> =
+            list_service_containers(&docker, &config.docker_network_name)
+                .await?
+                .iter()
+                .filter_map(summary_name)
+                .collect();
+
+        for name in client_names.iter().chain(service_names.iter()) {
+            log::info!("Removing container {name}");
+            docker
+                .remove_container(
+                    name,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+        }
+
+        match std::fs::remove_file(runtime_file_path(environment_id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        println!(
+            "Removed {} clients, {} machines and {} services",
+            client_names.len(),
+            machines.len(),
+            service_names.len()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct EnvStatusArgs {}
+
+impl EnvStatusArgs {
+    async fn execute(&self, config: &Config, environment_id: &str) -> Result<()> {
+        let docker = Docker::connect_with_unix_defaults()?;
+
+        let containers = list_labelled_containers(&docker, &config.docker_network_name).await?;
+        let client_names: Vec<String> = containers.iter().filter_map(summary_name).collect();
+        let machines = machines_for_clients(environment_id, &client_names).await?;
+
+        println!(
+            "{:<24} {:<10} {:<14} MACHINE",
+            "CLIENT", "CONTAINER", "BACKEND"
+        );
+        for container in containers.iter() {
+            let Some(name) = summary_name(container) else {
+                continue;
             };
-            execute_callback(&client, &request).await?;
+            let container_state = container.state.clone().unwrap_or_default();
+            let backend_state = if container_state == "running" {
+                match ztclient_status_json(&docker, &name).await {
+                    Ok(status) => status.backend_state.to_string(),
+                    Err(e) => {
+                        log::warn!("Unable to get status of {name}: {e}");
+                        "?".to_string()
+                    }
+                }
+            } else {
+                "-".to_string()
+            };
+            let machine_id = machines
+                .iter()
+                .find(|m| m.hostname == name)
+                .map(|m| m.machine_id.as_str())
+                .unwrap_or("-");
+            println!("{name:<24} {container_state:<10} {backend_state:<14} {machine_id}");
         }
         Ok(())
     }
 }
+
+#[derive(Debug, Default, Args)]
+pub struct LoadTestArgs {
+    #[arg(
+        short = 'c',
+        long,
+        help = "The number of clients to register",
+        default_value = "10"
+    )]
+    num_clients: u32,
+
+    #[arg(
+        short = 'r',
+        long,
+        help = "Clients started per second",
+        default_value = "1.0"
+    )]
+    rate: f64,
+
+    #[arg(
+        short = 'm',
+        long,
+        help = "Machine hostname prefix for the new client hostnames",
+        default_value = "loadtest"
+    )]
+    hostname_prefix: String,
+
+    #[arg(
+        short = 'n',
+        long,
+        help = "Namespace to register the clients in",
+        default_value = "loadtest"
+    )]
+    namespace_name: String,
+
+    #[arg(
+        long,
+        help = "Seconds each client may take to see all its peers once the policy exists",
+        default_value = "300"
+    )]
+    convergence_timeout: u64,
+
+    #[arg(
+        short = 'f',
+        long,
+        help = "Write every client's timings to this file, as JSON if it ends in .json and CSV otherwise"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Remove the clients, their machines and the policy afterwards"
+    )]
+    cleanup: bool,
+}
+
+impl LoadTestArgs {
+    async fn execute(
+        &self,
+        config: &Config,
+        environment_id: &str,
+        test_report: &mut TestReport,
+    ) -> Result<()> {
+        let docker = Docker::connect_with_unix_defaults()?;
+        let np = NinjaPandaClient::from(
+            &get_running_json_for(environment_id)
+                .with_context(|| "Unable to open runtime information")?,
+        );
+        let settings = LoadTestSettings {
+            num_clients: self.num_clients as usize,
+            rate: self.rate,
+            hostname_prefix: self.hostname_prefix.clone(),
+            namespace_name: self.namespace_name.clone(),
+            convergence_timeout: Duration::from_secs(self.convergence_timeout),
+        };
+        let report = run_load_test(&docker, &np, config, &settings).await?;
+        println!("{report}");
+        test_report.push(TestSuite::from(&report));
+        if let Some(output) = &self.output {
+            report.save(output)?;
+            log::info!("Wrote {}", output.display());
+        }
+
+        if self.cleanup {
+            let container_names = report
+                .samples
+                .iter()
+                .map(|s| s.container_name.clone())
+                .collect();
+            let machine_ids = report
+                .samples
+                .iter()
+                .filter_map(|s| s.machine_id.clone())
+                .collect();
+            container_cleanup(&docker, container_names, machine_ids, &np).await?;
+            if let Some(policy_id) = &report.policy_id {
+                np.delete_acl_policy(policy_id).await?;
+            }
+        }
+
+        let failed = report.samples.iter().filter(|s| s.error.is_some()).count();
+        if failed > 0 {
+            anyhow::bail!("{failed} of {} clients failed", report.num_clients);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct SoakArgs {
+    #[arg(
+        short = 'c',
+        long,
+        help = "The number of clients to churn",
+        default_value = "4"
+    )]
+    pool_size: u32,
+
+    #[arg(
+        short = 's',
+        long,
+        help = "Seed for choosing the steps; rerun a failure with the seed it printed"
+    )]
+    seed: Option<u64>,
+
+    #[arg(long, help = "Stop after this many steps")]
+    steps: Option<usize>,
+
+    #[arg(
+        short = 'd',
+        long,
+        help = "Stop after this many seconds; without this or --steps the soak runs until an invariant breaks"
+    )]
+    duration: Option<u64>,
+
+    #[arg(
+        short = 'm',
+        long,
+        help = "Machine hostname prefix for the new client hostnames",
+        default_value = "soak"
+    )]
+    hostname_prefix: String,
+
+    #[arg(
+        short = 'n',
+        long,
+        help = "Namespace for the clients, which must hold nothing else; a new one by default"
+    )]
+    namespace_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "Seconds the clients may take to be Running with all their peers after each step",
+        default_value = "120"
+    )]
+    settle_timeout: u64,
+
+    #[arg(
+        short = 'p',
+        long,
+        help = "How many clients to start and register at the same time",
+        default_value_t = DEFAULT_PARALLELISM
+    )]
+    parallelism: usize,
+
+    #[arg(
+        long,
+        help = "Remove the clients, their machines, the policy and the namespace if the soak passes"
+    )]
+    cleanup: bool,
+}
+
+impl SoakArgs {
+    async fn execute(&self, config: &Config, environment_id: &str) -> Result<()> {
+        let docker = Docker::connect_with_unix_defaults()?;
+        let np = NinjaPandaClient::from(
+            &get_running_json_for(environment_id)
+                .with_context(|| "Unable to open runtime information")?,
+        );
+        let seed = self.seed.unwrap_or_else(random_seed);
+        println!("Soak seed: {seed}");
+        let settings = SoakSettings {
+            seed,
+            pool_size: self.pool_size as usize,
+            max_steps: self.steps,
+            duration: self.duration.map(Duration::from_secs),
+            hostname_prefix: self.hostname_prefix.clone(),
+            namespace_name: self
+                .namespace_name
+                .clone()
+                .unwrap_or_else(|| format!("soak{}", random_container_name())),
+            settle_timeout: Duration::from_secs(self.settle_timeout),
+            parallelism: self.parallelism,
+        };
+        let outcome = run_soak(&docker, &np, config, &settings).await?;
+        println!("Soak passed {} steps with seed {seed}", outcome.steps);
+
+        if self.cleanup {
+            container_cleanup(&docker, outcome.container_names, outcome.machine_ids, &np).await?;
+            if let Some(policy_id) = &outcome.policy_id {
+                np.delete_acl_policy(policy_id).await?;
+            }
+            np.delete_namespace(&settings.namespace_name).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RunScenarioArgs {
+    #[arg(
+        required = true,
+        help = "Scenario files, read as TOML if they end in .toml and as YAML otherwise"
+    )]
+    files: Vec<PathBuf>,
+}
+
+impl RunScenarioArgs {
+    async fn execute(
+        &self,
+        config: &Config,
+        environment_id: &str,
+        test_report: &mut TestReport,
+    ) -> Result<()> {
+        // Load them all first so that a typo in the last one doesn't waste a run.
+        let scenarios = self
+            .files
+            .iter()
+            .map(|file| Scenario::load(file))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let runtime_info = get_running_json_for(environment_id)
+            .with_context(|| "Unable to open runtime information")?;
+
+        let mut failed = 0;
+        for scenario in scenarios.iter() {
+            let ctx = TestContext::from_parts(
+                Docker::connect_with_unix_defaults()?,
+                config.clone(),
+                runtime_info.clone(),
+            );
+            let result = run_scenario(&ctx, scenario).await;
+            ctx.cleanup().await?;
+            if let Err(e) = &result {
+                log::error!("Scenario {} failed: {e}", scenario.name);
+                test_report.push(TestSuite::from_result(&scenario.name, &result));
+            }
+            // A scenario that couldn't be set up fails on its own; the others still run.
+            let Ok(report) = result else {
+                failed += 1;
+                continue;
+            };
+            println!("{report}");
+            test_report.push(TestSuite::from(&report));
+            if !report.passed() {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{failed} of {} scenarios failed", scenarios.len());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ReachabilityArgs {
+    #[arg(help = "Clients to probe between; every running client of the environment if none")]
+    clients: Vec<String>,
+
+    #[arg(
+        short = 't',
+        long = "tcp-port",
+        help = "TCP port to try with zt-con, can be given more than once",
+        default_value = "80"
+    )]
+    tcp_ports: Vec<u16>,
+
+    #[arg(
+        short = 'u',
+        long = "udp-port",
+        help = "UDP port to try with zt-con -u, can be given more than once"
+    )]
+    udp_ports: Vec<u16>,
+
+    #[arg(long, help = "Leave out zt-ping")]
+    no_ping: bool,
+
+    #[arg(
+        short = 'p',
+        long,
+        help = "How many probes to run at the same time",
+        default_value_t = DEFAULT_PARALLELISM
+    )]
+    parallelism: usize,
+
+    #[arg(
+        long,
+        help = "Seconds a ping waits for its pong before the peer counts as unreachable",
+        default_value = "10"
+    )]
+    probe_timeout: u64,
+
+    #[arg(
+        short = 'f',
+        long,
+        help = "Write every probe's outcome to this file as JSON"
+    )]
+    output: Option<PathBuf>,
+}
+
+impl ReachabilityArgs {
+    async fn execute(&self, config: &Config, test_report: &mut TestReport) -> Result<()> {
+        let docker = Docker::connect_with_unix_defaults()?;
+        let clients = if self.clients.is_empty() {
+            list_labelled_containers(&docker, &config.docker_network_name)
+                .await?
+                .iter()
+                .filter(|c| c.state.as_deref() == Some("running"))
+                .filter_map(summary_name)
+                .collect()
+        } else {
+            self.clients.clone()
+        };
+        if clients.len() < 2 {
+            anyhow::bail!("Need at least two clients, found {}", clients.len());
+        }
+
+        let mut probes: Vec<Probe> = self.tcp_ports.iter().map(|p| Probe::tcp(*p)).collect();
+        probes.extend(self.udp_ports.iter().map(|p| Probe::udp(*p)));
+        if !self.no_ping {
+            probes.push(Probe::Ping);
+        }
+        let matrix = check_reachability(
+            &docker,
+            &clients,
+            &probes,
+            self.parallelism,
+            Duration::from_secs(self.probe_timeout),
+        )
+        .await;
+        print!("{matrix}");
+        test_report.push(TestSuite::from(&matrix));
+        if let Some(output) = &self.output {
+            matrix.save(output)?;
+            log::info!("Wrote {}", output.display());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PruneNamespacesArgs {
+    #[arg(
+        help = "Names of the namespaces to delete, with * for any run of characters, e.g. 'optm*'"
+    )]
+    pattern: String,
+
+    #[arg(
+        long,
+        help = "Only delete namespaces created more than this many seconds ago",
+        default_value = "86400"
+    )]
+    older_than: u64,
+
+    #[arg(
+        long,
+        help = "List the namespaces that would be deleted without deleting them"
+    )]
+    dry_run: bool,
+}
+
+impl PruneNamespacesArgs {
+    async fn execute(&self, environment_id: &str) -> Result<()> {
+        let runtime_info = get_running_json_for(environment_id)
+            .with_context(|| "Unable to open runtime information")?;
+        let np = NinjaPandaClient::from(&runtime_info);
+        let filter = NamespaceFilter {
+            pattern: self.pattern.clone(),
+            older_than: Duration::from_secs(self.older_than),
+        };
+        let namespaces = prune_namespaces(&np, &filter, self.dry_run).await?;
+        let verb = if self.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        };
+        for namespace in namespaces.iter() {
+            println!("{verb} {namespace}");
+        }
+        println!("{verb} {} namespace(s)", namespaces.len());
+        Ok(())
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -258,17 +971,32 @@ async fn main() -> Result<()> {
 
     // Parse command line arguments using Clap and fix dependent variables
     let opts: Cli = Cli::parse();
+    let environment_id = opts.environment.as_str();
+    let env_config = config.for_environment(environment_id);
+
+    let command_name = opts.command.name();
+    let mut test_report = TestReport::default();
+    let report = &mut test_report;
+    let result = match opts.command {
+        Command::CreateEnvironment(x) => x.validate().execute(&config, environment_id).await,
+        Command::RegisterClients(x) => x.execute(&env_config, environment_id, report).await,
+        Command::LaunchClients(x) => x.execute(&env_config, report).await,
+        Command::DestroyEnvironment(x) => x.execute(&env_config, environment_id).await,
+        Command::EnvStatus(x) => x.execute(&env_config, environment_id).await,
+        Command::LoadTest(x) => x.execute(&env_config, environment_id, report).await,
+        Command::Soak(x) => x.execute(&env_config, environment_id).await,
+        Command::RunScenario(x) => x.execute(&env_config, environment_id, report).await,
+        Command::Reachability(x) => x.execute(&env_config, report).await,
+        Command::PruneNamespaces(x) => x.execute(environment_id).await,
+    };
 
-    match opts.command {
-        Command::CreateEnvironment(x) => {
-            x.validate().execute(&config).await?;
-        }
-        Command::RegisterClients(x) => {
-            x.execute(&config).await?;
-        }
-        Command::LaunchClients(x) => {
-            x.execute(&config).await?;
+    if let Some(path) = &opts.report {
+        // Commands without results of their own, or that failed before getting any, report how
+        // the command as a whole went.
+        if test_report.suites.is_empty() {
+            test_report.push(TestSuite::from_result(command_name, &result));
         }
-    };
-    Ok(())
+        test_report.save(path)?;
+    }
+    result
 }
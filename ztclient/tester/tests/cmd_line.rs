@@ -1,41 +1,16 @@
-use rstest::{fixture, rstest};
+use rstest::rstest;
 
 mod cmd_line_tests {
     use super::*;
     use bollard::Docker;
+    use ztclient_common::fixtures::{config, docker};
 
     use ztclient_common::{
         containers::remove_container,
-        get_running_json,
         ztclient::{start_ztclientd, ztclient_execute},
-        Config, RuntimeInformation,
+        Config,
     };
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
     async fn command_line_tests(docker: Docker, config: Config) {
@@ -274,7 +249,8 @@ their respective license information, visit:
             let answer = ztclient_execute(&docker, container_name, test.commands.clone())
                 .await
                 .unwrap();
-            let got: &str = answer.first().unwrap().as_str();
+            let got = answer.combined();
+            let got = got.as_str();
             let result = std::panic::catch_unwind(|| {
                 similar_asserts::assert_eq!(test.want, got);
             });
@@ -1,86 +1,50 @@
-use rstest::{fixture, rstest};
+use rstest::rstest;
 
 mod equivalency_tests {
     use std::time::Duration;
 
     use super::*;
     use bollard::Docker;
-
-    use reqwest::Client;
+    use ztclient_common::fixtures::{config, docker, np};
 
     use tokio::time::sleep;
     use ztclient_common::{
         containers::remove_container,
         errors::Errors,
-        get_running_json,
-        models::status::StatusResult,
-        ninjapanda::create_namespace,
+        handle::ZtClientHandle,
+        models::status::{BackendState, StatusResult},
+        ninjapanda::NinjaPandaClient,
         random_container_name, start_and_register_client, start_and_register_client_nh,
-        ztclient::{
-            states::RUNNING_STATE, wait_for_state_change, ztclient_execute, ztclient_netmap,
-        },
-        Config, RuntimeInformation,
+        ztclient::{wait_for_state_change, ztclient_netmap},
+        Config,
     };
 
     const NUM_CONTAINERS: u32 = 4;
     const SLEEP_TIME_MS: u64 = 800;
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
     async fn single_container_name_is_not_altered(
-        runtime_info: RuntimeInformation,
         docker: Docker,
         config: Config,
-        client: Client,
+        np: NinjaPandaClient,
     ) {
         let mut error_container = Errors::new();
         let container_str = random_container_name();
         let container_name = container_str.as_str();
         let namespace_name = "scnamenot";
-        create_namespace(namespace_name, &runtime_info, &client)
+        np.create_namespace(namespace_name).await.unwrap();
+
+        start_and_register_client(&docker, &np, &config, container_name, namespace_name, 5)
             .await
             .unwrap();
 
-        start_and_register_client(
-            &docker,
-            &runtime_info,
-            &config,
-            &client,
-            container_name,
-            namespace_name,
-            5,
-        )
-        .await;
-
-        let _status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let _status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
 
         // This is ugly, but hoping for a better netmap next time.
-        let netmap = ztclient_netmap(&docker, container_name).await;
+        let netmap = ztclient_netmap(&docker, container_name).await.unwrap();
         error_container.string_eq_assert(
             format!("{}.{}.ztmesh.net", container_name, namespace_name),
             netmap.self_node.name,
@@ -91,36 +55,31 @@ mod equivalency_tests {
 
     #[rstest]
     #[tokio::test]
-    async fn node_equivalency_nopref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn node_equivalency_nopref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let container_str = random_container_name();
         let container_name = container_str.as_str();
         let namespace_name = "nodeeqnopref";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         for x in 1..NUM_CONTAINERS + 1 {
             start_and_register_client_nh(
                 &docker,
-                &runtime_info,
+                &np,
                 &config,
-                &client,
                 container_name,
                 namespace_name,
                 x as usize % 9,
             )
-            .await;
+            .await
+            .unwrap();
             if x > 1 {
-                let _status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+                let _status = wait_for_state_change(&docker, container_name, BackendState::Running)
+                    .await
+                    .unwrap();
 
                 // This is ugly, but hoping for a better netmap next time.
-                let netmap = ztclient_netmap(&docker, container_name).await;
+                let netmap = ztclient_netmap(&docker, container_name).await.unwrap();
                 error_container.string_eq_assert(
                     format!("{}-{}.{}.ztmesh.net", container_name, x - 1, namespace_name),
                     netmap.self_node.name,
@@ -132,18 +91,11 @@ mod equivalency_tests {
 
     #[rstest]
     #[tokio::test]
-    async fn node_equivalency_withpref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn node_equivalency_withpref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let renamed_name = random_container_name();
         let namespace_name = "nodeeqwithpref";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -151,39 +103,25 @@ mod equivalency_tests {
             container_names.push(random_container_name());
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
-
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
-
-            ztclient_execute(
-                &docker,
-                name,
-                vec!["ztclient", "configure", "--hostname", renamed_name.as_str()],
-            )
-            .await
-            .unwrap();
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            ztclient_execute(&docker, name, vec!["ztclient", "disconnect"])
+            wait_for_state_change(&docker, name, BackendState::Running)
                 .await
                 .unwrap();
-            ztclient_execute(&docker, name, vec!["ztclient", "connect"])
+
+            ZtClientHandle::new(&docker, name)
+                .rename(&renamed_name)
                 .await
                 .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -197,27 +135,18 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn capital_letters_in_hostname(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn capital_letters_in_hostname(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = random_container_name().to_ascii_uppercase();
         let renamed_name = random_name.as_str();
         let namespace_name = "caplethostname";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -225,24 +154,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client_nh(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client_nh(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -257,28 +182,19 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn ending_in_numbers_hostname(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn ending_in_numbers_hostname(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_collector = Errors::new();
 
         let random_name = format!("{}{}", random_container_name(), "1234");
         let renamed_name = random_name.as_str();
         let namespace_name = "endnumhostname";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -286,24 +202,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client_nh(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client_nh(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             error_collector.expect_none(&netmap.packet_filter, "netmap.packet_filter");
             dbg!(&status.self_field.host_name, &netmap.self_node.name, index);
 
@@ -323,8 +235,6 @@ mod equivalency_tests {
                     format!("{}-{}", renamed_name, index - 1),
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
 
@@ -333,21 +243,14 @@ mod equivalency_tests {
 
     #[rstest]
     #[tokio::test]
-    async fn with_underscores_in_hostname(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn with_underscores_in_hostname(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = format!("a_{}", random_container_name());
         let renamed_name = random_name.replace('_', "-");
         let renamed_name = renamed_name.as_str();
         let namespace_name = "withundhostname";
 
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -355,25 +258,20 @@ mod equivalency_tests {
             container_names.push(random_name.clone())
         }
 
-        let mut index = 1;
-
-        for name in container_names.iter() {
-            start_and_register_client_nh(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client_nh(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -388,28 +286,19 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn with_hyphens_in_hostname(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn with_hyphens_in_hostname(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = format!("a-{}-b", random_container_name());
         let renamed_name = random_name.as_str();
         let namespace_name = "withhyphhostname";
 
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -417,24 +306,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client_nh(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client_nh(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -449,27 +334,18 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn capital_letters_in_connhnpref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn capital_letters_in_connhnpref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = random_container_name().to_ascii_uppercase();
         let renamed_name = random_name.as_str();
         let namespace_name = "caplethnpref";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -477,24 +353,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -509,28 +381,19 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn ending_in_numbers_in_connhnpref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn ending_in_numbers_in_connhnpref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = random_container_name();
         let random_name = format!("{}{}", random_name, "1234");
         let renamed_name = random_name.as_str();
         let namespace_name = "endnumhnpref";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -538,24 +401,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -570,20 +429,13 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn with_underscores_in_connhnpref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn with_underscores_in_connhnpref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let random_name = random_container_name();
         let random_name = format!("a_{}", random_name);
@@ -591,9 +443,7 @@ mod equivalency_tests {
         let renamed_name = renamed_name.as_str();
         let namespace_name = "withundhnpref";
 
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -601,25 +451,20 @@ mod equivalency_tests {
             container_names.push(random_name.clone())
         }
 
-        let mut index = 1;
-
-        for name in container_names.iter() {
-            start_and_register_client(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -634,28 +479,19 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn with_hyphens_in_connhnpref(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn with_hyphens_in_connhnpref(docker: Docker, config: Config, np: NinjaPandaClient) {
         let random_name = random_container_name();
         let random_name = format!("a-{}-b", random_name);
         let renamed_name = random_name.as_str();
         let namespace_name = "withhyphhnpref";
 
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
         let mut container_names = Vec::new();
 
@@ -663,24 +499,20 @@ mod equivalency_tests {
             container_names.push(renamed_name)
         }
 
-        let mut index = 1;
-        for name in container_names.iter() {
-            start_and_register_client(
-                &docker,
-                &runtime_info,
-                &config,
-                &client,
-                name,
-                namespace_name,
-                4,
-            )
-            .await;
+        for (index, name) in (1..).zip(container_names.iter()) {
+            start_and_register_client(&docker, &np, &config, name, namespace_name, 4)
+                .await
+                .unwrap();
 
-            wait_for_state_change(&docker, name, RUNNING_STATE).await;
+            wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
-            let _status: StatusResult = wait_for_state_change(&docker, name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, name).await;
+            let _status: StatusResult = wait_for_state_change(&docker, name, BackendState::Running)
+                .await
+                .unwrap();
+            let netmap = ztclient_netmap(&docker, name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
 
             if index > 1 {
@@ -695,67 +527,47 @@ mod equivalency_tests {
                     netmap.self_node.name,
                 );
             }
-            index += 1;
-
             remove_container(&docker, renamed_name).await;
         }
     }
 
     #[rstest]
     #[tokio::test]
-    async fn multiple_renames(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn multiple_renames(docker: Docker, config: Config, np: NinjaPandaClient) {
         let random_name = random_container_name();
         let namespace_name = "multirens";
 
-        create_namespace(namespace_name, &runtime_info, &client)
+        np.create_namespace(namespace_name).await.unwrap();
+
+        start_and_register_client(&docker, &np, &config, &random_name, namespace_name, 4)
             .await
             .unwrap();
 
-        start_and_register_client(
-            &docker,
-            &runtime_info,
-            &config,
-            &client,
-            &random_name,
-            namespace_name,
-            4,
-        )
-        .await;
-
-        wait_for_state_change(&docker, &random_name, RUNNING_STATE).await;
+        wait_for_state_change(&docker, &random_name, BackendState::Running)
+            .await
+            .unwrap();
 
         sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
         let _status: StatusResult =
-            wait_for_state_change(&docker, &random_name, RUNNING_STATE).await;
-        let netmap = ztclient_netmap(&docker, &random_name).await;
+            wait_for_state_change(&docker, &random_name, BackendState::Running)
+                .await
+                .unwrap();
+        let netmap = ztclient_netmap(&docker, &random_name).await.unwrap();
         dbg!(&_status.self_field.host_name, &netmap.self_node.name);
 
         for index in 0..NUM_CONTAINERS {
             let new_name = random_container_name();
-            ztclient_execute(
-                &docker,
-                &random_name,
-                vec!["ztclient", "configure", "--hostname", new_name.as_str()],
-            )
-            .await
-            .unwrap();
-
-            ztclient_execute(&docker, &random_name, vec!["ztclient", "disconnect"])
-                .await
-                .unwrap();
-            ztclient_execute(&docker, &random_name, vec!["ztclient", "connect"])
+            ZtClientHandle::new(&docker, &random_name)
+                .rename(&new_name)
                 .await
                 .unwrap();
 
             sleep(Duration::from_millis(SLEEP_TIME_MS)).await;
             let _status: StatusResult =
-                wait_for_state_change(&docker, &random_name, RUNNING_STATE).await;
-            let netmap = ztclient_netmap(&docker, &random_name).await;
+                wait_for_state_change(&docker, &random_name, BackendState::Running)
+                    .await
+                    .unwrap();
+            let netmap = ztclient_netmap(&docker, &random_name).await.unwrap();
             dbg!(&_status.self_field.host_name, &netmap.self_node.name, index);
         }
         remove_container(&docker, &random_name).await;
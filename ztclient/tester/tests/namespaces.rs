@@ -0,0 +1,51 @@
+use rstest::rstest;
+
+mod namespaces_tests {
+    use std::time::Duration;
+
+    use super::*;
+    use ztclient_common::fixtures::ctx;
+
+    use ztclient_common::{
+        context::TestContext, ninjapanda::NamespaceCreation, random_container_name,
+    };
+
+    #[rstest]
+    #[tokio::test]
+    async fn namespace_lifecycle(ctx: TestContext) {
+        let np = &ctx.np;
+        let namespace_name = format!("test{}", random_container_name());
+        let renamed = format!("{namespace_name}r");
+
+        let created = np
+            .create_namespace_with_ttl(&namespace_name, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        ctx.track_namespace(&namespace_name);
+        ctx.track_namespace(&renamed);
+        let NamespaceCreation::Created(namespace) = created else {
+            panic!("{namespace_name} already existed");
+        };
+        assert_eq!(namespace_name, namespace.name);
+        assert!(namespace.created().is_some(), "{namespace:?}");
+        assert_eq!(
+            NamespaceCreation::AlreadyExists,
+            np.create_namespace(&namespace_name).await.unwrap()
+        );
+
+        let listed = np.list_namespaces().await.unwrap();
+        assert!(listed.iter().any(|n| n.name == namespace_name));
+
+        let namespace = np
+            .rename_namespace(&namespace_name, &renamed)
+            .await
+            .unwrap();
+        assert_eq!(renamed, namespace.name);
+        assert_eq!(renamed, np.get_namespace(&renamed).await.unwrap().name);
+        assert!(np.get_namespace(&namespace_name).await.is_err());
+
+        np.delete_namespace(&renamed).await.unwrap();
+        let listed = np.list_namespaces().await.unwrap();
+        assert!(!listed.iter().any(|n| n.name == renamed));
+    }
+}
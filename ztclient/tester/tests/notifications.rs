@@ -1,131 +1,98 @@
-use rstest::{fixture, rstest};
+use rstest::rstest;
 
 mod notifications_tests {
     use std::time::Duration;
 
     use super::*;
+    use ztclient_common::fixtures::{
+        config, docker, namespaced, np, running_clients, Namespaced, RunningClients,
+    };
 
     use bollard::Docker;
 
-    use reqwest::Client;
-
-    use tokio::time::sleep;
     use ztclient_common::{
         container_cleanup,
         errors::Errors,
-        get_running_json,
-        ninjapanda::{get_all_machine_ids, make_all_machines_peers, make_internet_gateway},
+        handle::ZtClientHandle,
+        ipn::WatchOptions,
+        kafka::{topics, KafkaEvent, KafkaSubscription},
+        launch::DEFAULT_PARALLELISM,
+        models::MachineUpdateMessage,
+        netmap::wait_for_netmap,
+        ninjapanda::NinjaPandaClient,
         random_container_name,
         ztclient::{create_running_clients, ztclient_netmap},
-        Config, RuntimeInformation,
+        Config,
     };
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
-    async fn notify_setup(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: Client,
-    ) {
+    async fn notify_setup(docker: Docker, config: Config, np: NinjaPandaClient) {
         let mut error_container = Errors::new();
         let hostname_prefix: String = random_container_name();
         let num_clients = 4;
         let namespace_name = "notifs";
         let container_names = create_running_clients(
-            &runtime_info,
+            &np,
             &docker,
             &config,
-            &client,
             hostname_prefix,
             num_clients,
             namespace_name,
+            DEFAULT_PARALLELISM,
         )
-        .await;
+        .await
+        .unwrap();
 
-        let machine_ids = get_all_machine_ids(&runtime_info, &client, &container_names)
-            .await
-            .unwrap();
-        let policy_id = make_all_machines_peers(&runtime_info, &machine_ids, &client)
-            .await
-            .unwrap();
+        let machine_ids = np.get_all_machine_ids(&container_names).await.unwrap();
+        let policy_id = np.make_all_machines_peers(&machine_ids).await.unwrap();
 
         dbg!(&policy_id);
 
         // Make one of them an internet gateway
         let machine_id1 = machine_ids.first().unwrap();
         let machine_id1 = &machine_id1.replace("machine:", "");
-        make_internet_gateway(&runtime_info, &client, machine_id1)
-            .await
-            .unwrap();
+        np.make_internet_gateway(machine_id1).await.unwrap();
 
-        let mut stop_loop = false;
-        let mut counter = 0;
-        while !stop_loop {
+        for x in container_names.iter() {
             // Now let's check the user names after the peers have been declared.
-            stop_loop = true;
-            counter += 1;
-            for x in container_names.iter() {
-                // dbg!(x);
-                let netmap = ztclient_netmap(&docker, x).await;
-                let peers = netmap.peers.unwrap_or_default();
-                if peers.is_empty() {
-                    stop_loop = false;
-                    sleep(Duration::from_secs(5)).await;
+            error_container.in_container(x);
+            let netmap = match wait_for_netmap(
+                &docker,
+                x,
+                |netmap| {
+                    netmap
+                        .peers
+                        .as_ref()
+                        .is_some_and(|p| p.len() == num_clients - 1)
+                },
+                Duration::from_secs(80),
+            )
+            .await
+            {
+                Ok(netmap) => netmap,
+                Err(e) => {
+                    error_container.add_error(e.to_string());
                     continue;
                 }
-                error_container.num_eq_assert(
-                    num_clients - 1,
-                    peers.len(),
-                    format!("Unexpected number of peers for {}", netmap.self_node.name).as_str(),
+            };
+            let peers = netmap.peers.unwrap_or_default();
+
+            // Assert that all the peers are online
+            for peer in peers.iter() {
+                error_container.bool_assert(
+                    peer.online,
+                    format!(
+                        "For machine={} peer {} not online but expected to!",
+                        netmap.self_node.name, peer.name
+                    ),
                 );
-                let _packet_filter = netmap.packet_filter.unwrap_or_default();
-
-                // Assert that all the peers are online
-                for peer in peers.iter() {
-                    error_container.bool_assert(
-                        peer.online,
-                        format!(
-                            "For machine={} peer {} not online but expected to!",
-                            netmap.self_node.name, peer.name
-                        ),
-                    );
-                }
-            }
-            if counter > 15 {
-                break;
             }
         }
-        dbg!(counter);
         let ig_route4 = "0.0.0.0/0".to_string();
         let mut count = 0;
         for x in container_names.iter() {
-            let netmap = ztclient_netmap(&docker, x).await;
+            let netmap = ztclient_netmap(&docker, x).await.unwrap();
             let self_node = netmap.self_node;
             let packet_filter = netmap.packet_filter.unwrap_or_default();
             dbg!(&packet_filter);
@@ -138,14 +105,87 @@ mod notifications_tests {
         }
 
         dbg!("Number of internet gateways", count);
-        container_cleanup(
-            &docker,
-            container_names,
-            machine_ids,
-            &runtime_info,
-            &client,
+        container_cleanup(&docker, container_names, machine_ids, &np)
+            .await
+            .unwrap();
+        error_container.assert_pop();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn register_and_route_change_are_published(#[future] namespaced: Namespaced) {
+        let ctx = namespaced.await;
+        let mut error_container = Errors::new();
+        let mut events = KafkaSubscription::subscribe(
+            &ctx.docker,
+            &ctx.config.kafka_container_name,
+            &[topics::MACHINE_REGISTER, topics::MACHINE_UPDATE],
         )
-        .await;
+        .await
+        .unwrap();
+
+        let namespace_name = ctx.namespace.as_str();
+        let container_name = random_container_name();
+        let machine_id = ctx
+            .create_and_register_client(&container_name, namespace_name, 1)
+            .await
+            .unwrap();
+
+        let registered = events
+            .expect_event(
+                |event: &KafkaEvent<MachineUpdateMessage>| {
+                    event.topic == topics::MACHINE_REGISTER
+                        && event.message.machine.machine_id == machine_id
+                },
+                Duration::from_secs(30),
+            )
+            .await
+            .unwrap();
+        error_container.string_eq_assert(
+            registered.message.machine.namespace.name,
+            namespace_name.to_string(),
+        );
+
+        ctx.np.make_internet_gateway(&machine_id).await.unwrap();
+        events
+            .expect_event(
+                |event: &KafkaEvent<MachineUpdateMessage>| {
+                    event.topic == topics::MACHINE_UPDATE
+                        && event.message.machine.machine_id == machine_id
+                },
+                Duration::from_secs(30),
+            )
+            .await
+            .unwrap();
+
         error_container.assert_pop();
     }
+
+    #[rstest]
+    #[tokio::test]
+    async fn new_peers_are_pushed_on_the_ipn_bus(#[future] running_clients: RunningClients) {
+        let clients = running_clients.await;
+        let (a, b) = (&clients.clients[0], &clients.clients[1]);
+        let client = ZtClientHandle::new(&clients.docker, &a.container_name);
+        let mut watch = client.watch_ipn(&WatchOptions::default()).await.unwrap();
+
+        let policy_id = clients
+            .np
+            .make_all_machines_peers(&clients.machine_ids())
+            .await
+            .unwrap();
+        clients.track_acl_policy(&policy_id);
+
+        let netmap = watch
+            .wait_for_peer_count(|peers| peers == 1, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let peers = netmap.peers.unwrap_or_default();
+        assert!(
+            peers
+                .iter()
+                .any(|peer| peer.name.starts_with(&b.container_name)),
+            "{peers:?}"
+        );
+    }
 }
@@ -10,113 +10,100 @@ mod policies_tests {
     use std::time::Duration;
 
     use anyhow::Result;
-    use bollard::Docker;
-    use rstest::{fixture, rstest};
+    use rstest::rstest;
     use tokio::time::sleep;
     use ztclient_common::{
-        containers::{remove_container, ContainerRemover},
+        acl::AclEvaluation,
         errors::Errors,
-        get_running_json, get_unique_timestamp,
-        ninjapanda::{
-            create_namespace, delete_acl_policy, get_all_machine_ids, grant_one_directional_policy,
-            make_all_machines_peers, zero_out_acl_policy,
-        },
-        random_container_name,
-        ztclient::{create_and_register_client, create_running_clients, ztclient_netmap},
-        Config, RuntimeInformation,
+        fixtures::{policy_pair, running_clients, PolicyPair, RunningClients},
+        models::Machine,
+        policy::{PolicyBuilder, Protocol},
+        ztclient::ztclient_netmap,
     };
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
-    async fn test01(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: reqwest::Client,
-    ) -> Result<()> {
+    async fn test01(#[future] policy_pair: PolicyPair) -> Result<()> {
         // * 1.  Create two machines, make them one-way peers on port 80, A->B.
         // *     - A.peers={B}
+        let pair = policy_pair.await;
+        let docker = &pair.docker;
         let mut error_container = Errors::new();
-        let namespace_suffix = get_unique_timestamp();
-        let namespace_name = format!("optm{namespace_suffix}");
-        create_namespace(namespace_name.as_str(), &runtime_info, &client)
-            .await
-            .unwrap();
-        let container_name1 = "policy01";
-        let container_name2 = "policy02";
-        let user_info_id = 3;
-        let machine_id1 = create_and_register_client(
-            &runtime_info,
-            &docker,
-            &config,
-            &client,
-            container_name1,
-            namespace_name.as_str(),
-            user_info_id,
-        )
-        .await
-        .unwrap();
-
-        let machine_id2 = create_and_register_client(
-            &runtime_info,
-            &docker,
-            &config,
-            &client,
-            container_name2,
-            namespace_name.as_str(),
-            user_info_id,
-        )
-        .await
-        .unwrap();
-
-        // Make them one-way peers
-        let policy_id =
-            grant_one_directional_policy(&runtime_info, &machine_id1, &machine_id2, 80, &client)
-                .await?;
+        let container_name1 = pair.source.container_name.as_str();
+        let container_name2 = pair.destination.container_name.as_str();
+        let policy_id = &pair.policy_id;
 
         sleep(Duration::from_millis(5000)).await;
 
-        let netmap1 = ztclient_netmap(&docker, &container_name1).await;
+        error_container.in_container(container_name1);
+        let netmap1 = ztclient_netmap(docker, container_name1).await.unwrap();
         error_container.expect_some(&netmap1.peers, "NetMap1.Peers");
         error_container.expect_none(&netmap1.packet_filter, "NetMap1.PacketFilter");
 
-        let netmap2 = ztclient_netmap(&docker, &container_name2).await;
+        error_container.in_container(container_name2);
+        let netmap2 = ztclient_netmap(docker, container_name2).await.unwrap();
         error_container.expect_some(&netmap2.peers, "NetMap2.Peers");
         error_container.expect_some(&netmap2.packet_filter, "NetMap2.PacketFilter");
 
-        zero_out_acl_policy(&runtime_info, &client, &policy_id).await?;
+        pair.np.zero_out_acl_policy(policy_id).await?;
         sleep(Duration::from_millis(5000)).await;
 
-        let netmap1_1 = ztclient_netmap(&docker, &container_name1).await;
+        error_container.in_container(container_name1);
+        let netmap1_1 = ztclient_netmap(docker, container_name1).await.unwrap();
         error_container.expect_none(&netmap1_1.peers, "NetMap1_1.Peers");
 
-        // remove_container(&docker, container_name1).await;
-        // remove_container(&docker, container_name2).await;
+        error_container.assert_pop();
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn test02(#[future] running_clients: RunningClients) -> Result<()> {
+        // * 2.  Create two machines, make them two-way peers on port 80, A->B and B->A
+        let clients = running_clients.await;
+        let (docker, np) = (&clients.docker, &clients.np);
+        let mut error_container = Errors::new();
+        let (a, b) = (&clients.clients[0], &clients.clients[1]);
+
+        let policy = PolicyBuilder::new()
+            .machines("a", std::slice::from_ref(&a.machine_id))
+            .machines("b", std::slice::from_ref(&b.machine_id))
+            .allow("a", "b", Protocol::Tcp, 80)
+            .allow("b", "a", Protocol::Tcp, 80);
+        clients.track_acl_policy(&policy.create(np).await?);
+        sleep(Duration::from_millis(5000)).await;
+
+        let machines: Vec<Machine> = np
+            .get_machines()
+            .await?
+            .into_iter()
+            .filter(|m| [&a.machine_id, &b.machine_id].contains(&&m.machine_id))
+            .collect();
+        let evaluation = AclEvaluation::evaluate(&[policy.build()?], &machines)?;
+        for client in [a, b] {
+            error_container.in_container(&client.container_name);
+            let netmap = ztclient_netmap(docker, &client.container_name).await?;
+            evaluation.check_netmap(&mut error_container, &client.machine_id, &netmap);
+            error_container.num_eq_assert(1, netmap.peers.unwrap_or_default().len(), "peers");
+            error_container.expect_some(&netmap.packet_filter, "packet filter");
+        }
+
+        // Back to one-way: only B may reach A, so B no longer accepts anything.
+        let policy = policy.without_rules().allow("b", "a", Protocol::Tcp, 80);
+        policy.update(np).await?;
+        sleep(Duration::from_millis(5000)).await;
+
+        error_container.in_container(&b.container_name);
+        let netmap = ztclient_netmap(docker, &b.container_name).await?;
+        error_container.expect_none(&netmap.packet_filter, "packet filter");
+
+        policy.delete(np).await?;
+        sleep(Duration::from_millis(5000)).await;
+
+        error_container.in_container(&a.container_name);
+        let netmap = ztclient_netmap(docker, &a.container_name).await?;
+        error_container.expect_none(&netmap.peers, "peers");
 
         error_container.assert_pop();
 
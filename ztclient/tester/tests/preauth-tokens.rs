@@ -1,83 +1,48 @@
-use rstest::{fixture, rstest};
+use rstest::rstest;
 mod preauth_tokens_tests {
     use super::*;
     use bollard::Docker;
+    use ztclient_common::fixtures::{config, docker, np, runtime_info};
 
     use ztclient_common::{
         containers::remove_container,
-        execute_callback, get_running_json,
-        models::CreatePreauthTokenRequest,
-        ninjapanda::{create_namespace, create_preauth_token},
+        models::{status::BackendState, CreatePreauthTokenRequest},
+        ninjapanda::NinjaPandaClient,
         users::get_user,
         ztclient::{
-            create_client_with_preauth_token, preauth_token_registration, states::RUNNING_STATE,
-            wait_for_state_change, ztclient_execute, ztclient_logout,
-            ztclient_register_forcereauth, ztclient_registration, NGINX_NP_URL,
+            create_client_with_preauth_token, preauth_token_registration, wait_for_state_change,
+            ztclient_execute, ztclient_logout, ztclient_register_forcereauth,
+            ztclient_registration, NGINX_NP_URL,
         },
-        Config, ExecuteCallbackRequest, RuntimeInformation,
+        Config, Error, RuntimeInformation,
     };
 
     const INVALID_AUTH_TOKEN_ERROR: &str = "backend error: Invalid preauth token\n";
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
 
-    async fn login_with_valid_preauth_token(
-        config: Config,
-        docker: Docker,
-        client: reqwest::Client,
-        runtime_info: RuntimeInformation,
-    ) {
+    async fn login_with_valid_preauth_token(config: Config, docker: Docker, np: NinjaPandaClient) {
         let namespace_name = "optm";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
-        let preauth_token = create_preauth_token(
-            &client,
-            &runtime_info,
-            CreatePreauthTokenRequest {
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
                 acl_tags: vec![],
                 namespace: namespace_name.to_string(),
                 prefix: "".to_string(),
                 reuse_count: 0,
                 ephemeral: false,
                 expiration: "".to_string(),
-            },
-        )
-        .await
-        .unwrap();
+            })
+            .await
+            .unwrap();
 
         let result = create_client_with_preauth_token(
             &docker,
             &config,
             "preauth_token_test01",
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
@@ -85,6 +50,24 @@ mod preauth_tokens_tests {
         remove_container(&docker, "preauth_token_test01").await;
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn create_preauth_token_with_bad_api_key(runtime_info: RuntimeInformation) {
+        let np = NinjaPandaClient::new(&runtime_info.ninja_panda_api_url, "not-an-api-key");
+        let result = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
+                namespace: "optm".to_string(),
+                ..Default::default()
+            })
+            .await;
+        match result {
+            Err(err @ Error::NinjaPanda { .. }) => {
+                assert_eq!(Some(reqwest::StatusCode::UNAUTHORIZED), err.status())
+            }
+            other => panic!("Expected a 401 from Ninja Panda, got {:?}", other),
+        }
+    }
+
     #[rstest]
     #[tokio::test]
     async fn login_with_invalid_preauth_token(config: Config, docker: Docker) {
@@ -108,33 +91,27 @@ mod preauth_tokens_tests {
     async fn login_with_depleted_preauth_token(
         config: Config,
         docker: Docker,
-        client: reqwest::Client,
-        runtime_info: RuntimeInformation,
+        np: NinjaPandaClient,
     ) {
         let namespace_name = "optm";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
-        let preauth_token = create_preauth_token(
-            &client,
-            &runtime_info,
-            CreatePreauthTokenRequest {
+        np.create_namespace(namespace_name).await.unwrap();
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
                 acl_tags: vec![],
                 namespace: namespace_name.to_string(),
                 prefix: "".to_string(),
                 reuse_count: 2,
                 ephemeral: false,
                 expiration: "".to_string(),
-            },
-        )
-        .await
-        .unwrap();
+            })
+            .await
+            .unwrap();
 
         let good1 = create_client_with_preauth_token(
             &docker,
             &config,
             "preauth_token_depleted_good01",
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
@@ -143,7 +120,7 @@ mod preauth_tokens_tests {
             &docker,
             &config,
             "preauth_token_depleted_good02",
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
@@ -152,7 +129,7 @@ mod preauth_tokens_tests {
             &docker,
             &config,
             "preauth_token_depleted_bad01",
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
@@ -173,35 +150,29 @@ mod preauth_tokens_tests {
     async fn login_with_expired_preauth_token(
         config: Config,
         docker: Docker,
-        client: reqwest::Client,
-        runtime_info: RuntimeInformation,
+        np: NinjaPandaClient,
     ) {
         let namespace_name = "optm";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
-        let preauth_token = create_preauth_token(
-            &client,
-            &runtime_info,
-            CreatePreauthTokenRequest {
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
                 acl_tags: vec![],
                 namespace: namespace_name.to_string(),
                 prefix: "".to_string(),
                 reuse_count: 0,
                 ephemeral: false,
                 expiration: "2s".to_string(),
-            },
-        )
-        .await
-        .unwrap();
+            })
+            .await
+            .unwrap();
 
         let container_name = "preauthexp01";
         let result = create_client_with_preauth_token(
             &docker,
             &config,
             container_name,
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
@@ -215,41 +186,37 @@ mod preauth_tokens_tests {
     async fn login_with_pat_then_switch_to_user(
         config: Config,
         docker: Docker,
-        client: reqwest::Client,
-        runtime_info: RuntimeInformation,
+        np: NinjaPandaClient,
     ) {
         let namespace_name = "patswitch";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
-        let preauth_token = create_preauth_token(
-            &client,
-            &runtime_info,
-            CreatePreauthTokenRequest {
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
                 acl_tags: vec![],
                 namespace: namespace_name.to_string(),
                 prefix: "".to_string(),
                 reuse_count: 0,
                 ephemeral: false,
                 expiration: "".to_string(),
-            },
-        )
-        .await
-        .unwrap();
+            })
+            .await
+            .unwrap();
 
         let container_name = "login_with_pat01";
         let result = create_client_with_preauth_token(
             &docker,
             &config,
             container_name,
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
         similar_asserts::assert_eq!("", result);
 
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         assert!(status.is_tagged_user());
 
         ztclient_logout(&docker, container_name).await.unwrap();
@@ -259,16 +226,14 @@ mod preauth_tokens_tests {
         let correlation_id = ztclient_registration(&docker, container_name)
             .await
             .unwrap();
-        let request = ExecuteCallbackRequest {
-            correlation_id: correlation_id.as_str(),
-            api_key: &runtime_info.ninja_panda_api_key,
-            namespace_name,
-            user_info_id: user_id,
-            ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-        };
-        let _ = execute_callback(&client, &request).await.unwrap();
-
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let _ = np
+            .execute_callback(&correlation_id, namespace_name, user_id)
+            .await
+            .unwrap();
+
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         let user = get_user(user_id);
         status.assert_user(&user);
 
@@ -278,13 +243,15 @@ mod preauth_tokens_tests {
         preauth_token_registration(
             &docker,
             container_name,
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
             NGINX_NP_URL,
         )
         .await
         .unwrap();
 
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         assert!(status.is_tagged_user());
 
         remove_container(&docker, container_name).await;
@@ -295,41 +262,37 @@ mod preauth_tokens_tests {
     async fn login_with_pat_force_reauth_to_user(
         config: Config,
         docker: Docker,
-        client: reqwest::Client,
-        runtime_info: RuntimeInformation,
+        np: NinjaPandaClient,
     ) {
         let namespace_name = "frcswitch";
-        create_namespace(namespace_name, &runtime_info, &client)
-            .await
-            .unwrap();
+        np.create_namespace(namespace_name).await.unwrap();
 
-        let preauth_token = create_preauth_token(
-            &client,
-            &runtime_info,
-            CreatePreauthTokenRequest {
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
                 acl_tags: vec![],
                 namespace: namespace_name.to_string(),
                 prefix: "".to_string(),
                 reuse_count: 0,
                 ephemeral: false,
                 expiration: "".to_string(),
-            },
-        )
-        .await
-        .unwrap();
+            })
+            .await
+            .unwrap();
 
         let container_name = "login_with_reauth01";
         let result = create_client_with_preauth_token(
             &docker,
             &config,
             container_name,
-            preauth_token.as_str(),
+            preauth_token.key.as_str(),
         )
         .await
         .unwrap();
         similar_asserts::assert_eq!("", result);
 
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         assert!(status.is_tagged_user());
 
         let user_id = 4;
@@ -338,16 +301,14 @@ mod preauth_tokens_tests {
             .await
             .unwrap();
 
-        let request = ExecuteCallbackRequest {
-            correlation_id: correlation_id.as_str(),
-            api_key: &runtime_info.ninja_panda_api_key,
-            namespace_name,
-            user_info_id: user_id,
-            ninja_panda_api_url: &runtime_info.ninja_panda_api_url,
-        };
-        let _ = execute_callback(&client, &request).await.unwrap();
+        let _ = np
+            .execute_callback(&correlation_id, namespace_name, user_id)
+            .await
+            .unwrap();
 
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         let user = get_user(user_id);
         status.assert_user(&user);
 
@@ -367,18 +328,119 @@ mod preauth_tokens_tests {
                 NGINX_NP_URL,
                 "--force-reauth",
                 "--auth-token",
-                preauth_token.as_str(),
+                preauth_token.key.as_str(),
             ],
         )
         .await
         .unwrap();
 
-        let status = wait_for_state_change(&docker, container_name, RUNNING_STATE).await;
+        let status = wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
         assert!(status.is_tagged_user());
 
         remove_container(&docker, container_name).await;
     }
 
+    #[rstest]
+    #[tokio::test]
+    /// Revoke a reusable key after one node registered with it, the next node must be refused.
+    async fn login_with_revoked_preauth_token(
+        config: Config,
+        docker: Docker,
+        np: NinjaPandaClient,
+    ) {
+        let namespace_name = "revoked";
+        np.create_namespace(namespace_name).await.unwrap();
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
+                namespace: namespace_name.to_string(),
+                reuse_count: 3,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let good = create_client_with_preauth_token(
+            &docker,
+            &config,
+            "preauth_token_revoked_good01",
+            preauth_token.key.as_str(),
+        )
+        .await
+        .unwrap();
+        similar_asserts::assert_eq!("", good);
+
+        np.revoke_preauth_token(namespace_name, &preauth_token.key)
+            .await
+            .unwrap();
+        let revoked = np
+            .get_preauth_token(&preauth_token.pre_auth_key_id)
+            .await
+            .unwrap();
+        assert!(revoked.is_revoked(), "{revoked:?}");
+        let listed = np.list_preauth_tokens(namespace_name).await.unwrap();
+        assert!(listed
+            .iter()
+            .any(|t| t.pre_auth_key_id == preauth_token.pre_auth_key_id && t.is_revoked()));
+
+        let bad = create_client_with_preauth_token(
+            &docker,
+            &config,
+            "preauth_token_revoked_bad01",
+            preauth_token.key.as_str(),
+        )
+        .await
+        .unwrap();
+        similar_asserts::assert_eq!(INVALID_AUTH_TOKEN_ERROR, bad);
+
+        remove_container(&docker, "preauth_token_revoked_good01").await;
+        remove_container(&docker, "preauth_token_revoked_bad01").await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn preauth_token_uses_are_counted(config: Config, docker: Docker, np: NinjaPandaClient) {
+        let namespace_name = "reusecount";
+        np.create_namespace(namespace_name).await.unwrap();
+        let preauth_token = np
+            .create_preauth_token(&CreatePreauthTokenRequest {
+                namespace: namespace_name.to_string(),
+                reuse_count: 2,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(2),
+            np.preauth_token_uses_remaining(&preauth_token)
+                .await
+                .unwrap()
+        );
+
+        let container_name = "preauth_token_counted01";
+        let result = create_client_with_preauth_token(
+            &docker,
+            &config,
+            container_name,
+            preauth_token.key.as_str(),
+        )
+        .await
+        .unwrap();
+        similar_asserts::assert_eq!("", result);
+        wait_for_state_change(&docker, container_name, BackendState::Running)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(1),
+            np.preauth_token_uses_remaining(&preauth_token)
+                .await
+                .unwrap()
+        );
+
+        remove_container(&docker, container_name).await;
+    }
+
     // Login with a preauth token for a namespace that doesn't exist. (i.e. create token, then drop namespace)
     // Create an ephemeral token, create a node, logit out and then re-start it, assert that a new IP gets created
     // NinjaPanda: FailedAuthentication with authkeys, protocl_common.go:490: Add Prometheus Metric
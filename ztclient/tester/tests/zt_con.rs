@@ -1,50 +1,22 @@
-use rstest::{fixture, rstest};
+use rstest::rstest;
 mod zt_con_tests {
 
     use super::*;
-    use bollard::{
-        container::LogOutput,
-        exec::{CreateExecOptions, StartExecResults},
-        Docker,
-    };
-
-    use futures::StreamExt;
+    use bollard::Docker;
+    use ztclient_common::fixtures::{config, ctx, docker, running_clients, RunningClients};
 
     use ztclient_common::{
-        containers::ContainerRemover,
+        acl::AclEvaluation,
+        context::TestContext,
         errors::Errors,
-        get_running_json, get_unique_timestamp,
-        models::ztn::SelfNode,
-        ninjapanda::{create_namespace, grant_one_directional_policy},
-        ztclient::{create_and_register_client, wait_for_peer, ztclient_netmap},
-        Config, RuntimeInformation,
+        get_unique_timestamp,
+        models::{ztn::SelfNode, Machine},
+        policy::{PolicyBuilder, Protocol},
+        reachability::{check_reachability, Probe, DEFAULT_PROBE_TIMEOUT},
+        ztclient::{wait_for_peer, zt_con, ztclient_netmap, ZT_CON_GREETING},
+        Config,
     };
 
-    #[fixture]
-    fn runtime_info() -> RuntimeInformation {
-        get_running_json().expect("Unable to read runtime.json, is environment created?")
-    }
-
-    #[fixture]
-    fn docker() -> Docker {
-        Docker::connect_with_unix_defaults().unwrap()
-    }
-
-    #[fixture]
-    fn config() -> Config {
-        dotenv::dotenv().ok();
-
-        match envy::from_env::<Config>() {
-            Ok(config) => config,
-            Err(error) => panic!("{:#?}", error),
-        }
-    }
-
-    #[fixture]
-    fn client() -> reqwest::Client {
-        reqwest::Client::new()
-    }
-
     #[rstest]
     #[tokio::test]
     async fn wait_peer_ztcon(_docker: Docker, _config: Config) {
@@ -53,66 +25,43 @@ mod zt_con_tests {
 
     #[rstest]
     #[tokio::test]
-    async fn wait_for_policy(
-        runtime_info: RuntimeInformation,
-        docker: Docker,
-        config: Config,
-        client: reqwest::Client,
-    ) {
+    async fn wait_for_policy(ctx: TestContext) {
+        let docker = &ctx.docker;
         let dummy_node = SelfNode::default();
 
         let mut error_container = Errors::new();
         let namespace_suffix = get_unique_timestamp();
         let namespace_name = format!("optm{namespace_suffix}");
-        create_namespace(namespace_name.as_str(), &runtime_info, &client)
-            .await
-            .unwrap();
+        ctx.create_namespace(namespace_name.as_str()).await.unwrap();
 
         // Create container1 and container2
         let container_name1 = "wfpolicy01";
         let container_name2 = "wfpolicy02";
         let user_info_id = 3;
-        let machine_id1 = create_and_register_client(
-            &runtime_info,
-            &docker,
-            &config,
-            &client,
-            container_name1,
-            namespace_name.as_str(),
-            user_info_id,
-        )
-        .await
-        .unwrap();
-
-        let _dropper1 = ContainerRemover::new(container_name1.to_string());
-        let _dropper2 = ContainerRemover::new(container_name2.to_string());
-
-        let machine_id2 = create_and_register_client(
-            &runtime_info,
-            &docker,
-            &config,
-            &client,
-            container_name2,
-            namespace_name.as_str(),
-            user_info_id,
-        )
-        .await
-        .unwrap();
+        let machine_id1 = ctx
+            .create_and_register_client(container_name1, namespace_name.as_str(), user_info_id)
+            .await
+            .unwrap();
+
+        let machine_id2 = ctx
+            .create_and_register_client(container_name2, namespace_name.as_str(), user_info_id)
+            .await
+            .unwrap();
 
         println!("Machines created, sleeping!");
         // sleep(Duration::from_millis(1300)).await;
 
-        let _policy_id =
-            grant_one_directional_policy(&runtime_info, &machine_id1, &machine_id2, 80, &client)
-                .await
-                .unwrap();
+        let _policy_id = ctx
+            .grant_one_directional_policy(&machine_id1, &machine_id2, 80)
+            .await
+            .unwrap();
         println!("Policy created, sleeping!");
         // sleep(Duration::from_millis(1300)).await;
 
         println!("Continuing on with wait for peer!");
         // sleep(Duration::from_secs(20)).await;
         // Let's execute a Wait For Peer
-        let result = wait_for_peer(&docker, container_name1, container_name2)
+        let result = wait_for_peer(docker, container_name1, container_name2)
             .await
             .unwrap();
         dbg!(result);
@@ -120,7 +69,7 @@ mod zt_con_tests {
         // sleep(Duration::from_secs(15)).await;
 
         // Let's verify the netmaps
-        let netmap1 = ztclient_netmap(&docker, container_name1).await;
+        let netmap1 = ztclient_netmap(docker, container_name1).await.unwrap();
         error_container.expect_none(
             &netmap1.packet_filter,
             "First container should not have a packet filter",
@@ -132,74 +81,87 @@ mod zt_con_tests {
         let other_peer = peers.first().unwrap_or(&dummy_node);
         dbg!(&other_peer.session_key);
 
-        let netmap2 = ztclient_netmap(&docker, container_name2).await;
+        let netmap2 = ztclient_netmap(docker, container_name2).await.unwrap();
         error_container.expect_some(
             &netmap2.packet_filter,
             "Second container should not have a packet filter",
         );
         error_container.expect_none(&netmap2.peers, "Node2.Peers");
         println!("Executing first zt-con!");
-        let response = ztcon(&docker, container_name1, container_name2, 80).await;
-        error_container.string_eq_assert(response, "HELLO".to_string());
+        let response = zt_con(docker, container_name1, container_name2, 80)
+            .await
+            .unwrap();
+        error_container.string_eq_assert(response, ZT_CON_GREETING.to_string());
 
         println!("Executing second zt-con!");
-        let response = ztcon(&docker, container_name1, container_name2, 80).await;
-        error_container.string_eq_assert(response, "HELLO".to_string());
+        let response = zt_con(docker, container_name1, container_name2, 80)
+            .await
+            .unwrap();
+        error_container.string_eq_assert(response, ZT_CON_GREETING.to_string());
 
         error_container.assert_pop();
-        // remove_container(&docker, container_name1).await;
-        // remove_container(&docker, container_name2).await;
     }
 
-    pub async fn ztcon(docker: &Docker, client1: &str, client2: &str, port_number: u32) -> String {
-        let res = docker
-            .create_exec(
-                client1,
-                CreateExecOptions {
-                    cmd: Some(vec![
-                        "ztclient",
-                        "zt-con",
-                        client2,
-                        &port_number.to_string(),
-                    ]),
-                    attach_stderr: Some(true),
-                    attach_stdin: Some(true),
-                    attach_stdout: Some(true),
-                    ..Default::default()
-                },
-            )
-            .await;
-        let result = res.unwrap();
-        let output_result = docker.start_exec(result.id.as_str(), None).await.unwrap();
-
-        let mut vect = Vec::new();
-        match output_result {
-            StartExecResults::Attached {
-                mut output,
-                mut input,
-            } => {
-                let output_line = output.next().await;
-                match output_line {
-                    Some(Ok(LogOutput::StdOut { message })) => {
-                        dbg!("StdOut", &message);
-                        vect.push(message);
-                        ""
-                    }
-                    Some(Ok(LogOutput::StdErr { message })) => {
-                        dbg!("StdErr", &message);
-                        vect.push(message);
-                        ""
-                    }
-                    _ => "",
-                };
-            }
-            StartExecResults::Detached => (),
-        };
-        vect.first()
-            .map(|x| std::str::from_utf8(x))
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
+    #[rstest]
+    #[tokio::test]
+    async fn reachability_matches_policy(
+        #[future]
+        #[with(3)]
+        running_clients: RunningClients,
+    ) {
+        // a -> b on port 80, and everyone may ping everyone.
+        let clients = running_clients.await;
+        let (docker, np) = (&clients.docker, &clients.np);
+        let mut error_container = Errors::new();
+        let (a, b) = (&clients.clients[0], &clients.clients[1]);
+        let policy = PolicyBuilder::new()
+            .machines("a", std::slice::from_ref(&a.machine_id))
+            .machines("b", std::slice::from_ref(&b.machine_id))
+            .machines("all", &clients.machine_ids())
+            .allow("a", "b", Protocol::Tcp, 80)
+            .allow("all", "all", Protocol::Icmp, ..);
+        clients.track_acl_policy(&policy.create(np).await.unwrap());
+        wait_for_peer(docker, &a.container_name, &b.container_name)
+            .await
+            .unwrap();
+
+        let machine_ids = clients.machine_ids();
+        let machines: Vec<Machine> = np
+            .get_machines()
+            .await
             .unwrap()
-            .to_string()
+            .into_iter()
+            .filter(|m| machine_ids.contains(&m.machine_id))
+            .collect();
+        let evaluation = AclEvaluation::evaluate(&[policy.build().unwrap()], &machines).unwrap();
+        let machine_of = |container_name: &str| {
+            let client = clients
+                .clients
+                .iter()
+                .find(|c| c.container_name == container_name);
+            client.map(|c| c.machine_id.clone()).unwrap_or_default()
+        };
+
+        let probes = [Probe::tcp(80), Probe::tcp(81), Probe::Ping];
+        let matrix = check_reachability(
+            docker,
+            &clients.container_names(),
+            &probes,
+            4,
+            DEFAULT_PROBE_TIMEOUT,
+        )
+        .await;
+        println!("{matrix}");
+        for cell in matrix.unexpected(|from, to, probe| {
+            probe.expected(&evaluation, &machine_of(from), &machine_of(to))
+        }) {
+            error_container.in_container(&cell.from);
+            error_container.add_error(format!(
+                "{} {} to {}: {:?}",
+                cell.probe, cell.outcome, cell.to, cell.outcome
+            ));
+        }
+
+        error_container.assert_pop();
     }
 }